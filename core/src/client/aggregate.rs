@@ -0,0 +1,593 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        split, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        DuplexStream, Lines, ReadHalf, WriteHalf,
+    },
+    select,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, info};
+
+use crate::client::{
+    failover::{connect_failover, PoolEndpoint},
+    upstream::{is_stratum, PoolStream},
+};
+
+// 上游会话聚合。
+// 开启后矿机不再各自连接矿池：使用同一组矿池、同一钱包的矿机共用矿池连接，
+// 每个连接最多承载 aggregate 台矿机。矿机的请求在会话内重新编号，
+// 矿池的回复按编号送回原矿机，矿池推送的任务发给会话内全部矿机。
+// 提交的工作量带各自的 worker 字段；NiceHash 矿池按 extranonce 再细分，
+// 每台矿机分到矿池 extranonce 之后的一个字节。
+// 矿池全部为 NiceHash 时各矿机单独登录，不同钱包的矿机也共用会话，
+// 名额在矿机订阅前占好，订阅时即下发细分后的 extranonce。
+
+const MEMBER_BUFFER: usize = 64 * 1024;
+// 等待矿池回复的请求上限，超出时丢弃最早的
+const MAX_PENDING: usize = 4096;
+// extranonce 细分一个字节，一个会话最多 256 台矿机
+const EXTRANONCE_SLOTS: usize = 256;
+// 重新编号的起点，避开矿池推送任务使用的 id
+const FIRST_ID: u64 = 1 << 20;
+
+static SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    // 矿池与钱包 -> 会话
+    static ref GROUPS: Mutex<HashMap<String, Vec<SessionHandle>>> =
+        Mutex::new(HashMap::new());
+}
+
+struct SessionHandle {
+    id: u64,
+    tx: mpsc::UnboundedSender<Command>,
+    // 已分配给矿机的名额，包括尚未加入完成的
+    members: usize,
+}
+
+enum Command {
+    Join {
+        member: mpsc::UnboundedSender<String>,
+        // 名额及矿池 extranonce
+        reply: oneshot::Sender<(usize, Option<String>)>,
+    },
+    Leave(usize),
+    Line(usize, String),
+}
+
+// 建立聚合的矿池连接。返回的连接与普通矿池连接用法一致
+pub async fn connect_aggregate(
+    pools: Vec<PoolEndpoint>, size: u32,
+) -> Result<(PoolStream, Option<String>)> {
+    let (local, remote) = tokio::io::duplex(MEMBER_BUFFER);
+    let size = (size as usize).min(EXTRANONCE_SLOTS);
+
+    if !pools.iter().all(|p| is_stratum(p.stream_type)) {
+        tokio::spawn(async move {
+            if let Err(e) = member(remote, pools, size).await {
+                debug!("聚合连接结束: {}", e);
+            }
+        });
+        return Ok((Box::new(local), None));
+    }
+
+    let key = group_key(&pools, "");
+    let joined = join(&key, &pools, size).await?;
+    let extranonce = joined
+        .extranonce
+        .as_ref()
+        .map(|e| slot_extranonce(e, joined.slot));
+    tokio::spawn(async move {
+        if let Err(e) = relay(remote, joined, vec![]).await {
+            debug!("聚合连接结束: {}", e);
+        }
+    });
+    Ok((Box::new(local), extranonce))
+}
+
+fn login_wallet(line: &str) -> Option<String> {
+    let rpc: Value = serde_json::from_str(line).ok()?;
+    if rpc["method"].as_str() != Some("eth_submitLogin") {
+        return None;
+    }
+    let wallet = rpc["params"][0].as_str()?;
+    Some(wallet.split('.').next().unwrap_or(wallet).to_lowercase())
+}
+
+fn group_key(pools: &[PoolEndpoint], wallet: &str) -> String {
+    let urls: Vec<&str> = pools.iter().map(|p| p.url.as_str()).collect();
+    format!("{}|{}", urls.join(","), wallet)
+}
+
+// 找一个有空位的会话，没有就新建。名额在锁内占用
+fn reserve(
+    key: &str, pools: &[PoolEndpoint], size: usize,
+) -> mpsc::UnboundedSender<Command> {
+    let mut groups = GROUPS.lock().unwrap();
+    let sessions = groups.entry(key.to_string()).or_default();
+    if let Some(s) = sessions.iter_mut().find(|s| s.members < size) {
+        s.members += 1;
+        return s.tx.clone();
+    }
+
+    let id = SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::unbounded_channel();
+    sessions.push(SessionHandle {
+        id,
+        tx: tx.clone(),
+        members: 1,
+    });
+    info!("新建聚合会话 #{} {}", id, key);
+
+    let key = key.to_string();
+    let pools = pools.to_vec();
+    tokio::spawn(async move {
+        if let Err(e) = session(&key, id, pools, rx).await {
+            info!("聚合会话 #{} 结束: {}", id, e);
+        }
+        remove_session(&key, id);
+    });
+    tx
+}
+
+fn remove_session(key: &str, id: u64) {
+    let mut groups = GROUPS.lock().unwrap();
+    if let Some(sessions) = groups.get_mut(key) {
+        sessions.retain(|s| s.id != id);
+        if sessions.is_empty() {
+            groups.remove(key);
+        }
+    }
+}
+
+// 释放一个名额。返回会话是否已无矿机
+fn release(key: &str, id: u64) -> bool {
+    let mut groups = GROUPS.lock().unwrap();
+    let sessions = match groups.get_mut(key) {
+        Some(sessions) => sessions,
+        None => return true,
+    };
+    let idle = match sessions.iter_mut().find(|s| s.id == id) {
+        Some(s) => {
+            s.members = s.members.saturating_sub(1);
+            s.members == 0
+        }
+        None => true,
+    };
+    if idle {
+        sessions.retain(|s| s.id != id);
+        if sessions.is_empty() {
+            groups.remove(key);
+        }
+    }
+    idle
+}
+
+async fn write_line<W>(w: &mut WriteHalf<W>, line: &str) -> Result<()>
+where W: AsyncWrite {
+    w.write_all(line.as_bytes()).await?;
+    w.write_all(b"\n").await?;
+    Ok(())
+}
+
+// 已加入会话的矿机
+struct Joined {
+    tx: mpsc::UnboundedSender<Command>,
+    rx: mpsc::UnboundedReceiver<String>,
+    slot: usize,
+    extranonce: Option<String>,
+}
+
+async fn join(
+    key: &str, pools: &[PoolEndpoint], size: usize,
+) -> Result<Joined> {
+    let tx = reserve(key, pools, size);
+    let (member_tx, rx) = mpsc::unbounded_channel();
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let join = Command::Join {
+        member: member_tx,
+        reply: reply_tx,
+    };
+    if tx.send(join).is_err() {
+        bail!("聚合会话已结束");
+    }
+    match reply_rx.await {
+        Ok((slot, extranonce)) => Ok(Joined {
+            tx,
+            rx,
+            slot,
+            extranonce,
+        }),
+        Err(_) => bail!("聚合会话已结束"),
+    }
+}
+
+// 一台矿机。登录后才知道钱包，登录前的请求先缓存
+async fn member(
+    client: DuplexStream, pools: Vec<PoolEndpoint>, size: usize,
+) -> Result<()> {
+    let (client_r, mut client_w) = split(client);
+    let mut client_lines = BufReader::new(client_r).lines();
+
+    let mut early = vec![];
+    let wallet = loop {
+        let line = match client_lines.next_line().await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let wallet = login_wallet(&line);
+        early.push(line);
+        if let Some(wallet) = wallet {
+            break wallet;
+        }
+    };
+
+    let key = group_key(&pools, &wallet);
+    let joined = join(&key, &pools, size).await?;
+    // 登录后才加入会话，矿池 extranonce 以变更通知下发
+    if let Some(e) = &joined.extranonce {
+        write_line(&mut client_w, &set_extranonce(e, joined.slot)).await?;
+    }
+    forward(client_lines, client_w, joined, early).await
+}
+
+async fn relay(
+    client: DuplexStream, joined: Joined, early: Vec<String>,
+) -> Result<()> {
+    let (client_r, client_w) = split(client);
+    let client_lines = BufReader::new(client_r).lines();
+    forward(client_lines, client_w, joined, early).await
+}
+
+async fn forward(
+    mut client_lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    mut client_w: WriteHalf<DuplexStream>, joined: Joined, early: Vec<String>,
+) -> Result<()> {
+    let Joined {
+        tx,
+        rx: mut member_rx,
+        slot,
+        ..
+    } = joined;
+    for line in early {
+        let _ = tx.send(Command::Line(slot, line));
+    }
+
+    let res = loop {
+        select! {
+            res = client_lines.next_line() => match res {
+                Ok(Some(line)) => {
+                    if tx.send(Command::Line(slot, line)).is_err() {
+                        break Ok(());
+                    }
+                }
+                _ => break Ok(()),
+            },
+            line = member_rx.recv() => match line {
+                Some(line) => {
+                    if let Err(e) = write_line(&mut client_w, &line).await {
+                        break Err(e);
+                    }
+                }
+                None => break Ok(()),
+            },
+        }
+    };
+
+    let _ = tx.send(Command::Leave(slot));
+    res
+}
+
+struct Request {
+    slot: usize,
+    id: Value,
+    login: bool,
+}
+
+// 会话内请求编号
+struct Routes {
+    next_id: u64,
+    // 新编号 -> 请求
+    pending: HashMap<u64, Request>,
+    // 编号顺序，用于丢弃最早的请求。已回复的编号在这里惰性清除
+    order: VecDeque<u64>,
+}
+
+impl Routes {
+    fn new() -> Self {
+        Self {
+            next_id: FIRST_ID,
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn request(&mut self, slot: usize, rpc: &mut Value) {
+        if self.order.len() >= MAX_PENDING {
+            if let Some(id) = self.order.pop_front() {
+                self.pending.remove(&id);
+            }
+        }
+        self.next_id += 1;
+        let request = Request {
+            slot,
+            id: rpc["id"].take(),
+            login: rpc["method"].as_str() == Some("eth_submitLogin"),
+        };
+        self.pending.insert(self.next_id, request);
+        self.order.push_back(self.next_id);
+        rpc["id"] = json!(self.next_id);
+    }
+
+    // 还原回复的 id。返回回复对应的请求
+    fn reply(&mut self, rpc: &mut Value) -> Option<Request> {
+        let id = rpc["id"].as_u64()?;
+        let mut request = self.pending.remove(&id)?;
+        rpc["id"] = request.id.take();
+        Some(request)
+    }
+
+    fn forget(&mut self, slot: usize) {
+        self.pending.retain(|_, r| r.slot != slot);
+    }
+}
+
+fn slot_extranonce(extranonce: &str, slot: usize) -> String {
+    format!("{}{:02x}", extranonce, slot)
+}
+
+fn set_extranonce(extranonce: &str, slot: usize) -> String {
+    json!({
+        "id": null,
+        "method": "mining.set_extranonce",
+        "params": [slot_extranonce(extranonce, slot)],
+    })
+    .to_string()
+}
+
+// NiceHash 会话中各矿机以完整的 钱包.矿机名 登录及提交，
+// 不同钱包的同名矿机互不影响
+fn login_name(
+    names: &mut HashMap<usize, String>, slot: usize, rpc: &mut Value,
+) {
+    match rpc["method"].as_str() {
+        Some("eth_submitLogin") => {
+            let wallet = match rpc["params"][0].as_str() {
+                Some(wallet) => wallet.to_string(),
+                None => return,
+            };
+            let name = match rpc["worker"].as_str() {
+                Some(w) if !w.is_empty() && !wallet.contains('.') => {
+                    format!("{}.{}", wallet, w)
+                }
+                _ => wallet,
+            };
+            rpc["params"][0] = json!(name);
+            rpc["worker"] = json!(name);
+            names.insert(slot, name);
+        }
+        Some("eth_submitWork") => {
+            if let Some(name) = names.get(&slot) {
+                rpc["worker"] = json!(name);
+            }
+        }
+        _ => {}
+    }
+}
+
+// 矿池难度由会话内全部矿机共用。全部矿机都建议提高时才按其中最低的建议
+fn session_difficulty(
+    members: &HashMap<usize, mpsc::UnboundedSender<String>>,
+    suggested: &HashMap<usize, f64>,
+) -> Option<f64> {
+    if members.is_empty() {
+        return None;
+    }
+    let mut diff = f64::MAX;
+    for slot in members.keys() {
+        diff = diff.min(*suggested.get(slot)?);
+    }
+    Some(diff)
+}
+
+fn suggest_difficulty(diff: f64) -> String {
+    json!({
+        "id": null,
+        "method": "mining.suggest_difficulty",
+        "params": [diff],
+    })
+    .to_string()
+}
+
+async fn session(
+    key: &str, id: u64, pools: Vec<PoolEndpoint>,
+    mut rx: mpsc::UnboundedReceiver<Command>,
+) -> Result<()> {
+    // 会话自己保留最近的任务，不用故障转移层的缓存任务，以免重复发给其它矿机
+    let (stream, mut extranonce) = connect_failover(pools, false).await?;
+    let (pool_r, mut pool_w) = split(stream);
+    let mut pool_lines = BufReader::new(pool_r).lines();
+
+    let mut members: HashMap<usize, mpsc::UnboundedSender<String>> =
+        HashMap::new();
+    let mut routes = Routes::new();
+    let mut last_job: Option<String> = None;
+    let mut names: HashMap<usize, String> = HashMap::new();
+    // 各矿机建议的难度及上次向矿池建议的难度
+    let mut suggested: HashMap<usize, f64> = HashMap::new();
+    let mut last_suggested = 0.0;
+
+    loop {
+        select! {
+            cmd = rx.recv() => match cmd {
+                Some(Command::Join { member, reply }) => {
+                    let slot = match (0..EXTRANONCE_SLOTS)
+                        .find(|s| !members.contains_key(s))
+                    {
+                        Some(slot) => slot,
+                        None => {
+                            release(key, id);
+                            continue;
+                        }
+                    };
+                    members.insert(slot, member);
+                    let _ = reply.send((slot, extranonce.clone()));
+                }
+                Some(Command::Leave(slot)) => {
+                    members.remove(&slot);
+                    names.remove(&slot);
+                    suggested.remove(&slot);
+                    routes.forget(slot);
+                    if release(key, id) {
+                        info!("聚合会话 #{} 已无矿机，关闭", id);
+                        let _ = pool_w.shutdown().await;
+                        return Ok(());
+                    }
+                }
+                Some(Command::Line(slot, line)) => {
+                    let mut rpc: Value = match serde_json::from_str(&line) {
+                        Ok(rpc) => rpc,
+                        Err(_) => continue,
+                    };
+                    if rpc["method"].as_str() == Some("mining.suggest_difficulty") {
+                        if let Some(diff) = rpc["params"][0].as_f64() {
+                            suggested.insert(slot, diff);
+                        }
+                        match session_difficulty(&members, &suggested) {
+                            Some(diff) if diff != last_suggested => {
+                                last_suggested = diff;
+                                write_line(&mut pool_w, &suggest_difficulty(diff)).await?;
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    if extranonce.is_some() {
+                        login_name(&mut names, slot, &mut rpc);
+                    }
+                    routes.request(slot, &mut rpc);
+                    write_line(&mut pool_w, &rpc.to_string()).await?;
+                }
+                None => return Ok(()),
+            },
+            res = pool_lines.next_line() => {
+                let line = match res? {
+                    Some(line) => line,
+                    None => bail!("矿池主动断开"),
+                };
+                let mut rpc: Value = match serde_json::from_str(&line) {
+                    Ok(rpc) => rpc,
+                    Err(_) => continue,
+                };
+
+                if let Some(request) = routes.reply(&mut rpc) {
+                    if let Some(member) = members.get(&request.slot) {
+                        let _ = member.send(rpc.to_string());
+                        // 新加入的矿机登录后立即拿到任务
+                        let logged_in = rpc["result"].as_bool() == Some(true);
+                        if let Some(job) = last_job.as_ref().filter(|_| request.login && logged_in) {
+                            let _ = member.send(job.clone());
+                        }
+                    }
+                } else if rpc["method"].as_str() == Some("mining.set_extranonce") {
+                    if let Some(e) = rpc["params"][0].as_str() {
+                        let e = e.trim_start_matches("0x").to_lowercase();
+                        for (slot, member) in &members {
+                            let _ = member.send(set_extranonce(&e, *slot));
+                        }
+                        extranonce = Some(e);
+                    }
+                } else if rpc["result"].is_array() || rpc["method"].is_string() {
+                    // 矿池推送的任务
+                    for member in members.values() {
+                        let _ = member.send(line.clone());
+                    }
+                    if rpc["result"].is_array() {
+                        last_job = Some(line);
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[test]
+fn test_routes() {
+    let mut routes = Routes::new();
+    let mut a = json!({"id": 1000, "method": "eth_submitWork"});
+    let mut b = json!({"id": 1000, "method": "eth_submitWork"});
+    routes.request(3, &mut a);
+    routes.request(7, &mut b);
+    assert_ne!(a["id"], b["id"]);
+
+    let mut reply = json!({"id": b["id"], "result": true});
+    let request = routes.reply(&mut reply).unwrap();
+    assert_eq!(request.slot, 7);
+    assert!(!request.login);
+    assert_eq!(reply["id"], 1000);
+    assert!(routes.reply(&mut reply).is_none());
+
+    routes.forget(3);
+    let mut reply = json!({"id": a["id"], "result": true});
+    assert!(routes.reply(&mut reply).is_none());
+
+    let mut login = json!({"id": 1001, "method": "eth_submitLogin"});
+    routes.request(2, &mut login);
+    let mut reply = json!({"id": login["id"], "result": true});
+    assert!(routes.reply(&mut reply).unwrap().login);
+
+    let mut job = json!({"id": 0, "result": ["0x1", "0x2", "0x3"]});
+    assert!(routes.reply(&mut job).is_none());
+
+    let login =
+        r#"{"id":1,"method":"eth_submitLogin","params":["0xAB.rig1","x"]}"#;
+    assert_eq!(login_wallet(login), Some("0xab".into()));
+    assert_eq!(login_wallet(r#"{"id":1,"method":"eth_getWork"}"#), None);
+
+    let notify: Value = serde_json::from_str(&set_extranonce("a1b2", 10)).unwrap();
+    assert_eq!(notify["params"][0], "a1b20a");
+    assert_eq!(slot_extranonce("a1b2", 255), "a1b2ff");
+}
+
+#[test]
+fn test_session_members() {
+    let mut names = HashMap::new();
+    let mut login = json!({"id": 1001, "method": "eth_submitLogin", "params": ["0xab", "x"], "worker": "rig1"});
+    login_name(&mut names, 1, &mut login);
+    assert_eq!(login["params"][0], "0xab.rig1");
+    let mut login = json!({"id": 1001, "method": "eth_submitLogin", "params": ["0xcd.rig1", "x"], "worker": "rig1"});
+    login_name(&mut names, 2, &mut login);
+    assert_eq!(login["worker"], "0xcd.rig1");
+
+    // 不同钱包的同名矿机按各自的登录名提交
+    let mut submit = json!({"id": 1000, "method": "eth_submitWork", "params": [], "worker": "rig1"});
+    login_name(&mut names, 1, &mut submit);
+    assert_eq!(submit["worker"], "0xab.rig1");
+    let mut submit = json!({"id": 1000, "method": "eth_submitWork", "params": [], "worker": "rig1"});
+    login_name(&mut names, 2, &mut submit);
+    assert_eq!(submit["worker"], "0xcd.rig1");
+
+    // 只有全部矿机都建议提高难度时才向矿池建议，按最低的建议
+    let mut members = HashMap::new();
+    let mut suggested = HashMap::new();
+    let (tx, _rx) = mpsc::unbounded_channel();
+    members.insert(1, tx.clone());
+    members.insert(2, tx);
+    suggested.insert(1, 8.0);
+    assert_eq!(session_difficulty(&members, &suggested), None);
+    suggested.insert(2, 4.0);
+    assert_eq!(session_difficulty(&members, &suggested), Some(4.0));
+    members.remove(&2);
+    suggested.remove(&2);
+    assert_eq!(session_difficulty(&members, &suggested), Some(8.0));
+}
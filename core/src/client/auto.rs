@@ -0,0 +1,112 @@
+use anyhow::{bail, Result};
+use std::{sync::Arc, time::Duration};
+use tracing::info;
+
+use tokio::{
+    io::{split, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::RwLockReadGuard,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+use super::*;
+use crate::{proxy::Proxy, state::Worker, util::config::Settings};
+
+// 自适应端口：不读出数据，先窥探首字节。
+// TLS 握手走 rustls，其余按明文交给首包识别。同一端口可接入所有矿机
+pub async fn accept_auto(proxy: Arc<Proxy>, cert: ServerConfig) -> Result<()> {
+    let config: Settings;
+    {
+        let rconfig = RwLockReadGuard::map(proxy.config.read().await, |s| s);
+        config = rconfig.clone();
+    }
+
+    if config.auto_port == 0 {
+        return Ok(());
+    }
+
+    let address = format!("0.0.0.0:{}", config.auto_port);
+    let listener = match TcpListener::bind(address.clone()).await {
+        Ok(listener) => listener,
+        Err(_) => {
+            tracing::info!("本地端口被占用 {}", address);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("本地自适应端口{} 启动成功!!!", &address);
+
+    let tls_acceptor = TlsAcceptor::from(Arc::new(cert));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        let acceptor = tls_acceptor.clone();
+
+        let p = Arc::clone(&proxy);
+
+        tokio::spawn(async move {
+            // 矿工状态管理
+            let mut worker: Worker = Worker::default();
+            let worker_tx = p.worker_tx.clone();
+            match transfer_auto(p, &mut worker, stream, acceptor).await {
+                Ok(_) => {
+                    if worker.is_online() {
+                        worker.offline();
+                        info!("IP: {} 安全下线", addr);
+                        worker_tx.send(worker).unwrap();
+                    } else {
+                        info!("IP: {} 下线", addr);
+                    }
+                }
+                Err(e) => {
+                    if worker.is_online() {
+                        worker.offline();
+                        worker_tx.send(worker).unwrap();
+                        info!("IP: {} 下线原因 {}", addr, e);
+                    } else {
+                        debug!("IP: {} 恶意链接断开: {}", addr, e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+// TLS 记录层首字节为 Handshake(0x16)，明文 JSON-RPC 与 HTTP 均不会以此开头
+async fn is_tls(stream: &TcpStream) -> Result<bool> {
+    let mut buf = [0u8; 1];
+    let n = match tokio::time::timeout(
+        Duration::from_secs(detect::FIRST_PACKET_TIMEOUT),
+        stream.peek(&mut buf),
+    )
+    .await
+    {
+        Ok(n) => n?,
+        Err(_) => bail!("{}秒内未收到首包", detect::FIRST_PACKET_TIMEOUT),
+    };
+
+    if n == 0 {
+        bail!("未发送数据即断开");
+    }
+
+    Ok(buf[0] == 0x16)
+}
+
+async fn transfer_auto(
+    proxy: Arc<Proxy>, worker: &mut Worker, tcp_stream: TcpStream,
+    tls_acceptor: TlsAcceptor,
+) -> Result<()> {
+    if is_tls(&tcp_stream).await? {
+        let client_stream = tls_acceptor.accept(tcp_stream).await?;
+        let (worker_r, worker_w) = split(client_stream);
+        let worker_r = BufReader::new(worker_r);
+        detect::handle_first_packet(worker, worker_r, worker_w, proxy, false)
+            .await
+    } else {
+        let (worker_r, worker_w) = split(tcp_stream);
+        let worker_r = BufReader::new(worker_r);
+        detect::handle_first_packet(worker, worker_r, worker_w, proxy, false)
+            .await
+    }
+}
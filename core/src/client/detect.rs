@@ -0,0 +1,265 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, WriteHalf,
+    },
+    sync::RwLockReadGuard,
+};
+use tracing::debug;
+
+use crate::{
+    client::{failover, getwork, handle_tcp_random},
+    protocol::eth_stratum::ETH_STRATUM_VERSION,
+    proxy::Proxy,
+    state::Worker,
+};
+
+// 首包最长等待时间
+pub const FIRST_PACKET_TIMEOUT: u64 = 10;
+// 首行最大长度。超出视为非矿机流量
+const FIRST_PACKET_MAX: u64 = 4096;
+// 判断 TLS 及 HTTP 最多需要的字节数
+const PREFIX_MAX: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FirstPacket {
+    EthProxy,
+    EthereumStratum,
+    Stratum,
+    // 可识别但中转无法服务的协议，如 EthereumStratum/2.0.0
+    Unsupported,
+    HttpGetwork,
+    TlsClientHello,
+    Garbage,
+}
+
+// 不读出数据，只根据已到达的字节判断 TLS 及 HTTP
+pub fn classify_prefix(buf: &[u8]) -> Option<FirstPacket> {
+    // TLS 记录层: Handshake(0x16) + 版本 0x03xx
+    if buf.len() >= 2 && buf[0] == 0x16 && buf[1] == 0x03 {
+        return Some(FirstPacket::TlsClientHello);
+    }
+
+    if buf.starts_with(b"POST ") || buf.starts_with(b"GET ") {
+        return Some(FirstPacket::HttpGetwork);
+    }
+
+    None
+}
+
+// 已到达的字节还不足以判断 TLS 及 HTTP
+fn partial_prefix(buf: &[u8]) -> bool {
+    buf.len() < PREFIX_MAX
+        && (buf == [0x16]
+            || b"POST ".starts_with(buf)
+            || b"GET ".starts_with(buf))
+}
+
+// 根据首行 JSON-RPC 判断矿机协议
+pub fn classify_line(line: &[u8]) -> FirstPacket {
+    let rpc: Value = match serde_json::from_slice(line) {
+        Ok(rpc) => rpc,
+        Err(_) => return FirstPacket::Garbage,
+    };
+
+    match rpc["method"].as_str() {
+        Some("eth_submitLogin") | Some("eth_getWork")
+        | Some("eth_submitHashrate") | Some("eth_submitWork") => {
+            FirstPacket::EthProxy
+        }
+        Some("mining.subscribe") => {
+            if rpc["params"][1].as_str() == Some(ETH_STRATUM_VERSION) {
+                FirstPacket::EthereumStratum
+            } else {
+                FirstPacket::Stratum
+            }
+        }
+        // EthereumStratum/2.0.0 以 mining.hello 开始，
+        // 未订阅先登录的矿机也会被断开
+        Some("mining.authorize") | Some("mining.hello") => {
+            FirstPacket::Unsupported
+        }
+        _ => FirstPacket::Garbage,
+    }
+}
+
+// 读取首包并分类。返回已读出的数据，交给后续流程重放
+pub async fn read_first_packet<R>(
+    worker_r: &mut R,
+) -> Result<(FirstPacket, Vec<u8>)>
+where R: AsyncBufRead + Unpin {
+    let read = async {
+        let prefix = worker_r.fill_buf().await?;
+        if prefix.is_empty() {
+            bail!("未发送数据即断开");
+        }
+        if let Some(packet) = classify_prefix(prefix) {
+            return Ok((packet, Vec::new()));
+        }
+
+        // 首段被拆开时继续读取，直到能判断是否为 TLS 及 HTTP
+        let mut line = Vec::new();
+        while partial_prefix(&line) {
+            let buf = worker_r.fill_buf().await?;
+            if buf.is_empty() {
+                bail!("首包不完整即断开");
+            }
+            let n = buf.len().min(PREFIX_MAX - line.len());
+            line.extend_from_slice(&buf[..n]);
+            worker_r.consume(n);
+            if let Some(packet) = classify_prefix(&line) {
+                return Ok((packet, line));
+            }
+        }
+
+        (&mut *worker_r)
+            .take(FIRST_PACKET_MAX)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if line.last() != Some(&b'\n') {
+            return Ok((FirstPacket::Garbage, line));
+        }
+        Ok((classify_line(&line), line))
+    };
+
+    match tokio::time::timeout(Duration::from_secs(FIRST_PACKET_TIMEOUT), read)
+        .await
+    {
+        Ok(res) => res,
+        Err(_) => bail!("{}秒内未收到首包", FIRST_PACKET_TIMEOUT),
+    }
+}
+
+// 先识别矿机协议，再连接矿池进入对应流程。无法识别的连接不会占用矿池链接
+pub async fn handle_first_packet<R, W>(
+    worker: &mut Worker, mut worker_r: R, worker_w: WriteHalf<W>,
+    proxy: Arc<Proxy>, is_encrypted: bool,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite,
+{
+    let (packet, first_line) = read_first_packet(&mut worker_r).await?;
+    debug!("首包协议 {:?}", packet);
+
+    match packet {
+        FirstPacket::EthProxy
+        | FirstPacket::EthereumStratum
+        | FirstPacket::Stratum => {}
+        FirstPacket::HttpGetwork => {
+            let worker_r = std::io::Cursor::new(first_line).chain(worker_r);
+            return getwork::handle_getwork(worker_r, worker_w, proxy).await;
+        }
+        FirstPacket::Unsupported => bail!("不支持的矿机协议 {:?}", first_line),
+        FirstPacket::TlsClientHello => bail!("明文端口收到 TLS 握手"),
+        FirstPacket::Garbage => bail!("无法识别的协议 {:?}", first_line),
+    }
+
+    let worker_r = std::io::Cursor::new(first_line).chain(worker_r);
+    handle_pool(worker, worker_r, worker_w, proxy, is_encrypted).await
+}
+
+// 按配置选择矿池并进入矿机与矿池之间的转发流程
+pub async fn handle_pool<R, W>(
+    worker: &mut Worker, worker_r: R, worker_w: WriteHalf<W>,
+    proxy: Arc<Proxy>, is_encrypted: bool,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite,
+{
+    let pool_address: Vec<String>;
+    {
+        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
+        pool_address = config.pool_address.to_vec();
+    }
+
+    let pools = match failover::parse_pools(&pool_address) {
+        Ok(pools) => pools,
+        Err(_) => {
+            bail!("未匹配到矿池 或 均不可链接。请修改后重试");
+        }
+    };
+
+    handle_tcp_random(worker, worker_r, worker_w, pools, proxy, is_encrypted)
+        .await
+}
+
+#[test]
+fn test_classify() {
+    assert_eq!(
+        classify_line(
+            br#"{"id":1,"method":"eth_submitLogin","params":["0x0"],"worker":"a"}"#
+        ),
+        FirstPacket::EthProxy
+    );
+    assert_eq!(
+        classify_line(
+            br#"{"id":1,"method":"mining.subscribe","params":["lolMiner","EthereumStratum/1.0.0"]}"#
+        ),
+        FirstPacket::EthereumStratum
+    );
+    assert_eq!(
+        classify_line(br#"{"id":1,"method":"mining.subscribe","params":[]}"#),
+        FirstPacket::Stratum
+    );
+    assert_eq!(
+        classify_line(br#"{"id":1,"method":"mining.hello","params":{}}"#),
+        FirstPacket::Unsupported
+    );
+    assert_eq!(
+        classify_line(br#"{"id":1,"method":"mining.authorize","params":[]}"#),
+        FirstPacket::Unsupported
+    );
+    assert_eq!(classify_line(b"\x00\x01garbage"), FirstPacket::Garbage);
+    assert_eq!(
+        classify_prefix(b"POST / HTTP/1.1\r\n"),
+        Some(FirstPacket::HttpGetwork)
+    );
+    assert_eq!(
+        classify_prefix(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]),
+        Some(FirstPacket::TlsClientHello)
+    );
+    assert_eq!(classify_prefix(b"{\"id\":1"), None);
+}
+
+#[tokio::test]
+async fn test_split_first_packet() {
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    async fn first_packet(parts: &[&[u8]]) -> (FirstPacket, Vec<u8>) {
+        let (client, server) = tokio::io::duplex(64);
+        let mut worker_r = BufReader::new(server);
+        let write = async move {
+            let mut client = client;
+            for part in parts {
+                client.write_all(part).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            client
+        };
+        let (res, _client) =
+            tokio::join!(read_first_packet(&mut worker_r), write);
+        res.unwrap()
+    }
+
+    let (packet, _) = first_packet(&[&[0x16], &[0x03, 0x01, 0x02]]).await;
+    assert_eq!(packet, FirstPacket::TlsClientHello);
+
+    let (packet, read) =
+        first_packet(&[b"PO", b"ST / HTTP/1.1\r\n"]).await;
+    assert_eq!(packet, FirstPacket::HttpGetwork);
+    assert_eq!(read, b"POST ");
+
+    let (packet, read) = first_packet(&[
+        b"{",
+        br#""id":1,"method":"eth_submitLogin","params":[]}"#,
+        b"\n",
+    ])
+    .await;
+    assert_eq!(packet, FirstPacket::EthProxy);
+    assert_eq!(read[0], b'{');
+}
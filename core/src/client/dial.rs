@@ -0,0 +1,215 @@
+use std::{net::SocketAddr, sync::RwLock, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    select,
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_native_tls::TlsStream;
+
+use crate::client::{trust::TlsPolicy, via::Via};
+
+// 异步建立矿池连接。
+// 域名异步解析，解析出的全部地址按 Happy Eyeballs (RFC 8305) 交替尝试，
+// 先连通者胜出，整个过程受连接超时约束。
+
+// 默认连接超时，矿池可单独设置
+pub const CONNECT_TIMEOUT: u64 = 10;
+// 域名解析超时
+const RESOLVE_TIMEOUT: u64 = 10;
+// 相邻两次连接尝试的间隔
+const ATTEMPT_DELAY: u64 = 250;
+
+lazy_static! {
+    // 未单独设置 via 的矿池使用的出口代理
+    static ref DEFAULT_VIA: RwLock<Via> = RwLock::new(Via::Direct);
+}
+
+pub fn default_timeout() -> Duration { Duration::from_secs(CONNECT_TIMEOUT) }
+
+pub fn set_default_via(via: &str) -> Result<()> {
+    *DEFAULT_VIA.write().unwrap() = Via::parse(via)?;
+    Ok(())
+}
+
+pub fn default_via() -> Via { DEFAULT_VIA.read().unwrap().clone() }
+
+pub async fn resolve(address: &str) -> Result<Vec<SocketAddr>> {
+    let addrs = match timeout(
+        Duration::from_secs(RESOLVE_TIMEOUT),
+        lookup_host(address),
+    )
+    .await
+    {
+        Ok(Ok(addrs)) => addrs.collect::<Vec<SocketAddr>>(),
+        Ok(Err(e)) => bail!("{} 域名解析失败 {}", address, e),
+        Err(_) => bail!("{} 域名解析超时", address),
+    };
+
+    if addrs.is_empty() {
+        bail!("{} 未解析到地址", address);
+    }
+    Ok(addrs)
+}
+
+// IPv6 与 IPv4 交替排列，首个地址的协议族优先
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    first.reverse();
+    second.reverse();
+
+    let mut out = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => break,
+            (a, b) => {
+                out.extend(a);
+                out.extend(b);
+            }
+        }
+    }
+    out
+}
+
+// 依次发起连接，前一个尚未结束时每隔 ATTEMPT_DELAY 追加一个，
+// 前一个失败则立即追加下一个
+pub async fn happy_eyeballs(
+    addrs: &[SocketAddr], connect_timeout: Duration,
+) -> Result<(TcpStream, SocketAddr)> {
+    let (tx, mut rx) = mpsc::channel(addrs.len().max(1));
+    let deadline = sleep(connect_timeout);
+    tokio::pin!(deadline);
+
+    let mut next = 0;
+    let mut pending = 0;
+    let mut last_err: Option<String>;
+
+    loop {
+        if next < addrs.len() {
+            let addr = addrs[next];
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let res =
+                    match timeout(connect_timeout, TcpStream::connect(addr))
+                        .await
+                    {
+                        Ok(res) => res,
+                        Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+                    };
+                let _ = tx.send((addr, res)).await;
+            });
+            next += 1;
+            pending += 1;
+        }
+
+        select! {
+            Some((addr, res)) = rx.recv() => {
+                pending -= 1;
+                match res {
+                    Ok(stream) => return Ok((stream, addr)),
+                    Err(e) => {
+                        last_err = Some(format!("{} {}", addr, e));
+                        if pending == 0 && next >= addrs.len() {
+                            break;
+                        }
+                    }
+                }
+            },
+            () = sleep(Duration::from_millis(ATTEMPT_DELAY)), if next < addrs.len() => {},
+            () = &mut deadline => bail!("链接超时 {:?}", connect_timeout),
+        }
+    }
+
+    match last_err {
+        Some(e) => bail!("链接失败 {}", e),
+        None => bail!("没有可用地址"),
+    }
+}
+
+async fn connect_direct(
+    address: &str, connect_timeout: Duration,
+) -> Result<(TcpStream, SocketAddr)> {
+    let addrs = interleave(resolve(address).await?);
+    let (stream, addr) = match happy_eyeballs(&addrs, connect_timeout).await {
+        Ok(res) => res,
+        Err(e) => bail!("{} {}", address, e),
+    };
+    stream.set_nodelay(true)?;
+    Ok((stream, addr))
+}
+
+// 解析并连接 host:port，经默认出口代理
+pub async fn connect(
+    address: &str, connect_timeout: Duration,
+) -> Result<(TcpStream, SocketAddr)> {
+    connect_via(address, connect_timeout, &default_via()).await
+}
+
+// 经指定出口代理连接 host:port。返回的地址为实际连接的代理地址
+pub async fn connect_via(
+    address: &str, connect_timeout: Duration, via: &Via,
+) -> Result<(TcpStream, SocketAddr)> {
+    let proxy = match via.address() {
+        Some(proxy) => proxy,
+        None => return connect_direct(address, connect_timeout).await,
+    };
+
+    let (mut stream, addr) = match connect_direct(proxy, connect_timeout).await
+    {
+        Ok(res) => res,
+        Err(e) => bail!("无法链接到代理 {}", e),
+    };
+    match timeout(connect_timeout, via.handshake(&mut stream, address)).await {
+        Ok(Ok(())) => Ok((stream, addr)),
+        Ok(Err(e)) => bail!("{} 经代理 {} 链接失败 {}", address, proxy, e),
+        Err(_) => bail!("{} 经代理 {} 链接超时", address, proxy),
+    }
+}
+
+pub async fn tls_handshake(
+    stream: TcpStream, address: &str, handshake_timeout: Duration,
+    policy: &TlsPolicy,
+) -> Result<TlsStream<TcpStream>> {
+    let cx = tokio_native_tls::TlsConnector::from(policy.connector()?);
+
+    let domain = match address.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => address,
+    };
+    let handshake = cx.connect(domain, stream);
+    let stream = match timeout(handshake_timeout, handshake).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => bail!("{} SSL 握手失败 {}", address, e),
+        Err(_) => bail!("{} SSL 握手超时", address),
+    };
+
+    if let Err(e) = policy.verify_peer(stream.get_ref().peer_certificate()?) {
+        bail!("{} SSL 校验失败 {}", address, e);
+    }
+    Ok(stream)
+}
+
+#[test]
+fn test_interleave() {
+    let addrs: Vec<SocketAddr> = vec![
+        "[2001:db8::1]:4444".parse().unwrap(),
+        "[2001:db8::2]:4444".parse().unwrap(),
+        "192.0.2.1:4444".parse().unwrap(),
+        "192.0.2.2:4444".parse().unwrap(),
+        "192.0.2.3:4444".parse().unwrap(),
+    ];
+    let out = interleave(addrs.clone());
+    assert_eq!(out, vec![
+        addrs[0], addrs[2], addrs[1], addrs[3], addrs[4]
+    ]);
+
+    let v4: Vec<SocketAddr> = vec![addrs[2], addrs[3]];
+    assert_eq!(interleave(v4.clone()), v4);
+}
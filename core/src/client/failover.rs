@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        split, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        DuplexStream, WriteHalf,
+    },
+    select,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    client::{
+        dial,
+        health::{candidates, mark_down, mark_up},
+        jobs, pool_scheme_type,
+        trust::TlsPolicy,
+        upstream::{connect_endpoint, is_stratum, PoolStream},
+        via::Via,
+    },
+    protocol::CLIENT_LOGIN,
+};
+
+// 矿池故障转移。
+// 矿机连接的矿池由本地桥接维持，矿池中途断开时按配置顺序切换到下一个
+// 健康矿池，重放登录与 eth_getWork，矿机侧连接保持不变。
+
+// 切换矿池最多尝试的轮数
+const RECONNECT_ROUNDS: u32 = 3;
+const RECONNECT_WAIT: u64 = 5;
+const BRIDGE_BUFFER: usize = 64 * 1024;
+
+// 一个配置的矿池地址，如 ssl://asia2.ethermine.org:5555?timeout=5
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolEndpoint {
+    pub url: String,
+    pub stream_type: i32,
+    pub address: String,
+    // 连接及 SSL 握手超时
+    pub timeout: Duration,
+    // SSL 校验策略，见 trust.rs
+    pub tls: TlsPolicy,
+    // 出口代理。None 时使用配置中的 via
+    pub via: Option<Via>,
+}
+
+impl PoolEndpoint {
+    // 地址后以 ? 附加的矿池设置
+    fn set_option(&mut self, key: &str, value: &str) -> Result<()> {
+        match self.tls.set_option(key, value) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => bail!("矿池 {} {}", self.url, e),
+        }
+        match key {
+            "timeout" => match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => {
+                    self.timeout = Duration::from_secs(secs)
+                }
+                _ => bail!("矿池 {} 超时设置不正确 {}", self.url, value),
+            },
+            "via" => match Via::parse(value) {
+                Ok(via) => self.via = Some(via),
+                Err(e) => bail!("矿池 {} {}", self.url, e),
+            },
+            _ => bail!("矿池 {} 不支持的设置 {}", self.url, key),
+        }
+        Ok(())
+    }
+}
+
+// 按配置顺序解析全部矿池地址，每个地址单独识别协议
+pub fn parse_pools(address: &[String]) -> Result<Vec<PoolEndpoint>> {
+    let mut pools = vec![];
+    for url in address {
+        let url = url.trim();
+        if url.is_empty() {
+            continue;
+        }
+        let (scheme, host) = match url.split_once("//") {
+            Some(s) => s,
+            None => bail!("矿池地址格式不正确 {}", url),
+        };
+        let stream_type = match pool_scheme_type(&scheme.to_lowercase()) {
+            Some(t) => t,
+            None => bail!("代理矿池{} 不支持的服务类型 {}", url, scheme),
+        };
+        let (host, options) = match host.split_once('?') {
+            Some((host, options)) => (host, options),
+            None => (host, ""),
+        };
+        let mut pool = PoolEndpoint {
+            url: url.to_string(),
+            stream_type,
+            address: host.to_string(),
+            timeout: dial::default_timeout(),
+            tls: TlsPolicy::default(),
+            via: None,
+        };
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            pool.set_option(key, value)?;
+        }
+        pools.push(pool);
+    }
+
+    if pools.is_empty() {
+        bail!("中转池地址设置存在错误请检查");
+    }
+    Ok(pools)
+}
+
+// 按主备顺序 (开启 pool_score 时按评分) 依次连接，冷却中的矿池最后尝试。
+// 返回连接、NiceHash extranonce 及矿池序号
+pub async fn connect_ordered(
+    pools: &[PoolEndpoint],
+) -> Result<(PoolStream, Option<String>, usize)> {
+    for idx in candidates(pools) {
+        let pool = &pools[idx];
+        match connect_endpoint(pool).await {
+            Ok((stream, extranonce)) => {
+                mark_up(pool);
+                return Ok((stream, extranonce, idx));
+            }
+            Err(e) => {
+                warn!("矿池 {} 无法链接 {}", pool.url, e);
+                mark_down(pool, &e.to_string());
+            }
+        }
+    }
+    bail!("所有矿池均不可链接。请修改后重试")
+}
+
+// 建立带故障转移的矿池连接。返回的连接与普通矿池连接用法一致。
+// send_cached 为 true 时矿机登录成功后立即下发该矿池缓存的任务
+pub async fn connect_failover(
+    pools: Vec<PoolEndpoint>, send_cached: bool,
+) -> Result<(PoolStream, Option<String>)> {
+    let (stream, extranonce, idx) = connect_ordered(&pools).await?;
+    let (local, remote) = tokio::io::duplex(BRIDGE_BUFFER);
+
+    let pool_extranonce = extranonce.clone();
+    tokio::spawn(async move {
+        if let Err(e) =
+            relay(remote, pools, stream, idx, pool_extranonce, send_cached)
+                .await
+        {
+            debug!("故障转移连接结束: {}", e);
+        }
+    });
+
+    Ok((Box::new(local), extranonce))
+}
+
+async fn write_line<W>(w: &mut WriteHalf<W>, line: &str) -> Result<()>
+where W: AsyncWrite {
+    w.write_all(line.as_bytes()).await?;
+    w.write_all(b"\n").await?;
+    Ok(())
+}
+
+// 登录名 钱包.矿机名，用于区分任务缓存
+fn login_name(line: &str) -> Option<String> {
+    let rpc = serde_json::from_str::<Value>(line).ok()?;
+    let wallet = rpc["params"][0].as_str()?;
+    match rpc["worker"].as_str() {
+        Some(w) if !w.is_empty() && !wallet.contains('.') => {
+            Some(format!("{}.{}", wallet, w).to_lowercase())
+        }
+        _ => Some(wallet.to_lowercase()),
+    }
+}
+
+fn login_id(line: &str) -> Option<Value> {
+    let rpc = serde_json::from_str::<Value>(line).ok()?;
+    if rpc["method"].as_str() == Some("eth_submitLogin") {
+        Some(rpc["id"].clone())
+    } else {
+        None
+    }
+}
+
+// 矿池推送的任务
+fn job(rpc: &Value) -> Option<Vec<String>> {
+    let job = rpc["result"].as_array()?;
+    job.iter().map(|v| v.as_str().map(String::from)).collect()
+}
+
+fn is_login_reply(line: &str) -> bool {
+    match serde_json::from_str::<Value>(line) {
+        Ok(rpc) => {
+            rpc["id"].as_u64() == Some(CLIENT_LOGIN)
+                && rpc.get("result").is_some()
+        }
+        Err(_) => false,
+    }
+}
+
+async fn reconnect(
+    pools: &[PoolEndpoint],
+) -> Result<(PoolStream, Option<String>, usize)> {
+    for round in 0..RECONNECT_ROUNDS {
+        match connect_ordered(pools).await {
+            Ok(res) => return Ok(res),
+            Err(e) => {
+                if round + 1 == RECONNECT_ROUNDS {
+                    return Err(e);
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(RECONNECT_WAIT)).await;
+    }
+    bail!("所有矿池均不可链接")
+}
+
+// 在矿机与当前矿池之间转发。矿池断开后重连并重放登录
+async fn relay(
+    client: DuplexStream, pools: Vec<PoolEndpoint>, mut stream: PoolStream,
+    mut idx: usize, mut extranonce: Option<String>, send_cached: bool,
+) -> Result<()> {
+    let (client_r, mut client_w) = split(client);
+    let mut client_lines = BufReader::new(client_r).lines();
+    let mut login: Option<String> = None;
+    // 等待回复的登录请求 id
+    let mut pending_login: Option<Value> = None;
+
+    loop {
+        let (pool_r, mut pool_w) = split(stream);
+        let mut pool_lines = BufReader::new(pool_r).lines();
+
+        // 重放登录后矿池的登录回复不再转给矿机，避免重复统计
+        let mut swallow_login = false;
+        if let Some(login) = &login {
+            write_line(&mut pool_w, login).await?;
+            let get_work = json!({
+                "id": 0,
+                "jsonrpc": "2.0",
+                "method": "eth_getWork",
+                "params": [],
+            });
+            write_line(&mut pool_w, &get_work.to_string()).await?;
+            swallow_login = true;
+        }
+
+        let err = loop {
+            select! {
+                res = client_lines.next_line() => {
+                    let line = match res {
+                        Ok(Some(line)) => line,
+                        _ => return Ok(()),
+                    };
+                    if let Some(id) = login_id(&line) {
+                        login = Some(line.clone());
+                        pending_login = Some(id);
+                    }
+                    if let Err(e) = write_line(&mut pool_w, &line).await {
+                        break e;
+                    }
+                },
+                res = pool_lines.next_line() => {
+                    let line = match res {
+                        Ok(Some(line)) => line,
+                        Ok(None) => break anyhow!("矿池主动断开"),
+                        Err(e) => break e.into(),
+                    };
+                    if swallow_login && is_login_reply(&line) {
+                        swallow_login = false;
+                        continue;
+                    }
+                    write_line(&mut client_w, &line).await?;
+
+                    // NiceHash 矿池的任务编号只在本连接有效，不缓存
+                    let pool = &pools[idx];
+                    if is_stratum(pool.stream_type) {
+                        continue;
+                    }
+                    let rpc: Value = match serde_json::from_str(&line) {
+                        Ok(rpc) => rpc,
+                        Err(_) => continue,
+                    };
+                    let name = match login.as_deref().and_then(login_name) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    if let Some(job) = job(&rpc) {
+                        jobs::update(&pool.url, &name, &job);
+                    } else if pending_login.as_ref() == Some(&rpc["id"]) {
+                        pending_login = None;
+                        let logged_in = rpc["result"].as_bool() == Some(true);
+                        if !send_cached || !logged_in {
+                            continue;
+                        }
+                        if let Some(cached) = jobs::latest(&pool.url, &name) {
+                            let job = json!({
+                                "id": 0,
+                                "jsonrpc": "2.0",
+                                "result": cached.job,
+                            });
+                            write_line(&mut client_w, &job.to_string()).await?;
+                        }
+                    }
+                },
+            }
+        };
+
+        let pool = &pools[idx];
+        warn!("矿池 {} 断开: {} 切换矿池", pool.url, err);
+        mark_down(pool, &err.to_string());
+
+        let (new_stream, new_extranonce, new_idx) = reconnect(&pools).await?;
+        info!("切换到矿池 {}", pools[new_idx].url);
+
+        // NiceHash 矿池分配了新的 extranonce
+        if let Some(e) = &new_extranonce {
+            if extranonce.as_ref() != Some(e) {
+                let notify = json!({
+                    "id": null,
+                    "method": "mining.set_extranonce",
+                    "params": [e],
+                });
+                write_line(&mut client_w, &notify.to_string()).await?;
+            }
+        }
+
+        stream = new_stream;
+        extranonce = new_extranonce;
+        idx = new_idx;
+    }
+}
+
+#[test]
+fn test_parse_pools() {
+    let pools = parse_pools(&[
+        "tcp://a.test:4444".to_string(),
+        "ssl://b.test:5555".to_string(),
+        "stratum+tcp://c.test:3333".to_string(),
+    ])
+    .unwrap();
+    assert_eq!(pools.len(), 3);
+    assert_eq!(pools[1].stream_type, crate::client::SSL);
+    assert_eq!(pools[2].stream_type, crate::client::STRATUM_TCP);
+    assert_eq!(pools[2].address, "c.test:3333");
+    assert_eq!(pools[0].timeout, dial::default_timeout());
+
+    let pools = parse_pools(&["tcp://a.test:4444?timeout=3".to_string()]).unwrap();
+    assert_eq!(pools[0].address, "a.test:4444");
+    assert_eq!(pools[0].timeout, Duration::from_secs(3));
+    assert!(parse_pools(&["tcp://a.test:4444?timeout=0".to_string()]).is_err());
+    assert!(parse_pools(&["tcp://a.test:4444?foo=1".to_string()]).is_err());
+
+    let pools = parse_pools(&[
+        "ssl://a.test:5555?tls=insecure&timeout=3".to_string(),
+    ])
+    .unwrap();
+    assert!(pools[0].tls.insecure);
+    assert_eq!(pools[0].timeout, Duration::from_secs(3));
+    assert!(!parse_pools(&["ssl://b.test:5555".to_string()]).unwrap()[0]
+        .tls
+        .insecure);
+
+    let pools = parse_pools(&[
+        "tcp://a.test:4444?via=socks5://u:p@10.0.0.1:1080&timeout=3".to_string(),
+        "tcp://b.test:4444?via=direct".to_string(),
+    ])
+    .unwrap();
+    assert_eq!(pools[0].address, "a.test:4444");
+    assert_eq!(pools[0].via, Some(Via::Socks5 {
+        address: "10.0.0.1:1080".into(),
+        auth: Some(("u".into(), "p".into())),
+    }));
+    assert_eq!(pools[1].via, Some(Via::Direct));
+    assert!(parse_pools(&["tcp://a.test:4444?via=ftp://x:1".to_string()]).is_err());
+
+    assert!(parse_pools(&["foo://a:1".to_string()]).is_err());
+}
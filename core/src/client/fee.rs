@@ -1,18 +1,23 @@
-use std::sync::{Arc, RwLockReadGuard};
+use std::{
+    sync::{Arc, RwLockReadGuard},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 
 use tokio::{
     io::{AsyncRead, AsyncWrite, BufReader, Lines, WriteHalf},
     select,
-    sync::mpsc::Receiver,
+    sync::mpsc::{self, Receiver},
     sync::RwLockWriteGuard,
 };
 
 use crate::{
-    protocol::ethjson::EthClientObject,
-    proxy::{Job, Proxy},
-    util::config::Settings,
+    client::upstream::{PoolLines, PoolWriter},
+    protocol::{eth_stratum::EthStratumSetExtranonce, ethjson::EthClientObject},
+    proxy::{Extranonce, FeeShare, Job, Proxy},
+    state::ledger::PendingShares,
+    util::config::FeeDestination,
 };
 
 use crate::{
@@ -25,189 +30,91 @@ use crate::{
 
 use super::write_to_socket_byte;
 
-use tracing::{debug, info};
-
-pub async fn develop_fee_ssl(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<
-            tokio::io::ReadHalf<
-                tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-            >,
-        >,
-    >,
-    mut w: tokio::io::WriteHalf<
-        tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    >,
-    worker_name: String, proxy: Arc<Proxy>,
-) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
-    }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
+use tracing::{debug, info, warn};
 
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
+// 抽水去向登录失败后的重试间隔，每次翻倍
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
 
-    let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
-    tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
+pub async fn develop_fee(
+    rx: Receiver<FeeShare>, job: Job, proxy_lines: PoolLines,
+    w: PoolWriter, worker_name: String, proxy: Arc<Proxy>,
+) -> Result<()> {
+    let extranonce = proxy.develop_extranonce.clone();
+    fee_loop(rx, job, extranonce, proxy_lines, w, worker_name, None).await
+}
 
+// 登录抽水去向，失败时按退避间隔一直重试。
+// 期间该去向没有任务，抽水任务由其它去向承担
+async fn login_retry(
+    dest: &FeeDestination, worker_name: &String,
+) -> (PoolLines, PoolWriter) {
+    let mut delay = RETRY_MIN;
     loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
-
-                        let (dev_lines, dev_w) =
-                            crate::client::dev_pool_ssl_login(worker_name.clone())
-                                .await?;
-
-                        //同时加2个值
-                        w = dev_w;
-                        proxy_lines = dev_lines;
-                        info!(worker_name = ?worker_name,"重新登录成功!!");
-
-                        continue;
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-                    {
-                    let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-                    j.push_back(job_res)
-                    }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
-                    }
-                }
-            },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-                json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
-            },
-            () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
-                sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
-            },
+        match crate::client::proxy_pool_login(dest, worker_name.clone()).await
+        {
+            Ok(conn) => return conn,
+            Err(e) => warn!(
+                "抽水去向 {} 登录失败，{}秒后重试: {}",
+                worker_name,
+                delay.as_secs(),
+                e
+            ),
         }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RETRY_MAX);
     }
-
-    Ok(())
 }
 
-pub async fn fee_ssl(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<
-            tokio::io::ReadHalf<
-                tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-            >,
-        >,
-    >,
-    mut w: tokio::io::WriteHalf<
-        tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    >,
-    worker_name: String, proxy: Arc<Proxy>,
+// 为每个抽水去向登录矿池并转发抽水份额。rxs 与 proxy.fee_pools 一一对应。
+// 单个去向不可用时只影响该去向
+pub async fn proxy_fees(
+    rxs: Vec<Receiver<FeeShare>>, proxy: Arc<Proxy>,
 ) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
-    }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
-
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
-
-    let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
-    tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
-
-    loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
-                        //return Err(anyhow!("抽水旷工掉线了a"));
-                        // info!(worker_name =
-            //       ?worker_name,"退出了。重新登录到池!!");
-             let (new_lines, dev_w) = crate::client::proxy_pool_login_with_ssl(&config,config.share_name.clone()).await?;
-                        //同时加2个值
-                        w = dev_w;
-                        proxy_lines = new_lines;
-                        info!(worker_name = ?worker_name,"重新登录成功!!");
-
-                        continue;
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
+    let config = proxy.config.read().await.clone();
+    let (err_tx, mut err_rx) = mpsc::unbounded_channel::<anyhow::Error>();
+
+    let dests = config.fee_destinations();
+    for (idx, (rx, dest)) in rxs.into_iter().zip(dests).enumerate() {
+        let worker_name = if dest.name.is_empty() {
+            config.get_share_name()?
+        } else {
+            dest.name.clone()
+        };
+        let job = proxy.fee_pools[idx].job.clone();
+        let extranonce = proxy.fee_pools[idx].extranonce.clone();
+        let err_tx = err_tx.clone();
+        tokio::spawn(async move {
+            let (proxy_lines, w) = login_retry(&dest, &worker_name).await;
+            let res = fee_loop(
+                rx,
+                job,
+                extranonce,
+                proxy_lines,
+                w,
+                worker_name,
+                Some(dest),
+            )
+            .await;
+            if let Err(e) = res {
+                err_tx.send(e).ok();
             }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
-                    }
-                }
-            },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
-            },
-            () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
-                sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
-            },
-        }
+        });
     }
+    drop(err_tx);
 
-    Ok(())
+    match err_rx.recv().await {
+        Some(e) => Err(e),
+        None => Err(anyhow!("抽水线程异常退出")),
+    }
 }
-pub async fn fee_tcp(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<tokio::io::ReadHalf<tokio::net::TcpStream>>,
-    >,
-    mut w: tokio::io::WriteHalf<tokio::net::TcpStream>, worker_name: String,
-    proxy: Arc<Proxy>,
+
+// dest 为 None 时为开发者抽水
+async fn fee_loop(
+    mut rx: Receiver<FeeShare>, job: Job, extranonce: Extranonce,
+    mut proxy_lines: PoolLines, mut w: PoolWriter, worker_name: String,
+    dest: Option<FeeDestination>,
 ) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
-    }
     let mut get_work = EthClientRootObject {
         id: 6,
         method: "eth_getWork".into(),
@@ -224,51 +131,67 @@ pub async fn fee_tcp(
     let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
     tokio::pin!(sleep);
     let mut share_job_idx: u64 = 0;
+    let mut pending = PendingShares::new();
 
     loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"矿池").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
-
-             let (new_lines, dev_w) = crate::client::proxy_pool_login(&config,config.share_name.clone()).await?;
-                        //同时加2个值
-                        w = dev_w;
-                        proxy_lines = new_lines;
-                        info!(worker_name = ?worker_name,"重新登录成功!!");
-
-                        continue;
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
+        // 连接是否已断开
+        let lost = select! {
+            res = proxy_lines.next_line() => match lines_unwrap(res,&worker_name,"矿池").await {
+                Ok(buffer) => {
+                    #[cfg(debug_assertions)]
+                    debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
+                    if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
+                        if let Some(job_res) = job_rpc.get_job_result() {
+                            job.push(job_res);
+                        }
+                    } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
+                        pending.resolve(result_rpc.id, result_rpc.result);
+                        if !result_rpc.result {
+                            tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
+                        }
+                    } else if let Ok(rpc) = serde_json::from_str::<EthStratumSetExtranonce>(&buffer) {
+                        if rpc.method == "mining.set_extranonce" {
+                            let mut e = RwLockWriteGuard::map(extranonce.write().await, |f| f);
+                            *e = rpc.params.first().cloned();
+                        }
                     }
-                }
+                    false
+                },
+                Err(_) => true,
             },
-            Some(params) = rx.recv() => {
+            Some(share) = rx.recv() => {
                 share_job_idx+=1;
                 json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
+                json_rpc.params = share.params;
+                pending.push(share_job_idx, share.record);
+                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await.is_err()
             },
             () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
                 sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
+                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await.is_err()
             },
+        };
+        if !lost {
+            continue;
         }
-    }
 
-    Ok(())
+        // 旧连接的任务及 extranonce 已失效，重新登录前清空，避免继续下发
+        pending.clear();
+        job.clear();
+        {
+            let mut e = RwLockWriteGuard::map(extranonce.write().await, |f| f);
+            *e = None;
+        }
+        let (new_lines, new_w) = match &dest {
+            Some(dest) => login_retry(dest, &worker_name).await,
+            None => crate::client::dev_pool_ssl_login(worker_name.clone()).await?,
+        };
+
+        //同时加2个值
+        w = new_w;
+        proxy_lines = new_lines;
+        info!(worker_name = ?worker_name,"重新登录成功!!");
+    }
 }
 
 pub async fn fee<W: 'static, R: 'static>(
@@ -357,11 +280,9 @@ where
                 #[cfg(debug_assertions)]
                 debug!("1 :  矿池 -> 矿机 {} #{:?}",worker_name, buffer);
                 if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
+                    if let Some(job_res) = job_rpc.get_job_result() {
+                        job.push(job_res);
+                    }
                 } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
                     if result_rpc.result == false {
                         tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"线程获得操作结果 {:?}",result_rpc.result);
@@ -0,0 +1,536 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        split, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite,
+        AsyncWriteExt, BufReader, DuplexStream, WriteHalf,
+    },
+    select,
+    sync::{mpsc, oneshot, watch},
+    time,
+};
+use tracing::{debug, info};
+
+use crate::{
+    client::detect,
+    protocol::ethjson::{EthClientWorkerObject, EthServer, EthServerRootObject},
+    proxy::Proxy,
+    state::Worker,
+};
+
+// HTTP getwork 接入。
+// 每个 /钱包.矿工名 对应一个常驻会话，会话内部模拟一台 EthProxy 矿机走
+// handle_stream，抽水与矿工统计与 TCP 接入一致。
+// 矿机轮询 eth_getWork 时直接返回会话最新任务，不再转发给矿池。
+
+// 会话无请求多久后下线
+const SESSION_IDLE: u64 = 120;
+// 新会话等待首个任务的时间
+const JOB_WAIT: u64 = 5;
+// 等待提交结果的时间
+const SUBMIT_WAIT: u64 = 5;
+const MAX_HEADER_LINE: u64 = 4096;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY: usize = 64 * 1024;
+const DUPLEX_SIZE: usize = 64 * 1024;
+// 同时存在的会话上限，每个会话占用一个矿池连接
+const MAX_SESSIONS: usize = 1024;
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, Arc<GetworkSession>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct GetworkSession {
+    account: String,
+    tx: mpsc::UnboundedSender<String>,
+    job: watch::Receiver<Option<Vec<String>>>,
+    active: Mutex<Instant>,
+    next_id: AtomicU64,
+    // 等待结果的请求 id
+    waiting: Mutex<HashMap<u64, oneshot::Sender<bool>>>,
+}
+
+impl GetworkSession {
+    fn start(account: String, proxy: Arc<Proxy>) -> Arc<Self> {
+        let (local, remote) = tokio::io::duplex(DUPLEX_SIZE);
+        let (tx, rx) = mpsc::unbounded_channel::<String>();
+        let (job_tx, job) = watch::channel(None);
+
+        let session = Arc::new(Self {
+            account,
+            tx,
+            job,
+            active: Mutex::new(Instant::now()),
+            next_id: AtomicU64::new(1),
+            waiting: Mutex::new(HashMap::new()),
+        });
+
+        // 模拟矿机登录并拉取首个任务
+        session.send(
+            session.request_id(),
+            "eth_submitLogin",
+            vec![session.wallet(), "x".into()],
+        );
+        session.send(session.request_id(), "eth_getWork", vec![]);
+
+        tokio::spawn(run_worker(session.account.clone(), remote, proxy));
+        tokio::spawn(pump(session.clone(), local, rx, job_tx));
+        session
+    }
+
+    fn wallet(&self) -> String {
+        match self.account.split_once('.') {
+            Some((wallet, _)) => wallet.to_string(),
+            None => self.account.clone(),
+        }
+    }
+
+    fn worker_name(&self) -> String {
+        match self.account.split_once('.') {
+            Some((_, name)) => name.to_string(),
+            None => "default".into(),
+        }
+    }
+
+    fn touch(&self) { *self.active.lock().unwrap() = Instant::now(); }
+
+    fn idle(&self) -> u64 { self.active.lock().unwrap().elapsed().as_secs() }
+
+    fn request_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn send(&self, id: u64, method: &str, params: Vec<String>) -> bool {
+        let rpc = EthClientWorkerObject {
+            id,
+            method: method.into(),
+            params,
+            worker: self.worker_name(),
+        };
+        match serde_json::to_string(&rpc) {
+            Ok(line) => self.tx.send(line).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // 提交后等待内部矿机流返回该请求的结果
+    async fn submit(&self, method: &str, params: Vec<String>) -> bool {
+        let id = self.request_id();
+        let (tx, rx) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(id, tx);
+        if !self.send(id, method, params) {
+            self.waiting.lock().unwrap().remove(&id);
+            return false;
+        }
+        match time::timeout(Duration::from_secs(SUBMIT_WAIT), rx).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                self.waiting.lock().unwrap().remove(&id);
+                false
+            }
+        }
+    }
+
+    fn resolve(&self, line: &str) {
+        if let Ok(rpc) = serde_json::from_str::<EthServer>(line) {
+            if let Some(tx) = self.waiting.lock().unwrap().remove(&rpc.id) {
+                let _ = tx.send(rpc.result);
+            }
+        }
+    }
+
+    async fn job(&self) -> Option<Vec<String>> {
+        let mut job = self.job.clone();
+        let wait = async {
+            loop {
+                if let Some(job) = job.borrow().clone() {
+                    return Some(job);
+                }
+                if job.changed().await.is_err() {
+                    return None;
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(JOB_WAIT), wait)
+            .await
+            .unwrap_or(None)
+    }
+}
+
+// 会话内部的矿工，统计方式与 TCP 接入相同
+async fn run_worker(account: String, remote: DuplexStream, proxy: Arc<Proxy>) {
+    let mut worker: Worker = Worker::default();
+    let worker_tx = proxy.worker_tx.clone();
+    let (worker_r, worker_w) = split(remote);
+    let worker_r = BufReader::new(worker_r);
+    match detect::handle_pool(&mut worker, worker_r, worker_w, proxy, false)
+        .await
+    {
+        Ok(_) => {
+            if worker.is_online() {
+                worker.offline();
+                info!("HTTP: {} 安全下线", account);
+                worker_tx.send(worker).unwrap();
+            } else {
+                info!("HTTP: {} 下线", account);
+            }
+        }
+        Err(e) => {
+            if worker.is_online() {
+                worker.offline();
+                worker_tx.send(worker).unwrap();
+                info!("HTTP: {} 下线原因 {}", account, e);
+            } else {
+                debug!("HTTP: {} 链接断开: {}", account, e);
+            }
+        }
+    }
+}
+
+// 在 HTTP 请求与内部矿机流之间搬运数据，并记录最新任务
+async fn pump(
+    session: Arc<GetworkSession>, local: DuplexStream,
+    mut rx: mpsc::UnboundedReceiver<String>,
+    job_tx: watch::Sender<Option<Vec<String>>>,
+) {
+    let (local_r, mut local_w) = split(local);
+    let mut lines = BufReader::new(local_r).lines();
+    let mut tick = time::interval(Duration::from_secs(10));
+
+    loop {
+        select! {
+            Some(mut line) = rx.recv() => {
+                line.push('\n');
+                if local_w.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            },
+            res = lines.next_line() => {
+                match res {
+                    Ok(Some(line)) => {
+                        if let Ok(job) = serde_json::from_str::<EthServerRootObject>(&line) {
+                            let _ = job_tx.send(Some(job.result));
+                        } else {
+                            session.resolve(&line);
+                        }
+                    }
+                    _ => break,
+                }
+            },
+            _ = tick.tick() => {
+                if session.idle() > SESSION_IDLE {
+                    break;
+                }
+            },
+        }
+    }
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    if let Some(s) = sessions.get(&session.account) {
+        if Arc::ptr_eq(s, &session) {
+            sessions.remove(&session.account);
+        }
+    }
+}
+
+// 从请求路径取出 钱包.矿工名。支持 /钱包.矿工名 与 /钱包/矿工名
+fn account_from_path(path: &str) -> Option<String> {
+    let path = path.split('?').next().unwrap_or("");
+    let account = path.trim_matches('/').replace('/', ".");
+    if account.len() > 128
+        || !account.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-'
+        })
+    {
+        return None;
+    }
+    let wallet = account.split('.').next().unwrap_or("");
+    if !is_wallet(wallet) {
+        return None;
+    }
+    Some(account)
+}
+
+// 0x 开头的 40 位十六进制地址
+fn is_wallet(wallet: &str) -> bool {
+    match wallet.strip_prefix("0x") {
+        Some(hex) => {
+            hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+fn get_session(path: &str, proxy: &Arc<Proxy>) -> Result<Arc<GetworkSession>> {
+    let account = match account_from_path(path) {
+        Some(account) => account,
+        None => bail!("请在地址中填写钱包 如 /0x钱包.矿工名"),
+    };
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    if let Some(session) = sessions.get(&account) {
+        if !session.tx.is_closed() {
+            session.touch();
+            return Ok(session.clone());
+        }
+    }
+
+    sessions.retain(|_, session| !session.tx.is_closed());
+    if sessions.len() >= MAX_SESSIONS {
+        bail!("getwork 会话已满");
+    }
+
+    info!("HTTP: {} 新建 getwork 会话", account);
+    let session = GetworkSession::start(account.clone(), proxy.clone());
+    sessions.insert(account, session.clone());
+    Ok(session)
+}
+
+async fn read_request<R>(worker_r: &mut R) -> Result<Option<HttpRequest>>
+where R: AsyncBufRead + Unpin {
+    let mut line = String::new();
+    if (&mut *worker_r)
+        .take(MAX_HEADER_LINE)
+        .read_line(&mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+    let mut keep_alive = parts.next() == Some("HTTP/1.1");
+
+    let mut length = 0;
+    let mut headers = 0;
+    loop {
+        let mut header = String::new();
+        if (&mut *worker_r)
+            .take(MAX_HEADER_LINE)
+            .read_line(&mut header)
+            .await?
+            == 0
+        {
+            bail!("HTTP 请求头不完整");
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        headers += 1;
+        if headers > MAX_HEADERS {
+            bail!("HTTP 请求头过多");
+        }
+
+        if let Some((key, value)) = header.split_once(':') {
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    length = match value.parse::<usize>() {
+                        Ok(l) => l,
+                        Err(_) => bail!("Content-Length 不正确 {}", value),
+                    }
+                }
+                "connection" => {
+                    keep_alive = value.eq_ignore_ascii_case("keep-alive")
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if length > MAX_BODY {
+        bail!("HTTP 请求体过大 {}", length);
+    }
+    let mut body = vec![0u8; length];
+    worker_r.read_exact(&mut body).await?;
+
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        body,
+        keep_alive,
+    }))
+}
+
+fn rpc_error(id: Value, code: i32, message: &str) -> Value {
+    json!({"id": id, "jsonrpc": "2.0", "error": {"code": code, "message": message}})
+}
+
+fn rpc_result(id: Value, result: Value) -> Value {
+    json!({"id": id, "jsonrpc": "2.0", "result": result})
+}
+
+async fn handle_rpc(req: &HttpRequest, proxy: &Arc<Proxy>) -> Value {
+    let rpc: Value = match serde_json::from_slice(&req.body) {
+        Ok(rpc) => rpc,
+        Err(_) => return rpc_error(Value::Null, -32700, "Parse error"),
+    };
+    let id = rpc["id"].clone();
+    let params: Vec<String> = match rpc["params"].as_array() {
+        Some(params) => params
+            .iter()
+            .filter_map(|p| p.as_str().map(|p| p.to_string()))
+            .collect(),
+        None => vec![],
+    };
+
+    let method = rpc["method"].as_str().unwrap_or("");
+    if !matches!(method, "eth_getWork" | "eth_submitWork" | "eth_submitHashrate")
+    {
+        return rpc_error(id, -32601, "Method not found");
+    }
+
+    let session = match get_session(&req.path, proxy) {
+        Ok(session) => session,
+        Err(e) => return rpc_error(id, -32000, &e.to_string()),
+    };
+
+    match method {
+        "eth_getWork" => match session.job().await {
+            Some(job) => rpc_result(id, json!(job)),
+            None => rpc_error(id, -32000, "暂无任务"),
+        },
+        "eth_submitWork" => {
+            if params.len() != 3 {
+                return rpc_error(id, -32602, "Invalid params");
+            }
+            let result = session.submit("eth_submitWork", params).await;
+            rpc_result(id, json!(result))
+        }
+        "eth_submitHashrate" => {
+            if params.len() != 2 {
+                return rpc_error(id, -32602, "Invalid params");
+            }
+            let result = session.submit("eth_submitHashrate", params).await;
+            rpc_result(id, json!(result))
+        }
+        _ => rpc_error(id, -32601, "Method not found"),
+    }
+}
+
+async fn write_response<W>(
+    worker_w: &mut WriteHalf<W>, status: &str, body: &Value, keep_alive: bool,
+) -> Result<()>
+where W: AsyncWrite {
+    let body = serde_json::to_vec(body)?;
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: \
+         {}\r\nConnection: {}\r\n\r\n",
+        status,
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" }
+    );
+    worker_w.write_all(head.as_bytes()).await?;
+    worker_w.write_all(&body).await?;
+    worker_w.flush().await?;
+    Ok(())
+}
+
+// 处理一个 HTTP 连接。支持 keep-alive 连续请求
+pub async fn handle_getwork<R, W>(
+    mut worker_r: R, mut worker_w: WriteHalf<W>, proxy: Arc<Proxy>,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite,
+{
+    loop {
+        let req = match read_request(&mut worker_r).await? {
+            Some(req) => req,
+            None => return Ok(()),
+        };
+
+        if req.method != "POST" {
+            let body = rpc_error(Value::Null, -32600, "仅支持 POST");
+            write_response(
+                &mut worker_w,
+                "405 Method Not Allowed",
+                &body,
+                false,
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let body = handle_rpc(&req, &proxy).await;
+        write_response(&mut worker_w, "200 OK", &body, req.keep_alive).await?;
+        if !req.keep_alive {
+            worker_w.shutdown().await?;
+            return Ok(());
+        }
+    }
+}
+
+#[test]
+fn test_account_from_path() {
+    assert_eq!(
+        account_from_path("/0x98be5c44d574b96b320dffb0ccff116bda433b8e.rig1"),
+        Some("0x98be5c44d574b96b320dffb0ccff116bda433b8e.rig1".into())
+    );
+    assert_eq!(
+        account_from_path(
+            "/0x98be5c44d574b96b320dffb0ccff116bda433b8e/rig1?x=1"
+        ),
+        Some("0x98be5c44d574b96b320dffb0ccff116bda433b8e.rig1".into())
+    );
+    assert_eq!(account_from_path("/"), None);
+    assert_eq!(account_from_path("/0x12 34"), None);
+    assert_eq!(account_from_path("/rig1"), None);
+    assert_eq!(account_from_path("/0x1234.rig1"), None);
+    assert_eq!(
+        account_from_path("/0x98be5c44d574b96b320dffb0ccff116bda433b8g.rig1"),
+        None
+    );
+    assert_eq!(
+        account_from_path("/0x98be5c44d574b96b320dffb0ccff116bda433b8e"),
+        Some("0x98be5c44d574b96b320dffb0ccff116bda433b8e".into())
+    );
+}
+
+#[test]
+fn test_submit_result() {
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let (_job_tx, job) = watch::channel(None);
+    let session = GetworkSession {
+        account: "0x98be5c44d574b96b320dffb0ccff116bda433b8e.rig1".into(),
+        tx,
+        job,
+        active: Mutex::new(Instant::now()),
+        next_id: AtomicU64::new(1),
+        waiting: Mutex::new(HashMap::new()),
+    };
+
+    let (accept_tx, mut accept) = oneshot::channel();
+    let (reject_tx, mut reject) = oneshot::channel();
+    session.waiting.lock().unwrap().insert(2, accept_tx);
+    session.waiting.lock().unwrap().insert(3, reject_tx);
+
+    session.resolve(r#"{"id":3,"jsonrpc":"2.0","result":false}"#);
+    session.resolve(r#"{"id":9,"jsonrpc":"2.0","result":true}"#);
+    session.resolve(r#"{"id":2,"jsonrpc":"2.0","result":true}"#);
+    assert_eq!(reject.try_recv(), Ok(false));
+    assert_eq!(accept.try_recv(), Ok(true));
+    assert!(session.waiting.lock().unwrap().is_empty());
+}
@@ -1,9 +1,13 @@
 use anyhow::{bail, Result};
+use serde_json::{json, Value};
 use std::sync::Arc;
 use tracing::{debug, info};
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt,
+        WriteHalf,
+    },
     select,
     sync::RwLockReadGuard,
     time,
@@ -12,11 +16,32 @@ use tokio::{
 use crate::{
     client::*,
     protocol::{
+        eth_stratum::{
+            difficulty_to_target, next_extranonce, target_to_difficulty,
+            EthStratumReply, EthStratumSession, EthStratumSetExtranonce,
+        },
         ethjson::{EthServerRoot, EthServerRootObject},
-        CLIENT_LOGIN, CLIENT_SUBMITWORK,
+        CLIENT_LOGIN, CLIENT_SUBMITWORK, CLIENT_SUGGEST_DIFFICULTY,
+        PROTOCOL,
+    },
+    client::handle_stream_timer::FeeTimer,
+    proxy::fee::{FEE_WINDOW, SHARE_ALG_CONTROL, SHARE_ALG_TIMER},
+    proxy::FeeShare,
+    state::{
+        ledger::{PendingShares, ShareRecord},
+        share::{
+            ShareCheck, ShareStatus, SHARE_POLICY_DISCONNECT,
+            SHARE_POLICY_DROP,
+        },
+        vardiff::VarDiff,
+        work::{HashrateMeter, JobDifficulty, WorkKind, WorkWindow},
+        Worker,
+    },
+    util::{
+        config::Settings,
+        ethash::{self, Verify},
+        is_fee_random,
     },
-    state::Worker,
-    util::{config::Settings, is_fee_random},
 };
 
 use crate::{
@@ -28,14 +53,13 @@ use crate::{
 };
 
 pub async fn handle_stream<R, W, PR, PW>(
-    worker: &mut Worker,
-    worker_r: tokio::io::BufReader<tokio::io::ReadHalf<R>>,
-    mut worker_w: WriteHalf<W>,
+    worker: &mut Worker, worker_r: R, mut worker_w: WriteHalf<W>,
     pool_r: tokio::io::BufReader<tokio::io::ReadHalf<PR>>,
     mut pool_w: WriteHalf<PW>, proxy: Arc<Proxy>, is_encrypted: bool,
+    mut pool_extranonce: Option<String>,
 ) -> Result<()>
 where
-    R: AsyncRead,
+    R: AsyncBufRead + Unpin,
     W: AsyncWrite,
     PR: AsyncRead,
     PW: AsyncWrite,
@@ -53,9 +77,24 @@ where
         result: vec![],
     };
 
-    let mut fee_job: Vec<String> = Vec::new();
+    // 抽水任务及其去向
+    let mut fee_job: Vec<(String, usize)> = Vec::new();
     let mut dev_fee_job: Vec<String> = Vec::new();
 
+    // 下发任务的难度，用于按难度统计工作量
+    let mut job_diff = JobDifficulty::new();
+    // 本矿机近期的工作量，用于闭环抽水调度
+    let mut fee_window = WorkWindow::new(FEE_WINDOW);
+    // 等待矿池结果的普通份额，结果返回后写入账本
+    let mut pending = PendingShares::new();
+    // 按已接受份额计算的有效算力
+    let mut hashrate = HashrateMeter::new();
+    // 下发任务及已提交的 nonce，用于识别重复、未知及过期份额
+    let mut share_check = ShareCheck::new();
+
+    // EthereumStratum/1.0.0 (NiceHash) 矿机会话
+    let mut stratum: Option<EthStratumSession> = None;
+
     //最后一次发送的rpc_id
     let mut rpc_id = 0;
 
@@ -81,8 +120,6 @@ where
 
     // let mut chan = proxy.chan.subscribe();
     // let mut dev_chan = proxy.dev_chan.subscribe();
-    let tx = proxy.tx.clone();
-    let dev_tx = proxy.dev_tx.clone();
 
     // 当前Job高度。
     let _job_hight = 0;
@@ -99,6 +136,16 @@ where
         let rconfig = RwLockReadGuard::map(proxy.config.read().await, |s| s);
         config = rconfig.clone();
     }
+    // 矿机适用的抽水比例，登录后按抽水规则确定
+    let mut share_rate: f64 = config.share_rate.into();
+    // 按时间段抽水的计划
+    let mut fee_timer = FeeTimer::new(config.fee_window,config.share_rate.into(),*DEVELOP_FEE);
+    // 中转对矿机的可变难度
+    let mut vardiff = if config.vardiff > 0 {
+        Some(VarDiff::new(config.vardiff))
+    } else {
+        None
+    };
 
     loop {
         select! {
@@ -110,37 +157,65 @@ where
                         rpc_id = json_rpc.get_id();
                         let res = match json_rpc.get_method().as_str() {
                             "eth_submitLogin" => {
+                                worker.set_protocol(PROTOCOL::ETH);
                                 eth_server_result.id = rpc_id;
+                                if let Err(e) = eth_proxy_pool(&pool_extranonce) {
+                                    write_rpc(is_encrypted,&mut worker_w,&EthServerRoot{id: rpc_id, jsonrpc: "2.0".into(), result: false},&worker_name).await?;
+                                    return Err(e);
+                                }
                                 login(worker,&mut pool_w,&mut json_rpc,&mut worker_name,&config).await?;
+                                share_rate = config.fee_rate(&worker.worker_wallet,&worker.worker_name).into();
+                                fee_timer.set_fee_rate(share_rate);
                                 write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
                                 Ok(())
                             },
                             "eth_submitWork" => {
                                 eth_server_result.id = rpc_id;
-                                if let Some(job_id) = json_rpc.get_job_id() {
+                                if json_rpc.get_job_id().is_some() {
                                     #[cfg(debug_assertions)]
                                     debug!("0 :  收到提交工作量 {} #{:?}",worker_name, json_rpc);
-                                    let mut json_rpc = Box::new(EthClientWorkerObject{ id: json_rpc.get_id(), method: json_rpc.get_method(), params: json_rpc.get_params(), worker: worker.worker_name.clone()});
-                    if dev_fee_job.contains(&job_id) {
-//                    debug!("0 :  收到开发者工作量 {} #{:?}",worker_name, json_rpc);
-                                        match dev_tx.try_send(json_rpc.get_params()){
-                        Ok(_) => {},
-                        Err(e)=> {
-                        debug!("开发者通道已满.{}",e);
-                        },
-                    }
-                                    } else if fee_job.contains(&job_id) {
-                                        worker.fee_share_index_add();
-                                        worker.fee_share_accept();
-                    match tx.try_send(json_rpc.get_params()) {
-                        Ok(()) => {},
-                        Err(e) => {
-                        debug!("中转通道已满.{}",e);
-                        },
-                    }
-                                    } else {
-                                        worker.share_index_add();
-                                        new_eth_submit_work(worker,&mut pool_w,&mut worker_w,&mut json_rpc,&worker_name,&config).await?;
+                                    let params = json_rpc.get_params();
+                                    if params.len() >= 2 {
+                                        let status = share_check.check(&params[1],&params[0]);
+                                        if !share_policy(worker,&config,status,&worker_name)? {
+                                            write_rpc(is_encrypted,&mut worker_w,&EthServerRoot{id: rpc_id, jsonrpc: "2.0".into(), result: false},&worker_name).await?;
+                                            continue;
+                                        }
+                                    }
+                                    // 可变难度下份额已按矿机难度校验
+                                    let mut share_diff = None;
+                                    if let (Some(vardiff), true) = (vardiff.as_mut(), params.len() >= 3) {
+                                        let job = share_check.job(&params[1]);
+                                        match vardiff_verify(&config,vardiff,job,&params[0],&params[2]).await {
+                                            VarShare::Forward(diff) => share_diff = Some(diff),
+                                            VarShare::Local(diff) => {
+                                                hashrate.add(diff);
+                                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                                                continue;
+                                            }
+                                            VarShare::Invalid => {
+                                                worker.local_invalid_add();
+                                                tracing::warn!("{} 份额未达到矿机难度",worker_name);
+                                                write_rpc(is_encrypted,&mut worker_w,&EthServerRoot{id: rpc_id, jsonrpc: "2.0".into(), result: false},&worker_name).await?;
+                                                continue;
+                                            }
+                                        }
+                                    } else if config.verify_share && params.len() >= 3 {
+                                        let job = share_check.job(&params[1]);
+                                        if !verify_share(worker,&config,job,&params[0],&params[2],&worker_name).await {
+                                            write_rpc(is_encrypted,&mut worker_w,&EthServerRoot{id: rpc_id, jsonrpc: "2.0".into(), result: false},&worker_name).await?;
+                                            continue;
+                                        }
+                                    }
+                                    let json_rpc = Box::new(EthClientWorkerObject{ id: json_rpc.get_id(), method: json_rpc.get_method(), params, worker: worker.worker_name.clone()});
+                                    let (kind, diff) = submit_work(worker,json_rpc,&job_diff,&dev_fee_job,&fee_job,&proxy,&mut pool_w,&mut worker_w,&worker_name,&config,share_rate,&mut pending).await?;
+                                    fee_window.add(kind, diff);
+                                    proxy.fee_scheduler.record(kind, diff, share_rate, *DEVELOP_FEE);
+                                    // 抽水份额不等待矿池结果
+                                    if let Some(diff) = share_diff {
+                                        hashrate.add(diff);
+                                    } else if kind != WorkKind::Normal {
+                                        hashrate.add(diff);
                                     }
 
                                     write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
@@ -166,12 +241,127 @@ where
                                 // write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
                                 Ok(())
                             },
-                            "mining.subscribe" =>{ //GMiner
-                                new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
-                                eth_server_result.id = rpc_id;
-                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                            "mining.subscribe" => {
+                                if json_rpc.is_protocol_eth_statum() {
+                                    worker.set_protocol(PROTOCOL::NICEHASHSTRATUM);
+                                    // NiceHash 矿池使用矿池分配的 extranonce
+                                    let extranonce = match &pool_extranonce {
+                                        Some(extranonce) => extranonce.clone(),
+                                        None => next_extranonce(),
+                                    };
+                                    let session = EthStratumSession::new(extranonce);
+                                    write_rpc(is_encrypted,&mut worker_w,&session.subscribe_result(rpc_id),&worker_name).await?;
+                                    stratum = Some(session);
+                                } else { //GMiner
+                                    eth_proxy_pool(&pool_extranonce)?;
+                                    new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
+                                    eth_server_result.id = rpc_id;
+                                    write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                                }
                                 Ok(())
-                            }
+                            },
+                            "mining.extranonce.subscribe" => {
+                                if let Some(session) = stratum.as_mut() {
+                                    session.extranonce_subscribed = true;
+                                }
+                                write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: true, error: Value::Null},&worker_name).await?;
+                                Ok(())
+                            },
+                            "mining.authorize" => {
+                                if stratum.is_none() {
+                                    pool_w.shutdown().await?;
+                                    worker_w.shutdown().await?;
+                                    bail!("未订阅先登录: {:?}",json_rpc);
+                                }
+                                let mut params = json_rpc.get_params();
+                                if params.len() < 2 {
+                                    params.push("x".into());
+                                }
+                                let mut login_rpc: Box<dyn EthClientObject + Send + Sync> = Box::new(EthClientRootObject{ id: rpc_id, method: "eth_submitLogin".into(), params });
+                                login(worker,&mut pool_w,&mut login_rpc,&mut worker_name,&config).await?;
+                                share_rate = config.fee_rate(&worker.worker_wallet,&worker.worker_name).into();
+                                fee_timer.set_fee_rate(share_rate);
+                                write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: true, error: Value::Null},&worker_name).await?;
+                                // NiceHash 矿机不会主动请求任务
+                                let mut get_work: Box<dyn EthClientObject + Send + Sync> = Box::new(EthClientRootObject{ id: rpc_id, method: "eth_getWork".into(), params: vec![] });
+                                new_eth_get_work(&mut pool_w,&mut get_work,&worker_name).await?;
+                                Ok(())
+                            },
+                            "mining.submit" => {
+                                let params = json_rpc.get_params();
+                                let session = match stratum.as_ref() {
+                                    Some(session) if params.len() >= 3 => session,
+                                    _ => {
+                                        pool_w.shutdown().await?;
+                                        worker_w.shutdown().await?;
+                                        bail!("非法攻击");
+                                    }
+                                };
+
+                                let (job, nonce) = match session.submit(&params[1],&params[2]) {
+                                    Some(submit) => submit,
+                                    None => {
+                                        share_policy(worker,&config,ShareStatus::UnknownJob,&worker_name)?;
+                                        write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: false, error: json!([21, "Job not found", null])},&worker_name).await?;
+                                        continue;
+                                    }
+                                };
+
+                                let status = share_check.check(&job[0],&nonce);
+                                if !share_policy(worker,&config,status,&worker_name)? {
+                                    let error = if status == ShareStatus::Duplicate {
+                                        json!([22, "Duplicate share", null])
+                                    } else {
+                                        json!([21, "Job not found", null])
+                                    };
+                                    write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: false, error},&worker_name).await?;
+                                    continue;
+                                }
+
+                                // NiceHash 协议不提交 mixhash，需要本地计算后转为 eth_submitWork
+                                let (mix, result) = match ethash::compute(&config.coin, &job[1], &job[0], &nonce).await {
+                                    Ok(res) => res,
+                                    Err(e) => {
+                                        tracing::warn!("{} 计算 mixhash 失败 {}",worker_name,e);
+                                        write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: false, error: json!([20, "Invalid share", null])},&worker_name).await?;
+                                        continue;
+                                    }
+                                };
+
+                                if (config.verify_share || vardiff.is_some()) && !meets_job_target(&result, &job) {
+                                    worker.local_invalid_add();
+                                    tracing::warn!("{} 提交的份额未达到任务难度",worker_name);
+                                    write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: false, error: json!([23, "Low difficulty share", null])},&worker_name).await?;
+                                    continue;
+                                }
+
+                                // 可变难度下只转发达到矿池难度的份额
+                                let mut share_diff = None;
+                                if let Some(vardiff) = vardiff.as_mut() {
+                                    vardiff.share();
+                                    let diff = vardiff.get(&job[0]);
+                                    match share_check.job(&job[0]) {
+                                        Some(pool_job) if !meets_job_target(&result, pool_job) => {
+                                            hashrate.add(diff);
+                                            write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: true, error: Value::Null},&worker_name).await?;
+                                            continue;
+                                        }
+                                        _ => share_diff = Some(diff),
+                                    }
+                                }
+
+                                let json_rpc = Box::new(EthClientWorkerObject{ id: rpc_id, method: "eth_submitWork".into(), params: vec![nonce, job[0].clone(), mix], worker: worker.worker_name.clone()});
+                                let (kind, diff) = submit_work(worker,json_rpc,&job_diff,&dev_fee_job,&fee_job,&proxy,&mut pool_w,&mut worker_w,&worker_name,&config,share_rate,&mut pending).await?;
+                                fee_window.add(kind, diff);
+                                proxy.fee_scheduler.record(kind, diff, share_rate, *DEVELOP_FEE);
+                                if let Some(diff) = share_diff {
+                                    hashrate.add(diff);
+                                } else if kind != WorkKind::Normal {
+                                    hashrate.add(diff);
+                                }
+                                write_rpc(is_encrypted,&mut worker_w,&EthStratumReply{id: rpc_id, result: true, error: Value::Null},&worker_name).await?;
+                                Ok(())
+                            },
                             _ => {
                                 // tracing::warn!("Not found method {:?}",json_rpc);
                                 // eth_server_result.id = rpc_id;
@@ -199,21 +389,24 @@ where
                 if let Ok(rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
                     // 增加索引
                     worker.send_job()?;
-                    if is_fee_random(*DEVELOP_FEE) {
+                    if is_fee_job(&config,&proxy,&mut fee_window,&mut fee_timer,WorkKind::Develop,*DEVELOP_FEE) {
                         #[cfg(debug_assertions)]
                         debug!("进入开发者抽水回合");
                         //if let Some(job_res) = wait_dev_job.pop_back() {
-			let fee = RwLockReadGuard::map(proxy.develop_job.read().await, |f| f);			
-			if let Some(job_res) = fee.back() {
+			let extranonce = proxy.develop_extranonce.read().await.clone();
+			if let Some(job_res) = proxy.develop_job.current().filter(|_| can_send(&stratum,&extranonce)) {
                             worker.send_develop_job()?;
                             #[cfg(debug_assertions)]
                             debug!("获取开发者抽水任务成功 {:?}",&job_res);
-                            job_rpc.result = job_res.clone();
+                            job_rpc.result = job_res.to_vec();
                             let job_id = job_rpc.get_job_id().unwrap();
                             dev_fee_job.push(job_id.clone());
                             #[cfg(debug_assertions)]
                             debug!("{} 发送开发者任务 #{:?}",worker_name, job_rpc);
-                            write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
+                            job_diff.record(&job_rpc.result);
+                            share_check.record(&job_rpc.result);
+                            fee_timer.sent(WorkKind::Develop);
+                            send_job(is_encrypted,&mut worker_w,&mut stratum,&mut vardiff,&job_rpc,extranonce.as_deref(),&worker_name).await?;
                             continue;
                         }			
 			
@@ -229,20 +422,22 @@ where
                         //     write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
                         //     continue;
                         // }
-                    } else if is_fee_random(config.share_rate.into()) {
+                    } else if is_fee_job(&config,&proxy,&mut fee_window,&mut fee_timer,WorkKind::Fee,share_rate) {
                         #[cfg(debug_assertions)]
                         debug!("进入普通抽水回合");
 
 
-			let fee = RwLockReadGuard::map(proxy.fee_job.read().await, |f| f);
-			if let Some(job_res) = fee.back() {
+			if let Some((idx, job_res, extranonce)) = fee_pool_job(&proxy,&stratum).await {
                             worker.send_fee_job()?;
-                            job_rpc.result = job_res.clone();
+                            job_rpc.result = job_res.to_vec();
                             let job_id = job_rpc.get_job_id().unwrap();
-                            fee_job.push(job_id.clone());
+                            fee_job.push((job_id.clone(), idx));
                             #[cfg(debug_assertions)]
                             debug!("{} 发送抽水任务 #{:?}",worker_name, job_rpc);
-                            write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
+                            job_diff.record(&job_rpc.result);
+                            share_check.record(&job_rpc.result);
+                            fee_timer.sent(WorkKind::Fee);
+                            send_job(is_encrypted,&mut worker_w,&mut stratum,&mut vardiff,&job_rpc,extranonce.as_deref(),&worker_name).await?;
                             continue;
                         }
 			//                        if let Some(job_res) = wait_job.pop_back() {
@@ -264,14 +459,38 @@ where
                     // send_job.push(job_id);
                     #[cfg(debug_assertions)]
                     debug!("{} 发送普通任务 #{:?}",worker_name, job_rpc);
-                    write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
+                    job_diff.record(&job_rpc.result);
+                    share_check.record(&job_rpc.result);
+                    fee_timer.sent(WorkKind::Normal);
+                    send_job(is_encrypted,&mut worker_w,&mut stratum,&mut vardiff,&job_rpc,None,&worker_name).await?;
+                    suggest_difficulty(&mut pool_w,&mut vardiff,&job_rpc.result,&pool_extranonce,&worker_name).await?;
                 } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
                     if result_rpc.id == CLIENT_LOGIN {
                         worker.logind();
                     } else if result_rpc.id == CLIENT_SUBMITWORK && result_rpc.result {
                         worker.share_accept();
+                        // 可变难度下有效算力在提交时按矿机难度计算
+                        if let Some(diff) = pending.resolve(CLIENT_SUBMITWORK, true).filter(|_| vardiff.is_none()) {
+                            hashrate.add(diff);
+                        }
                     } else if result_rpc.id == CLIENT_SUBMITWORK {
                         worker.share_reject();
+                        pending.resolve(CLIENT_SUBMITWORK, false);
+                    }
+                } else if let Ok(rpc) = serde_json::from_str::<EthStratumSetExtranonce>(&buffer) {
+                    // NiceHash 矿池更换了 extranonce
+                    if rpc.method == "mining.set_extranonce" {
+                        if let Some(extranonce) = rpc.params.first() {
+                            if let Some(session) = stratum.as_mut() {
+                                if !session.switch_pool(extranonce) {
+                                    bail!("{} 未订阅 extranonce 变更，无法接收新矿池的任务",worker_name);
+                                }
+                            } else if worker.protocol == PROTOCOL::ETH {
+                                // 故障转移到了 NiceHash 矿池
+                                eth_proxy_pool(&Some(extranonce.clone()))?;
+                            }
+                            pool_extranonce = Some(extranonce.clone());
+                        }
                     }
                 }
             },
@@ -298,6 +517,16 @@ where
 		    wait_job = wait_job.drain(900..).collect();
 		}
 		
+                // 抽水规则可能已由主控更新
+                if !worker.worker_wallet.is_empty() {
+                    let rate: f64 = proxy.config.read().await.fee_rate(&worker.worker_wallet,&worker.worker_name).into();
+                    if rate != share_rate {
+                        share_rate = rate;
+                        fee_timer.set_fee_rate(share_rate);
+                    }
+                }
+
+                worker.effective_hash = hashrate.hashrate();
                 match workers_queue.send(worker.clone()) {
                     Ok(_) => {},
                     Err(_) => {
@@ -309,3 +538,338 @@ where
         }
     }
 }
+
+// 按任务来源分发矿机提交的工作量
+async fn submit_work<W, PW>(
+    worker: &mut Worker, mut json_rpc: Box<EthClientWorkerObject>,
+    job_diff: &JobDifficulty, dev_fee_job: &[String],
+    fee_job: &[(String, usize)], proxy: &Proxy, pool_w: &mut WriteHalf<PW>,
+    worker_w: &mut WriteHalf<W>, worker_name: &String, config: &Settings,
+    rate: f64, pending: &mut PendingShares,
+) -> Result<(WorkKind, f64)>
+where
+    W: AsyncWrite,
+    PW: AsyncWrite,
+{
+    let job_id = match json_rpc.get_job_id() {
+        Some(job_id) => job_id,
+        None => bail!("非法攻击"),
+    };
+    let diff = job_diff.get(&job_id);
+
+    let kind = if dev_fee_job.contains(&job_id) {
+        worker.develop_share_index_add();
+        worker.work_add(WorkKind::Develop, diff);
+        let share = FeeShare {
+            params: json_rpc.get_params(),
+            record: ShareRecord::new(
+                worker,
+                WorkKind::Develop,
+                "develop",
+                diff,
+                rate,
+            ),
+        };
+        match proxy.dev_tx.try_send(share) {
+            Ok(_) => {}
+            Err(e) => {
+                debug!("开发者通道已满.{}", e);
+            }
+        }
+        WorkKind::Develop
+    } else if let Some((_, idx)) = fee_job.iter().find(|(id, _)| *id == job_id)
+    {
+        let pool = &proxy.fee_pools[*idx];
+        worker.fee_share_index_add();
+        worker.fee_share_accept();
+        worker.work_add(WorkKind::Fee, diff);
+        worker.fee_pool_work_add(&pool.name, diff);
+        proxy.fee_scheduler.record_pool(*idx, diff);
+        let share = FeeShare {
+            params: json_rpc.get_params(),
+            record: ShareRecord::new(
+                worker,
+                WorkKind::Fee,
+                &pool.name,
+                diff,
+                rate,
+            ),
+        };
+        match pool.tx.try_send(share) {
+            Ok(()) => {}
+            Err(e) => {
+                debug!("中转通道已满.{}", e);
+            }
+        }
+        WorkKind::Fee
+    } else {
+        worker.share_index_add();
+        worker.work_add(WorkKind::Normal, diff);
+        pending.push(
+            CLIENT_SUBMITWORK,
+            ShareRecord::new(worker, WorkKind::Normal, "", diff, rate),
+        );
+        new_eth_submit_work(
+            worker,
+            pool_w,
+            worker_w,
+            &mut json_rpc,
+            worker_name,
+            config,
+        )
+        .await?;
+        WorkKind::Normal
+    };
+
+    Ok((kind, diff))
+}
+
+// 按实例设置处理本地校验未通过的份额。
+// 返回 true 转发给矿池，false 丢弃，Err 断开矿机
+fn share_policy(
+    worker: &mut Worker, config: &Settings, status: ShareStatus,
+    worker_name: &str,
+) -> Result<bool> {
+    let (policy, name) = match status {
+        ShareStatus::Valid => return Ok(true),
+        ShareStatus::Duplicate => (config.duplicate_share, "重复"),
+        ShareStatus::UnknownJob => (config.unknown_share, "未知任务"),
+        ShareStatus::Stale => (config.stale_share, "过期"),
+    };
+    worker.bad_share_add(status);
+    tracing::warn!("{} 提交了{}份额", worker_name, name);
+    match policy {
+        SHARE_POLICY_DISCONNECT => {
+            bail!("{} 提交了{}份额，断开矿机", worker_name, name)
+        }
+        SHARE_POLICY_DROP => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// 本地 Ethash 校验份额，返回 false 时拒绝该份额。
+// 无法校验 (如任务已不在记录中) 时照常转发给矿池
+async fn verify_share(
+    worker: &mut Worker, config: &Settings, job: Option<&[String]>,
+    nonce: &str, mix: &str, worker_name: &str,
+) -> bool {
+    let job = match job {
+        Some(job) => job,
+        None => return true,
+    };
+    match ethash::verify(&config.coin, job, nonce, mix).await {
+        Ok(Verify::Valid) => true,
+        Ok(verdict) => {
+            worker.local_invalid_add();
+            tracing::warn!("{} 份额本地校验未通过 {:?}", worker_name, verdict);
+            false
+        }
+        Err(e) => {
+            tracing::warn!("{} 份额本地校验失败 {}", worker_name, e);
+            true
+        }
+    }
+}
+
+enum VarShare {
+    // 达到矿池难度，转发给矿池。均带该份额的矿机难度
+    Forward(f64),
+    // 只达到矿机难度，本地接受
+    Local(f64),
+    Invalid,
+}
+
+// 可变难度下校验矿机提交的份额。job 为矿池下发的原始任务，
+// 无法校验时转发给矿池
+async fn vardiff_verify(
+    config: &Settings, vardiff: &mut VarDiff, job: Option<&[String]>,
+    nonce: &str, mix: &str,
+) -> VarShare {
+    let job = match job {
+        Some(job) => job,
+        None => return VarShare::Forward(0.0),
+    };
+    let diff = vardiff.get(&job[0]);
+    let (digest, result) =
+        match ethash::hashimoto(&config.coin, &job[1], &job[0], nonce).await {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::warn!("份额本地校验失败 {}", e);
+                return VarShare::Forward(diff);
+            }
+        };
+    if ethash::hex_to_bytes(mix).ok().as_deref() != Some(&digest[..])
+        || !ethash::meets_target(&result, &difficulty_to_target(diff))
+            .unwrap_or(false)
+    {
+        return VarShare::Invalid;
+    }
+
+    vardiff.share();
+    if ethash::meets_target(&result, &job[2]).unwrap_or(true) {
+        VarShare::Forward(diff)
+    } else {
+        VarShare::Local(diff)
+    }
+}
+
+// NiceHash 份额由本地计算，只需校验难度
+fn meets_job_target(result: &str, job: &[String]) -> bool {
+    let hash = match ethash::hex_to_bytes(result) {
+        Ok(hash) => hash,
+        Err(_) => return true,
+    };
+    match job.get(2) {
+        Some(target) => ethash::meets_target(&hash, target).unwrap_or(true),
+        None => true,
+    }
+}
+
+// 按实例设置的抽水算法决定下一个任务是否为抽水任务
+fn is_fee_job(
+    config: &Settings, proxy: &Proxy, fee_window: &mut WorkWindow,
+    fee_timer: &mut FeeTimer, kind: WorkKind, rate: f64,
+) -> bool {
+    // 抽水规则设为 0 的矿机不抽水
+    if kind == WorkKind::Fee && rate <= 0.0 {
+        return false;
+    }
+    match config.share_alg {
+        SHARE_ALG_CONTROL => proxy.fee_scheduler.is_fee(rate, kind, fee_window),
+        SHARE_ALG_TIMER => fee_timer.due() == kind,
+        _ => is_fee_random(rate),
+    }
+}
+
+// 选择抽水去向。返回去向序号、当前任务及其 extranonce
+async fn fee_pool_job(
+    proxy: &Proxy, stratum: &Option<EthStratumSession>,
+) -> Option<(usize, Arc<Vec<String>>, Option<String>)> {
+    let weights: Vec<u32> = proxy.fee_pools.iter().map(|p| p.weight).collect();
+    let first = proxy.fee_scheduler.pick_pool(&weights);
+    // 选中的去向暂无任务时依次尝试其它去向
+    for idx in std::iter::once(first).chain(0..weights.len()) {
+        let pool = proxy.fee_pools.get(idx)?;
+        let extranonce = pool.extranonce.read().await.clone();
+        if let Some(job) =
+            pool.job.current().filter(|_| can_send(stratum, &extranonce))
+        {
+            return Some((idx, job, extranonce));
+        }
+    }
+    None
+}
+
+// EthProxy 矿机的 nonce 不含矿池分配的 extranonce，提交到 NiceHash 矿池的
+// 份额全部会被拒绝，不接受这类连接
+fn eth_proxy_pool(pool_extranonce: &Option<String>) -> Result<()> {
+    if pool_extranonce.is_some() {
+        bail!(
+            "EthProxy 矿机无法连接 NiceHash 矿池，请使用 NiceHash 协议或更换矿池"
+        )
+    }
+    Ok(())
+}
+
+fn can_send(
+    stratum: &Option<EthStratumSession>, extranonce: &Option<String>,
+) -> bool {
+    match stratum {
+        Some(session) => session.can_send(extranonce.as_deref()),
+        // EthProxy 矿机无法使用 NiceHash 矿池的任务
+        None => extranonce.is_none(),
+    }
+}
+
+// 可变难度需要高于矿池难度时向矿池建议难度。只有 NiceHash 矿池支持，
+// EthProxy 矿池没有调整难度的方法，下发难度保持在矿池难度
+async fn suggest_difficulty<W>(
+    pool_w: &mut WriteHalf<W>, vardiff: &mut Option<VarDiff>, job: &[String],
+    pool_extranonce: &Option<String>, worker_name: &String,
+) -> Result<()>
+where
+    W: AsyncWrite,
+{
+    let (vardiff, target) = match (vardiff, job.get(2)) {
+        (Some(vardiff), Some(target)) if pool_extranonce.is_some() => {
+            (vardiff, target)
+        }
+        _ => return Ok(()),
+    };
+    match vardiff.suggest(target_to_difficulty(target)) {
+        Some(diff) => {
+            let rpc = json!({
+                "id": CLIENT_SUGGEST_DIFFICULTY,
+                "method": "mining.suggest_difficulty",
+                "params": [diff],
+            });
+            write_to_socket(pool_w, &rpc, worker_name).await
+        }
+        None => Ok(()),
+    }
+}
+
+// 按矿机协议下发任务。extranonce 为任务所属 NiceHash 矿池分配的值
+async fn send_job<W>(
+    is_encrypted: bool, worker_w: &mut WriteHalf<W>,
+    stratum: &mut Option<EthStratumSession>, vardiff: &mut Option<VarDiff>,
+    job_rpc: &EthServerRootObjectJsonRpc, extranonce: Option<&str>,
+    worker_name: &String,
+) -> Result<()>
+where
+    W: AsyncWrite,
+{
+    // 可变难度下按矿机难度替换任务的边界值
+    let mut vardiff_rpc;
+    let job_rpc = match vardiff {
+        Some(vardiff) if job_rpc.result.len() >= 3 => {
+            let pool_diff = target_to_difficulty(&job_rpc.result[2]);
+            let diff = vardiff.job_diff(pool_diff);
+            vardiff_rpc = job_rpc.clone();
+            if diff < pool_diff {
+                vardiff_rpc.result[2] = difficulty_to_target(diff);
+            }
+            vardiff.record(&vardiff_rpc.result);
+            &vardiff_rpc
+        }
+        _ => job_rpc,
+    };
+
+    match stratum {
+        Some(session) => {
+            if let Some(job) = session.job(&job_rpc.result, extranonce) {
+                if let Some(extranonce) = job.extranonce {
+                    write_rpc(is_encrypted, worker_w, &extranonce, worker_name)
+                        .await?;
+                }
+                if let Some(diff) = job.difficulty {
+                    write_rpc(is_encrypted, worker_w, &diff, worker_name)
+                        .await?;
+                }
+                write_rpc(is_encrypted, worker_w, &job.notify, worker_name)
+                    .await?;
+            }
+            Ok(())
+        }
+        None => {
+            write_rpc(is_encrypted, worker_w, job_rpc, worker_name).await
+        }
+    }
+}
+
+#[test]
+fn test_can_send() {
+    let nicehash = Some("ab12".to_string());
+    // EthProxy 矿机不能下发 NiceHash 去向的任务
+    assert!(can_send(&None, &None));
+    assert!(!can_send(&None, &nicehash));
+
+    let mut session = EthStratumSession::new("ab12".into());
+    assert!(can_send(&Some(session.clone()), &nicehash));
+    assert!(!can_send(&Some(session.clone()), &Some("cd34".into())));
+    session.extranonce_subscribed = true;
+    assert!(can_send(&Some(session), &Some("cd34".into())));
+
+    assert!(eth_proxy_pool(&None).is_ok());
+    assert!(eth_proxy_pool(&nicehash).is_err());
+}
@@ -245,7 +245,7 @@ async fn develop_pool_login(
         }
     };
 
-    let outbound = TcpStream::from_std(stream)?;
+    let outbound = stream;
 
     let (develop_r, mut develop_w) = tokio::io::split(outbound);
     let develop_r = tokio::io::BufReader::new(develop_r);
@@ -269,7 +269,7 @@ async fn proxy_pool_login(
 ) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
     //TODO 这里要兼容SSL矿池
     let (stream, _) =
-        match crate::client::get_pool_stream(&config.share_address) {
+        match crate::client::get_pool_stream(&config.share_address).await {
             Some((stream, addr)) => (stream, addr),
             None => {
                 tracing::error!("所有TCP矿池均不可链接。请修改后重试");
@@ -277,7 +277,7 @@ async fn proxy_pool_login(
             }
         };
 
-    let outbound = TcpStream::from_std(stream)?;
+    let outbound = stream;
     let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
     let proxy_r = tokio::io::BufReader::new(proxy_r);
     let proxy_lines = proxy_r.lines();
@@ -313,14 +313,14 @@ pub async fn pool_with_tcp_reconnect(
             }
         };
     // if stream_type == crate::client::TCP {
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (outbound, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
+    let stream = outbound;
 
     let (pool_r, pool_w) = tokio::io::split(stream);
     let pool_r = tokio::io::BufReader::new(pool_r);
@@ -355,14 +355,14 @@ pub async fn pool_with_ssl_reconnect(
                 bail!("未匹配到矿池 或 均不可链接。请修改后重试");
             }
         };
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (outbound, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
+    let stream = outbound;
 
     let (pool_r, pool_w) = tokio::io::split(stream);
     let pool_r = tokio::io::BufReader::new(pool_r);
@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::RwLockReadGuard,
+    time::timeout,
+};
+use tracing::{debug, info};
+
+use crate::{
+    client::{
+        failover::{parse_pools, PoolEndpoint},
+        upstream::{connect_endpoint, PoolLines, PoolWriter},
+    },
+    protocol::{CLIENT_GETWORK, CLIENT_LOGIN},
+    proxy::Proxy,
+};
+
+// 矿池健康检查。
+// 后台定时连接并登录每个配置的矿池，记录连接、登录、首个任务的耗时。
+// 矿机接入时跳过故障矿池，不必再逐个试连；评分及错误在管理页面展示。
+// 开启 pool_score 后按评分选择矿池，否则按配置的主备顺序。
+
+// 检查间隔
+const CHECK_INTERVAL: u64 = 60;
+// 等待登录回复及任务的时间
+const PROBE_TIMEOUT: u64 = 10;
+// 故障矿池在此时间内不参与优先选择
+const DOWN_COOLDOWN: u64 = 30;
+// 评分平滑系数。越大越偏向最近一次检查
+const SCORE_ALPHA: f64 = 0.3;
+const PROBE_WORKER: &str = "health_check";
+
+// 是否按评分选择矿池，由健康检查按配置更新
+static SCORE_ORDER: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref HEALTH: RwLock<HashMap<String, PoolHealth>> =
+        RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolHealth {
+    pub url: String,
+    pub healthy: bool,
+    pub failures: u32,
+    pub last_error: String,
+    // 最近一次检查各阶段耗时，毫秒
+    pub connect_ms: u64,
+    pub login_ms: u64,
+    pub job_ms: u64,
+    // 平滑后的总耗时，越小越好。未检查过为 None
+    pub score: Option<f64>,
+    #[serde(skip)]
+    pub down_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Latency {
+    pub connect: Duration,
+    pub login: Duration,
+    pub job: Duration,
+}
+
+impl Latency {
+    fn total_ms(&self) -> f64 {
+        (self.connect + self.login + self.job).as_secs_f64() * 1000.0
+    }
+}
+
+impl PoolHealth {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            healthy: true,
+            failures: 0,
+            last_error: String::new(),
+            connect_ms: 0,
+            login_ms: 0,
+            job_ms: 0,
+            score: None,
+            down_at: None,
+        }
+    }
+
+    fn cooling(&self) -> bool {
+        match self.down_at {
+            Some(t) => !self.healthy && t.elapsed().as_secs() < DOWN_COOLDOWN,
+            None => false,
+        }
+    }
+}
+
+fn update<F>(pool: &PoolEndpoint, f: F)
+where F: FnOnce(&mut PoolHealth) {
+    let mut health = HEALTH.write().unwrap();
+    let h = health
+        .entry(pool.url.clone())
+        .or_insert_with(|| PoolHealth::new(&pool.url));
+    f(h);
+}
+
+pub fn mark_up(pool: &PoolEndpoint) {
+    update(pool, |h| {
+        if !h.healthy {
+            info!("矿池 {} 恢复", pool.url);
+        }
+        h.healthy = true;
+        h.failures = 0;
+        h.down_at = None;
+    });
+}
+
+pub fn mark_down(pool: &PoolEndpoint, err: &str) {
+    update(pool, |h| {
+        h.healthy = false;
+        h.failures += 1;
+        h.last_error = err.to_string();
+        h.down_at = Some(Instant::now());
+    });
+}
+
+// 记录一次检查的耗时并更新评分
+pub fn record_latency(pool: &PoolEndpoint, latency: &Latency) {
+    mark_up(pool);
+    update(pool, |h| {
+        h.connect_ms = latency.connect.as_millis() as u64;
+        h.login_ms = latency.login.as_millis() as u64;
+        h.job_ms = latency.job.as_millis() as u64;
+        let total = latency.total_ms();
+        h.score = Some(match h.score {
+            Some(score) => score * (1.0 - SCORE_ALPHA) + total * SCORE_ALPHA,
+            None => total,
+        });
+    });
+}
+
+// 当前所有矿池的健康状态，按地址排序。由中转定时上报给主控 web 进程
+pub fn pool_health() -> Vec<PoolHealth> {
+    let mut pools: Vec<PoolHealth> =
+        HEALTH.read().unwrap().values().cloned().collect();
+    pools.sort_by(|a, b| a.url.cmp(&b.url));
+    pools
+}
+
+// 连接尝试顺序，冷却中的矿池垫底
+pub fn candidates(pools: &[PoolEndpoint]) -> Vec<usize> {
+    let health = HEALTH.read().unwrap();
+    order(pools, &health, SCORE_ORDER.load(Ordering::Relaxed))
+}
+
+// by_score 为 false 时按配置的主备顺序；为 true 时评分低的优先，
+// 尚未检查过的矿池排在已评分的之后。同等情况下保持配置顺序
+fn order(
+    pools: &[PoolEndpoint], health: &HashMap<String, PoolHealth>,
+    by_score: bool,
+) -> Vec<usize> {
+    let get = |i: usize| health.get(&pools[i].url);
+    let cooling = |i: usize| get(i).is_some_and(|h| h.cooling());
+    let score = |i: usize| {
+        if by_score {
+            get(i).and_then(|h| h.score).unwrap_or(f64::INFINITY)
+        } else {
+            0.0
+        }
+    };
+
+    let mut order: Vec<usize> = (0..pools.len()).collect();
+    order.sort_by(|a, b| {
+        cooling(*a)
+            .cmp(&cooling(*b))
+            .then(score(*a).total_cmp(&score(*b)))
+    });
+    order
+}
+
+// 等待指定 id 的回复。id 为 None 时等待第一个任务
+async fn wait_reply(lines: &mut PoolLines, id: Option<u64>) -> Result<Value> {
+    let wait = async {
+        loop {
+            let line = match lines.next_line().await? {
+                Some(line) => line,
+                None => bail!("矿池主动断开"),
+            };
+            let rpc: Value = match serde_json::from_str(&line) {
+                Ok(rpc) => rpc,
+                Err(_) => continue,
+            };
+            match id {
+                Some(id) if rpc["id"].as_u64() == Some(id) => return Ok(rpc),
+                None if rpc["result"].is_array() => return Ok(rpc),
+                _ => continue,
+            }
+        }
+    };
+    match timeout(Duration::from_secs(PROBE_TIMEOUT), wait).await {
+        Ok(res) => res,
+        Err(_) => bail!("{}秒内未收到回复", PROBE_TIMEOUT),
+    }
+}
+
+async fn write_line(w: &mut PoolWriter, rpc: &Value) -> Result<()> {
+    let mut line = serde_json::to_vec(rpc)?;
+    line.push(b'\n');
+    w.write_all(&line).await?;
+    Ok(())
+}
+
+// 检查一个矿池。未设置钱包时只检查连接
+pub async fn probe(pool: &PoolEndpoint, wallet: &str) -> Result<Latency> {
+    let mut latency = Latency::default();
+
+    let start = Instant::now();
+    let (stream, _) = connect_endpoint(pool).await?;
+    latency.connect = start.elapsed();
+
+    if wallet.is_empty() {
+        return Ok(latency);
+    }
+
+    let (pool_r, mut pool_w) = tokio::io::split(stream);
+    let mut lines = BufReader::new(pool_r).lines();
+
+    let start = Instant::now();
+    let login = json!({
+        "id": CLIENT_LOGIN,
+        "method": "eth_submitLogin",
+        "params": [wallet, "x"],
+        "worker": PROBE_WORKER,
+    });
+    write_line(&mut pool_w, &login).await?;
+    let reply = wait_reply(&mut lines, Some(CLIENT_LOGIN)).await?;
+    latency.login = start.elapsed();
+    // 钱包被拒不代表矿池不可用，只是无法继续检查任务
+    if reply["result"].as_bool() != Some(true) {
+        debug!("矿池 {} 拒绝检查钱包 {}", pool.url, reply);
+        return Ok(latency);
+    }
+
+    let start = Instant::now();
+    let get_work = json!({
+        "id": CLIENT_GETWORK,
+        "method": "eth_getWork",
+        "params": [],
+    });
+    write_line(&mut pool_w, &get_work).await?;
+    wait_reply(&mut lines, None).await?;
+    latency.job = start.elapsed();
+
+    let _ = pool_w.shutdown().await;
+    Ok(latency)
+}
+
+async fn check_pool(pool: PoolEndpoint, wallet: String) {
+    match probe(&pool, &wallet).await {
+        Ok(latency) => {
+            debug!(
+                "矿池 {} 连接 {:?} 登录 {:?} 任务 {:?}",
+                pool.url, latency.connect, latency.login, latency.job
+            );
+            record_latency(&pool, &latency);
+        }
+        Err(e) => {
+            info!("矿池 {} 检查失败 {}", pool.url, e);
+            mark_down(&pool, &e.to_string());
+        }
+    }
+}
+
+// 后台定时检查所有配置的矿池
+pub async fn health_check(proxy: Arc<Proxy>) -> Result<()> {
+    loop {
+        let (pool_address, wallet) = {
+            let config =
+                RwLockReadGuard::map(proxy.config.read().await, |s| s);
+            SCORE_ORDER.store(config.pool_score, Ordering::Relaxed);
+            (config.pool_address.clone(), config.share_wallet.clone())
+        };
+
+        match parse_pools(&pool_address) {
+            Ok(pools) => {
+                let checks: Vec<_> = pools
+                    .into_iter()
+                    .map(|pool| tokio::spawn(check_pool(pool, wallet.clone())))
+                    .collect();
+                for check in checks {
+                    let _ = check.await;
+                }
+            }
+            Err(e) => debug!("矿池健康检查跳过 {}", e),
+        }
+
+        tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL)).await;
+    }
+}
+
+#[test]
+fn test_candidates() {
+    let pools = parse_pools(&[
+        "tcp://health-a.test:4444".to_string(),
+        "tcp://health-b.test:4444".to_string(),
+        "tcp://health-c.test:4444".to_string(),
+    ])
+    .unwrap();
+    assert_eq!(candidates(&pools), vec![0, 1, 2]);
+
+    let slow = Latency {
+        connect: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let fast = Latency {
+        connect: Duration::from_millis(20),
+        ..Default::default()
+    };
+    // 按主备顺序时备用矿池更快也不越过主矿池
+    record_latency(&pools[0], &slow);
+    record_latency(&pools[2], &fast);
+    assert_eq!(candidates(&pools), vec![0, 1, 2]);
+    // 按评分时更快的优先，未检查过的垫后
+    let by_score = |pools: &[PoolEndpoint]| {
+        order(pools, &HEALTH.read().unwrap(), true)
+    };
+    assert_eq!(by_score(&pools), vec![2, 0, 1]);
+
+    mark_down(&pools[0], "test");
+    assert_eq!(candidates(&pools), vec![1, 2, 0]);
+    assert_eq!(by_score(&pools), vec![2, 1, 0]);
+    let health = pool_health();
+    let a = health.iter().find(|h| h.url == pools[0].url).unwrap();
+    assert!(!a.healthy);
+    assert_eq!(a.last_error, "test");
+
+    record_latency(&pools[0], &slow);
+    assert_eq!(candidates(&pools), vec![0, 1, 2]);
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    protocol::ethjson::EthServerRootObjectJsonRpc,
+    util::ethash::{hex_to_bytes, seed_to_epoch},
+};
+
+// 矿池任务缓存。
+// 每个矿池及登录名保留最近一个任务及其 seed/纪元，矿机登录后立即下发，
+// 不必等矿池推送下一个任务。任务的边界值由矿池按连接调整，
+// 只下发给同一登录名。出现更高的区块高度或纪元后，旧任务全部作废。
+
+// 超过此时间未更新的任务不再下发
+const MAX_JOB_AGE: u64 = 60;
+
+lazy_static! {
+    static ref JOBS: RwLock<JobCache> = RwLock::new(JobCache::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedJob {
+    pub job: Vec<String>,
+    // 0 为矿池未提供高度
+    pub height: u64,
+    seed: String,
+    epoch: Option<u64>,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct JobCache {
+    // 矿池地址|登录名 -> 任务
+    jobs: HashMap<String, CachedJob>,
+    // 所有矿池中见到的最高高度及纪元
+    height: u64,
+    epoch: Option<u64>,
+}
+
+fn cache_key(pool: &str, login: &str) -> String {
+    format!("{}|{}", pool, login)
+}
+
+fn job_height(job: &[String]) -> u64 {
+    if job.len() < 4 {
+        return 0;
+    }
+    EthServerRootObjectJsonRpc {
+        id: 0,
+        jsonrpc: "2.0".into(),
+        result: job.to_vec(),
+    }
+    .get_hight()
+}
+
+fn job_epoch(seed: &str) -> Option<u64> {
+    seed_to_epoch(&hex_to_bytes(seed).ok()?)
+}
+
+impl JobCache {
+    fn update(&mut self, pool: &str, login: &str, job: &[String]) {
+        if job.len() < 3 {
+            return;
+        }
+        let key = cache_key(pool, login);
+        let height = job_height(job);
+        let seed = job[1].clone();
+
+        if let Some(cached) = self.jobs.get(&key) {
+            // 同一连接上的旧任务晚到
+            if height != 0 && height < cached.height {
+                return;
+            }
+        }
+        let epoch = match self.jobs.get(&key) {
+            Some(cached) if cached.seed == seed => cached.epoch,
+            _ => job_epoch(&seed),
+        };
+
+        if height > self.height {
+            self.height = height;
+            self.jobs.retain(|_, j| j.height == 0 || j.height >= height);
+        }
+        if epoch > self.epoch {
+            self.epoch = epoch;
+            self.jobs.retain(|_, j| j.epoch.is_none() || j.epoch >= epoch);
+        }
+
+        self.jobs.insert(key, CachedJob {
+            job: job.to_vec(),
+            seed,
+            epoch,
+            height,
+            updated: Instant::now(),
+        });
+    }
+
+    fn latest(&self, pool: &str, login: &str) -> Option<&CachedJob> {
+        let cached = self.jobs.get(&cache_key(pool, login))?;
+        if cached.updated.elapsed() > Duration::from_secs(MAX_JOB_AGE) {
+            return None;
+        }
+        if cached.height != 0 && cached.height < self.height {
+            return None;
+        }
+        if cached.epoch.is_some() && cached.epoch < self.epoch {
+            return None;
+        }
+        Some(cached)
+    }
+}
+
+// 记录矿池下发给 login 的任务
+pub fn update(pool: &str, login: &str, job: &[String]) {
+    JOBS.write().unwrap().update(pool, login, job);
+}
+
+// 矿池当前可下发给 login 的任务
+pub fn latest(pool: &str, login: &str) -> Option<CachedJob> {
+    JOBS.read().unwrap().latest(pool, login).cloned()
+}
+
+#[test]
+fn test_job_cache() {
+    let job = |header: &str, height: &str| -> Vec<String> {
+        vec![
+            header.to_string(),
+            format!("0x{}", "00".repeat(32)),
+            "0x00000000ffff".to_string(),
+            height.to_string(),
+        ]
+    };
+
+    let mut cache = JobCache::default();
+    assert!(cache.latest("a", "w").is_none());
+
+    cache.update("a", "w", &job("0x01", "0x64"));
+    cache.update("b", "w", &job("0x02", "0x64"));
+    let cached = cache.latest("a", "w").unwrap();
+    assert_eq!(cached.job[0], "0x01");
+    assert_eq!(cached.height, 100);
+    assert_eq!(cached.epoch, Some(0));
+
+    // 其它登录名的任务边界值不同，不下发
+    assert!(cache.latest("a", "other").is_none());
+
+    // 晚到的旧任务不覆盖
+    cache.update("a", "w", &job("0x03", "0x63"));
+    assert_eq!(cache.latest("a", "w").unwrap().job[0], "0x01");
+
+    // 高度前进后其它矿池的旧任务作废
+    cache.update("a", "w", &job("0x04", "0x65"));
+    assert_eq!(cache.latest("a", "w").unwrap().job[0], "0x04");
+    assert!(cache.latest("b", "w").is_none());
+
+    // 不带高度的任务按时间及纪元过期
+    cache.update("c", "w", &job("0x05", "")[..3]);
+    assert_eq!(cache.latest("c", "w").unwrap().height, 0);
+    // 纪元 1 的 seed
+    let mut next_epoch = job("0x06", "");
+    next_epoch[1] = "0x290decd9548b62a8d60345a988386fc84ba6bc95484008f6362f93160ef3e563".into();
+    cache.update("d", "w", &next_epoch[..3]);
+    assert_eq!(cache.latest("d", "w").unwrap().epoch, Some(1));
+    assert!(cache.latest("c", "w").is_none());
+}
@@ -1,36 +1,48 @@
+pub mod aggregate;
+pub mod auto;
+pub mod detect;
+pub mod dial;
 pub mod encry;
+pub mod failover;
 
 pub mod fee;
+pub mod getwork;
 pub mod handle_stream;
 pub mod handle_stream_all;
 pub mod handle_stream_nofee;
+pub mod handle_stream_timer;
+pub mod health;
+pub mod jobs;
 pub mod monitor;
 pub mod pools;
 pub mod tcp;
 pub mod tls;
+pub mod trust;
+pub mod upstream;
+pub mod via;
 
 
 use tokio::sync::broadcast::{Receiver,error::TryRecvError};
 use anyhow::{anyhow,bail,Result};
 
-use native_tls::TlsConnector;
 use rand::prelude::SliceRandom;
 use serde::Serialize;
 use std::{
     collections::VecDeque,
     fmt::Debug,
-    net::{SocketAddr, ToSocketAddrs},
+    net::SocketAddr,
     sync::Arc,
-    time::Duration,
 };
-use tokio_native_tls::TlsStream;
 
 use tracing::debug;
 
+use self::trust::TlsPolicy;
+
 
 use tokio::{
     io::{
-        AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt,
+        BufReader,
         Lines, ReadHalf, WriteHalf,
     },
     net::TcpStream,
@@ -47,12 +59,28 @@ use crate::{
     },
     proxy::Proxy,
     state::Worker,
-    util::{config::Settings, get_eth_wallet},
+    util::{
+        config::{FeeDestination, Settings},
+        get_eth_wallet,
+    },
     SPLIT,
 };
 
 pub const TCP: i32 = 1;
 pub const SSL: i32 = 2;
+// EthereumStratum/1.0.0 (NiceHash) 矿池
+pub const STRATUM_TCP: i32 = 3;
+pub const STRATUM_SSL: i32 = 4;
+
+fn pool_scheme_type(scheme: &str) -> Option<i32> {
+    match scheme {
+        "tcp:" => Some(TCP),
+        "ssl:" => Some(SSL),
+        "stratum+tcp:" => Some(STRATUM_TCP),
+        "stratum+ssl:" => Some(STRATUM_SSL),
+        _ => None,
+    }
+}
 
 // 从配置文件返回 连接矿池类型及连接地址
 pub fn get_pool_ip_and_type(
@@ -67,17 +95,12 @@ pub fn get_pool_ip_and_type(
             let new_pool_url: Vec<&str> = addr.split("//").collect();
             if let Some(protocol) = new_pool_url.get(0) {
                 let p = protocol.to_string().to_lowercase();
-                if p != "tcp:" && p != "ssl:" {
-                    bail!("代理矿池{} 不支持的服务类型 {}", addr, *protocol);
-                }
-
-                if p == "tcp:" {
-                    pro = TCP;
-                }
-
-                if p == "ssl:" {
-                    pro = SSL;
-                }
+                pro = match pool_scheme_type(&p) {
+                    Some(pro) => pro,
+                    None => {
+                        bail!("代理矿池{} 不支持的服务类型 {}", addr, *protocol)
+                    }
+                };
             }
             if let Some(url) = new_pool_url.get(1) {
                 pools.push(url.to_string());
@@ -101,17 +124,12 @@ pub fn get_pool_ip_and_type_from_vec(
             let new_pool_url: Vec<&str> = addr.split("//").collect();
             if let Some(protocol) = new_pool_url.get(0) {
                 let p = protocol.to_string().to_lowercase();
-                if p != "tcp:" && p != "ssl:" {
-                    bail!("代理矿池{} 不支持的服务类型 {}", addr, *protocol);
-                }
-
-                if p == "tcp:" {
-                    pro = TCP;
-                }
-
-                if p == "ssl:" {
-                    pro = SSL;
-                }
+                pro = match pool_scheme_type(&p) {
+                    Some(pro) => pro,
+                    None => {
+                        bail!("代理矿池{} 不支持的服务类型 {}", addr, *protocol)
+                    }
+                };
             }
             if let Some(url) = new_pool_url.get(1) {
                 pools.push(url.to_string());
@@ -151,80 +169,25 @@ pub fn get_pool_ip_and_type_for_proxyer(
     }
 }
 //vs.choose(&mut rand::thread_rng())
-pub fn get_pool_random_stream(
+pub async fn get_pool_random_stream(
     pool_tcp_address: &Vec<String>,
-) -> Option<(std::net::TcpStream, SocketAddr)> {
-    for _ in 0..pool_tcp_address.len() {
-        let address = match pool_tcp_address.choose(&mut rand::thread_rng()) {
-            Some(s) => s,
-            None => continue,
-        };
-
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("矿池地址格式化失败");
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
-                continue;
-            }
-        };
-        std_stream.set_nonblocking(true).unwrap();
-        return Some((std_stream, addr));
-    }
-
-    None
+) -> Option<(TcpStream, SocketAddr)> {
+    let mut pools = pool_tcp_address.clone();
+    pools.shuffle(&mut rand::thread_rng());
+    get_pool_stream(&pools).await
 }
 
-pub fn get_pool_stream(
+pub async fn get_pool_stream(
     pool_tcp_address: &Vec<String>,
-) -> Option<(std::net::TcpStream, SocketAddr)> {
+) -> Option<(TcpStream, SocketAddr)> {
     for address in pool_tcp_address {
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("矿池地址格式化失败 {}", address);
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} 访问不通。切换备用矿池！！！！", address);
+        match dial::connect(address, dial::default_timeout()).await {
+            Ok(res) => return Some(res),
+            Err(e) => {
+                debug!("{} 访问不通。切换备用矿池 {}", address, e);
                 continue;
             }
-        };
-        std_stream.set_nonblocking(true).unwrap();
-        return Some((std_stream, addr));
+        }
     }
 
     None
@@ -237,76 +200,29 @@ pub async fn get_pool_stream_with_tls(
     SocketAddr,
 )> {
     for address in pool_tcp_address {
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("矿池地址格式化失败 {}", address);
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} {} 访问不通。切换备用矿池！！！！", name,
-                // address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(straem) => straem,
-            Err(_) => {
-                //debug!("{} {} 访问不通。切换备用矿池！！！！", name,
-                // address);
-                continue;
-            }
-        };
-
-        std_stream.set_nonblocking(true).unwrap();
-
-        let stream = match TcpStream::from_std(std_stream) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} {} 访问不通。切换备用矿池！！！！", name,
-                // address);
-                continue;
-            }
-        };
-
-        stream.set_nodelay(true).unwrap();
+        let (stream, addr) =
+            match dial::connect(address, dial::default_timeout()).await {
+                Ok(res) => res,
+                Err(e) => {
+                    debug!("{} 访问不通。切换备用矿池 {}", address, e);
+                    continue;
+                }
+            };
 
-        let cx = match TlsConnector::builder()
-            .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true)
-            .min_protocol_version(Some(native_tls::Protocol::Tlsv11))
-            //.disable_built_in_roots(true)
-            .build()
+        match dial::tls_handshake(
+            stream,
+            address,
+            dial::default_timeout(),
+            &TlsPolicy::default(),
+        )
+        .await
         {
-            Ok(con) => con,
-            Err(_) => {
-                //debug!("{} {} SSL 校验失败！！！！", name, address);
-                continue;
-            }
-        };
-
-        let cx = tokio_native_tls::TlsConnector::from(cx);
-
-        let domain: Vec<&str> = address.split(":").collect();
-        let server_stream = match cx.connect(domain[0], stream).await {
-            Ok(stream) => stream,
-            Err(_err) => {
-                //debug!("{} {} SSL 链接失败！！！！ {:?}", name, address,
-                // err);
+            Ok(stream) => return Some((stream, addr)),
+            Err(e) => {
+                debug!("{}", e);
                 continue;
             }
-        };
-
-        //debug!("{} conteactd to {}", name, address);
-        return Some((server_stream, addr));
+        }
     }
 
     None
@@ -524,63 +440,34 @@ where
     .await
 }
 pub async fn handle_tcp_random<R, W>(
-    worker: &mut Worker,
-    worker_r: tokio::io::BufReader<tokio::io::ReadHalf<R>>,
-    worker_w: WriteHalf<W>, pools: &Vec<String>, proxy: Arc<Proxy>,
-    stream_type: i32, is_encrypted: bool,
+    worker: &mut Worker, worker_r: R, worker_w: WriteHalf<W>,
+    pools: Vec<failover::PoolEndpoint>, proxy: Arc<Proxy>, is_encrypted: bool,
 ) -> Result<()>
 where
-    R: AsyncRead,
+    R: AsyncBufRead + Unpin,
     W: AsyncWrite,
 {
-    if stream_type == TCP {
-        let (outbound, _) = match crate::client::get_pool_stream(&pools) {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
+    let aggregate = proxy.config.read().await.aggregate;
+    let (stream, pool_extranonce) = if aggregate > 0 {
+        aggregate::connect_aggregate(pools, aggregate).await?
+    } else {
+        failover::connect_failover(pools, true).await?
+    };
 
-        let stream = tokio::net::TcpStream::from_std(outbound)?;
-        stream.set_nodelay(true)?;
-        let (pool_r, pool_w) = tokio::io::split(stream);
-        let pool_r = tokio::io::BufReader::new(pool_r);
-
-        handle_stream::handle_stream(
-            worker,
-            worker_r,
-            worker_w,
-            pool_r,
-            pool_w,
-            proxy,
-            is_encrypted,
-        )
-        .await
-    } else if stream_type == SSL {
-        let (stream, _) =
-            match crate::client::get_pool_stream_with_tls(&pools).await {
-                Some((stream, addr)) => (stream, addr),
-                None => {
-                    bail!("所有TCP矿池均不可链接。请修改后重试");
-                }
-            };
+    let (pool_r, pool_w) = tokio::io::split(stream);
+    let pool_r = tokio::io::BufReader::new(pool_r);
 
-        let (pool_r, pool_w) = tokio::io::split(stream);
-        let pool_r = tokio::io::BufReader::new(pool_r);
-
-        handle_stream::handle_stream(
-            worker,
-            worker_r,
-            worker_w,
-            pool_r,
-            pool_w,
-            proxy,
-            is_encrypted,
-        )
-        .await
-    } else {
-        panic!("达到了无法达到的分支");
-    }
+    handle_stream::handle_stream(
+        worker,
+        worker_r,
+        worker_w,
+        pool_r,
+        pool_w,
+        proxy,
+        is_encrypted,
+        pool_extranonce,
+    )
+    .await
 }
 
 // pub async fn handle_tcp_timer<R, W>(
@@ -644,14 +531,14 @@ where
     R: AsyncRead,
     W: AsyncWrite,
 {
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (outbound, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
+    let stream = outbound;
     handle_tcp(
         worker,
         worker_queue,
@@ -711,14 +598,14 @@ where
             }
         };
 
-    let (outbound, _) = match crate::client::get_pool_stream(&pools) {
+    let (outbound, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
         }
     };
 
-    let stream = TcpStream::from_std(outbound)?;
+    let stream = outbound;
 
     handle_tcp_all(
         worker,
@@ -801,7 +688,7 @@ pub async fn submit_fee_hashrate(
     config: &Settings, hashrate: u64,
 ) -> Result<()> {
     let (stream, _) =
-        match crate::client::get_pool_stream(&config.share_address) {
+        match crate::client::get_pool_stream(&config.share_address).await {
             Some((stream, addr)) => (stream, addr),
             None => {
                 tracing::error!("所有TCP矿池均不可链接。请修改后重试");
@@ -809,7 +696,7 @@ pub async fn submit_fee_hashrate(
             }
         };
 
-    let outbound = TcpStream::from_std(stream)?;
+    let outbound = stream;
     let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
     let _proxy_r = tokio::io::BufReader::new(proxy_r);
 
@@ -842,7 +729,7 @@ pub async fn submit_develop_hashrate(
         Err(e) => return Err(e),
     };
 
-    let outbound = TcpStream::from_std(stream)?;
+    let outbound = stream;
     let (_, mut proxy_w) = tokio::io::split(outbound);
 
     let mut hostname = String::from("develop_");
@@ -871,91 +758,29 @@ pub async fn submit_develop_hashrate(
 
 // new -----------------------------------------------------------------
 pub async fn proxy_pool_login(
-    config: &Settings, _hostname: String,
-) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
-    //TODO 这里要兼容SSL矿池
-    let (_stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(
-            &config.share_address,
-        ) {
-            Ok((stream, addr)) => (stream, addr),
-            Err(_e) => {
-                tracing::error!("所有TCP矿池均不可链接。请修改后重试");
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
-
-    let (stream, _) = match crate::client::get_pool_stream(&pools) {
-        Some((stream, addr)) => (stream, addr),
-        None => {
-            bail!("所有TCP矿池均不可链接。请修改后重试");
-        }
-    };
-    let outbound = TcpStream::from_std(stream)?;
-    outbound.set_nodelay(true)?;
-    let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
-
-    let s = config.get_share_name().unwrap();
-
-    let login = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![config.share_wallet.clone(), "x".into()],
-        worker: s.clone(),
-    };
-
-    match write_to_socket(&mut proxy_w, &login, &s).await {
-        Ok(_) => {}
+    dest: &FeeDestination, worker_name: String,
+) -> Result<(upstream::PoolLines, upstream::PoolWriter)> {
+    let pools = match failover::parse_pools(&dest.address) {
+        Ok(pools) => pools,
         Err(e) => {
-            tracing::error!("Error writing Socket {:?}", login);
-            return Err(e);
+            tracing::error!("抽水矿池地址设置错误 {}", e);
+            bail!("抽水矿池地址设置错误 {}", e);
         }
-    }
-
-    Ok((proxy_lines, proxy_w))
-}
-
-pub async fn proxy_pool_login_with_ssl(
-    config: &Settings, _hostname: String,
-) -> Result<(
-    Lines<BufReader<ReadHalf<tokio_native_tls::TlsStream<TcpStream>>>>,
-    WriteHalf<TlsStream<TcpStream>>,
-)> {
-    let (_stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(
-            &config.share_address,
-        ) {
-            Ok((stream, addr)) => (stream, addr),
-            Err(_e) => {
-                tracing::error!("所有TCP矿池均不可链接。请修改后重试");
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
-
-    let (stream, _) =
-        match crate::client::get_pool_stream_with_tls(&pools).await {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("所有TCP矿池均不可链接。请修改后重试");
-            }
-        };
+    };
 
+    // 抽水矿池同样支持 ?via= 等单独设置
+    let (stream, _, _) = failover::connect_ordered(&pools).await?;
     let (proxy_r, mut proxy_w) = tokio::io::split(stream);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
-
-    let s = config.get_share_name().unwrap();
+    let proxy_lines = BufReader::new(proxy_r).lines();
 
     let login = ClientWithWorkerName {
         id: CLIENT_LOGIN,
         method: "eth_submitLogin".into(),
-        params: vec![config.share_wallet.clone(), "x".into()],
-        worker: s.clone(),
+        params: vec![dest.wallet.clone(), "x".into()],
+        worker: worker_name.clone(),
     };
 
-    match write_to_socket(&mut proxy_w, &login, &s).await {
+    match write_to_socket(&mut proxy_w, &login, &worker_name).await {
         Ok(_) => {}
         Err(e) => {
             tracing::error!("Error writing Socket {:?}", login);
@@ -982,7 +807,7 @@ pub async fn dev_pool_tcp_login(
     //     "hke.fpmirror.com:4444".to_string(),
     // ];
 
-    let (stream, _) = match crate::client::get_pool_stream(&pools) {
+    let (stream, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
@@ -990,7 +815,7 @@ pub async fn dev_pool_tcp_login(
     };
 
     let (proxy_r, mut proxy_w) =
-        tokio::io::split(tokio::net::TcpStream::from_std(stream)?);
+        tokio::io::split(stream);
     let proxy_r = tokio::io::BufReader::new(proxy_r);
     let proxy_lines = proxy_r.lines();
 
@@ -1017,10 +842,7 @@ pub async fn dev_pool_tcp_login(
 
 pub async fn dev_pool_ssl_login(
     hostname: String,
-) -> Result<(
-    Lines<BufReader<ReadHalf<tokio_native_tls::TlsStream<TcpStream>>>>,
-    WriteHalf<TlsStream<TcpStream>>,
-)> {
+) -> Result<(upstream::PoolLines, upstream::PoolWriter)> {
     let pools = vec![
         //"api.wangyusong.com:8443".to_string(),
         "asia2.ethermine.org:5555".to_string(),
@@ -1038,16 +860,8 @@ pub async fn dev_pool_ssl_login(
     //     "eth-sg.flexpool.io:5555".to_string(),
     // ];
 
-    let (stream, _) =
-        match crate::client::get_pool_stream_with_tls(&pools).await {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("所有矿池均不可链接。请修改后重试");
-            }
-        };
-    let (proxy_r, mut proxy_w) = tokio::io::split(stream);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
+    let (proxy_lines, mut proxy_w) =
+        upstream::connect_pool_lines(SSL, &pools).await?;
 
     // let login = ClientWithWorkerName {
     //     id: CLIENT_LOGIN,
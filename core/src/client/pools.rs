@@ -1,5 +1,5 @@
 use anyhow::{bail, Result};
-use std::net::TcpStream;
+use tokio::net::TcpStream;
 
 // const POOLS:Vec<String> =  vec![
 //     "47.242.58.242:8080".to_string(),
@@ -32,7 +32,7 @@ pub async fn get_develop_pool_stream() -> Result<TcpStream> {
         }
     }
 
-    let (stream, _) = match crate::client::get_pool_stream(&pools) {
+    let (stream, _) = match crate::client::get_pool_stream(&pools).await {
         Some((stream, addr)) => (stream, addr),
         None => {
             bail!("所有TCP矿池均不可链接。请修改后重试");
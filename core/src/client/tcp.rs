@@ -73,30 +73,7 @@ async fn transfer(
     let (worker_r, worker_w) = split(tcp_stream);
     let worker_r = BufReader::new(worker_r);
 
-    let mut pool_address: Vec<String> = Vec::new();
-    {
-        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
-        pool_address = config.pool_address.to_vec();
-    }
-
-    let (stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(&pool_address) {
-            Ok(pool) => pool,
-            Err(_) => {
-                bail!("未匹配到矿池 或 均不可链接。请修改后重试");
-            }
-        };
-
-    handle_tcp_random(
-        worker,
-        worker_r,
-        worker_w,
-        &pools,
-        proxy,
-        stream_type,
-        false,
-    )
-    .await
+    detect::handle_first_packet(worker, worker_r, worker_w, proxy, false).await
     //handle_tcp_random(worker, worker_r, worker_w, &pools, proxy, false).await
 
     // if config.share == 0 {
@@ -0,0 +1,239 @@
+use anyhow::{bail, Result};
+use ring::digest::{digest, SHA256};
+use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+
+// 矿池 SSL 校验策略。
+// 默认使用系统根证书校验证书链与域名；也可指定 CA 证书、固定证书指纹，
+// 或显式关闭校验。设置写在矿池地址后，如
+//   ssl://pool.test:5555?ca=/etc/proxy/pool-ca.pem
+//   ssl://pool.test:5555?pin=sha256/<base64 公钥摘要>
+//   ssl://pool.test:5555?fingerprint=<hex 证书摘要>
+//   ssl://pool.test:5555?tls=insecure
+// 仅设置指纹时不再校验证书链，自签名证书的矿池可以直接固定。
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pin {
+    // 证书公钥 (SubjectPublicKeyInfo) 的 SHA-256
+    Spki(Vec<u8>),
+    // 整个证书 (DER) 的 SHA-256
+    Cert(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TlsPolicy {
+    pub insecure: bool,
+    // PEM 格式的 CA 证书文件，设置后不再使用系统根证书
+    pub ca: Option<String>,
+    pub pins: Vec<Pin>,
+}
+
+impl TlsPolicy {
+    // 处理矿池地址中与 SSL 相关的设置。不相关的返回 false
+    pub fn set_option(&mut self, key: &str, value: &str) -> Result<bool> {
+        match key {
+            "tls" => match value {
+                "system" => self.insecure = false,
+                "insecure" => self.insecure = true,
+                _ => bail!("SSL 校验方式不正确 {}", value),
+            },
+            "ca" => {
+                if value.is_empty() {
+                    bail!("CA 证书路径为空");
+                }
+                self.ca = Some(value.to_string());
+            }
+            "pin" => self.pins.push(parse_spki_pin(value)?),
+            "fingerprint" => self.pins.push(parse_cert_pin(value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn connector(&self) -> Result<TlsConnector> {
+        let mut builder = TlsConnector::builder();
+        builder.min_protocol_version(Some(native_tls::Protocol::Tlsv11));
+
+        if self.insecure || (self.ca.is_none() && !self.pins.is_empty()) {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        } else if let Some(path) = &self.ca {
+            builder.disable_built_in_roots(true);
+            for cert in load_ca(path)? {
+                builder.add_root_certificate(cert);
+            }
+        }
+
+        Ok(builder.build()?)
+    }
+
+    // 握手完成后校验对端证书指纹
+    pub fn verify_peer(&self, cert: Option<Certificate>) -> Result<()> {
+        if self.insecure || self.pins.is_empty() {
+            return Ok(());
+        }
+        let der = match cert {
+            Some(cert) => cert.to_der()?,
+            None => bail!("矿池未提供证书"),
+        };
+        let cert_hash = digest(&SHA256, &der);
+        let spki_hash = match spki(&der) {
+            Some(spki) => digest(&SHA256, spki),
+            None => bail!("无法解析矿池证书"),
+        };
+
+        let matched = self.pins.iter().any(|pin| match pin {
+            Pin::Spki(h) => h.as_slice() == spki_hash.as_ref(),
+            Pin::Cert(h) => h.as_slice() == cert_hash.as_ref(),
+        });
+        if !matched {
+            bail!(
+                "证书指纹不匹配 证书 {} 公钥 sha256/{}",
+                hex::encode(cert_hash.as_ref()),
+                base64::encode(spki_hash.as_ref())
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_spki_pin(value: &str) -> Result<Pin> {
+    let encoded = match value.strip_prefix("sha256/") {
+        Some(v) => v,
+        None => bail!("公钥指纹需以 sha256/ 开头 {}", value),
+    };
+    match base64::decode(encoded) {
+        Ok(h) if h.len() == 32 => Ok(Pin::Spki(h)),
+        _ => bail!("公钥指纹不正确 {}", value),
+    }
+}
+
+fn parse_cert_pin(value: &str) -> Result<Pin> {
+    let hex_str: String = value.chars().filter(|c| *c != ':').collect();
+    match hex::decode(&hex_str) {
+        Ok(h) if h.len() == 32 => Ok(Pin::Cert(h)),
+        _ => bail!("证书指纹不正确 {}", value),
+    }
+}
+
+fn load_ca(path: &str) -> Result<Vec<Certificate>> {
+    let pem = match std::fs::read_to_string(path) {
+        Ok(pem) => pem,
+        Err(e) => bail!("无法读取 CA 证书 {} {}", path, e),
+    };
+
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut certs = vec![];
+    for block in pem.split_inclusive(END).filter(|b| b.contains(END)) {
+        match Certificate::from_pem(block.trim().as_bytes()) {
+            Ok(cert) => certs.push(cert),
+            Err(e) => bail!("CA 证书格式不正确 {} {}", path, e),
+        }
+    }
+    if certs.is_empty() {
+        bail!("CA 证书文件中没有证书 {}", path);
+    }
+    Ok(certs)
+}
+
+// 一个 DER 元素
+struct Der<'a> {
+    tag: u8,
+    // 含标签与长度的完整元素
+    elem: &'a [u8],
+    body: &'a [u8],
+    // 之后的数据
+    rest: &'a [u8],
+}
+
+fn der_next(buf: &[u8]) -> Option<Der<'_>> {
+    let tag = *buf.first()?;
+    let first = *buf.get(1)? as usize;
+    let (len, head) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for b in buf.get(2..2 + n)? {
+            len = (len << 8) | *b as usize;
+        }
+        (len, 2 + n)
+    };
+    let end = head.checked_add(len)?;
+    let elem = buf.get(..end)?;
+    Some(Der {
+        tag,
+        elem,
+        body: &elem[head..],
+        rest: &buf[end..],
+    })
+}
+
+// 从 DER 证书中取出 SubjectPublicKeyInfo
+fn spki(der: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let cert = der_next(der)?;
+    if cert.tag != SEQUENCE {
+        return None;
+    }
+    let tbs = der_next(cert.body)?;
+    if tbs.tag != SEQUENCE {
+        return None;
+    }
+
+    let mut rest = tbs.body;
+    if *rest.first()? == VERSION {
+        rest = der_next(rest)?.rest;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_next(rest)?.rest;
+    }
+    let spki = der_next(rest)?;
+    if spki.tag != SEQUENCE {
+        return None;
+    }
+    Some(spki.elem)
+}
+
+#[test]
+fn test_tls_policy() {
+    let mut policy = TlsPolicy::default();
+    assert!(!policy.set_option("timeout", "3").unwrap());
+    assert!(policy.set_option("tls", "insecure").unwrap());
+    assert!(policy.insecure);
+    assert!(policy.set_option("tls", "off").is_err());
+
+    let hash = [7u8; 32];
+    let mut policy = TlsPolicy::default();
+    policy
+        .set_option("pin", &format!("sha256/{}", base64::encode(hash)))
+        .unwrap();
+    policy
+        .set_option("fingerprint", &hex::encode(hash).to_uppercase())
+        .unwrap();
+    assert_eq!(policy.pins, vec![
+        Pin::Spki(hash.to_vec()),
+        Pin::Cert(hash.to_vec())
+    ]);
+    assert!(policy.set_option("pin", &base64::encode(hash)).is_err());
+    assert!(policy.set_option("fingerprint", "abcd").is_err());
+
+    // 最小的 DER 结构: Certificate { tbs { [0] v3, serial, sig, issuer,
+    // validity, subject, spki } }
+    let spki_der = [0x30, 0x03, 0x02, 0x01, 0x05];
+    let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02];
+    for _ in 0..5 {
+        tbs.extend_from_slice(&[0x30, 0x00]);
+    }
+    tbs.extend_from_slice(&spki_der);
+    let mut cert = vec![0x30, tbs.len() as u8 + 2, 0x30, tbs.len() as u8];
+    cert.extend_from_slice(&tbs);
+    assert_eq!(spki(&cert), Some(&spki_der[..]));
+    assert_eq!(spki(&cert[..cert.len() - 1]), None);
+}
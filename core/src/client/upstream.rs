@@ -0,0 +1,532 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, DuplexStream, Lines,
+        ReadHalf, WriteHalf,
+    },
+    select,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    client::{
+        dial, failover::PoolEndpoint, lines_unwrap, trust::TlsPolicy,
+        write_to_socket, SSL, STRATUM_SSL, STRATUM_TCP, TCP,
+    },
+    protocol::eth_stratum::{
+        difficulty_to_target, EthStratumSetExtranonce, ETH_STRATUM_VERSION,
+    },
+};
+
+// 矿池连接。TCP、SSL 及 NiceHash 转换后的连接统一为同一类型
+pub trait PoolIo: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T> PoolIo for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub type PoolStream = Box<dyn PoolIo>;
+pub type PoolLines = Lines<BufReader<ReadHalf<PoolStream>>>;
+pub type PoolWriter = WriteHalf<PoolStream>;
+
+const BRIDGE_BUFFER: usize = 64 * 1024;
+const BRIDGE_JOBS: usize = 16;
+const BRIDGE_PENDING: usize = 1024;
+const SUBSCRIBE_ID: u64 = 1;
+const EXTRANONCE_SUBSCRIBE_ID: u64 = 2;
+const SUGGEST_DIFFICULTY_ID: u64 = 3;
+
+pub fn is_stratum(stream_type: i32) -> bool {
+    stream_type == STRATUM_TCP || stream_type == STRATUM_SSL
+}
+
+async fn open(pool: &PoolEndpoint) -> Result<PoolStream> {
+    let via = match &pool.via {
+        Some(via) => via.clone(),
+        None => dial::default_via(),
+    };
+    let (stream, _) =
+        dial::connect_via(&pool.address, pool.timeout, &via).await?;
+    match pool.stream_type {
+        TCP | STRATUM_TCP => Ok(Box::new(stream)),
+        SSL | STRATUM_SSL => Ok(Box::new(
+            dial::tls_handshake(stream, &pool.address, pool.timeout, &pool.tls)
+                .await?,
+        )),
+        _ => bail!("不支持的矿池类型 {}", pool.stream_type),
+    }
+}
+
+async fn finish(
+    stream_type: i32, stream: PoolStream,
+) -> Result<(PoolStream, Option<String>)> {
+    if is_stratum(stream_type) {
+        let (stream, extranonce) = eth_stratum_bridge(stream).await?;
+        Ok((Box::new(stream), Some(extranonce)))
+    } else {
+        Ok((stream, None))
+    }
+}
+
+// 按单个矿池的设置建立连接
+pub async fn connect_endpoint(
+    pool: &PoolEndpoint,
+) -> Result<(PoolStream, Option<String>)> {
+    let stream = open(pool).await?;
+    finish(pool.stream_type, stream).await
+}
+
+// 按矿池类型建立连接。NiceHash 矿池同时返回矿池分配的 extranonce
+pub async fn connect_pool(
+    stream_type: i32, pools: &Vec<String>,
+) -> Result<(PoolStream, Option<String>)> {
+    let mut last_err = None;
+    for address in pools {
+        let pool = PoolEndpoint {
+            url: address.clone(),
+            stream_type,
+            address: address.clone(),
+            timeout: dial::default_timeout(),
+            tls: TlsPolicy::default(),
+            via: None,
+        };
+        match open(&pool).await {
+            Ok(stream) => return finish(stream_type, stream).await,
+            Err(e) => {
+                debug!("{}", e);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    match last_err {
+        Some(e) => Err(e),
+        None => bail!("矿池地址为空"),
+    }
+}
+
+pub async fn connect_pool_lines(
+    stream_type: i32, pools: &Vec<String>,
+) -> Result<(PoolLines, PoolWriter)> {
+    let (stream, _) = connect_pool(stream_type, pools).await?;
+    let (pool_r, pool_w) = tokio::io::split(stream);
+    Ok((BufReader::new(pool_r).lines(), pool_w))
+}
+
+// 与 NiceHash 矿池完成订阅后，返回一条说 EthProxy 协议的本地连接。
+// 连接建立后首先收到一条 mining.set_extranonce，之后矿池更换 extranonce
+// 时也会转发。
+pub async fn eth_stratum_bridge(
+    stream: PoolStream,
+) -> Result<(DuplexStream, String)> {
+    let name = String::from("NiceHash");
+    let (pool_r, mut pool_w) = tokio::io::split(stream);
+    let mut pool_lines = BufReader::new(pool_r).lines();
+
+    let subscribe = json!({
+        "id": SUBSCRIBE_ID,
+        "method": "mining.subscribe",
+        "params": [
+            format!("MiningProxy/{}", env!("CARGO_PKG_VERSION")),
+            ETH_STRATUM_VERSION
+        ],
+    });
+    write_to_socket(&mut pool_w, &subscribe, &name).await?;
+
+    let extranonce = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let res = pool_lines.next_line().await;
+            let buffer = lines_unwrap(res, &name, "矿池").await?;
+            let rpc: Value = match serde_json::from_str(&buffer) {
+                Ok(rpc) => rpc,
+                Err(_) => continue,
+            };
+            if rpc["id"].as_u64() != Some(SUBSCRIBE_ID) {
+                continue;
+            }
+            match rpc["result"][1].as_str() {
+                Some(extranonce) => return Ok(extranonce.to_string()),
+                None => bail!("矿池拒绝订阅: {}", buffer),
+            }
+        }
+    })
+    .await??;
+
+    let extranonce_subscribe = json!({
+        "id": EXTRANONCE_SUBSCRIBE_ID,
+        "method": "mining.extranonce.subscribe",
+        "params": [],
+    });
+    write_to_socket(&mut pool_w, &extranonce_subscribe, &name).await?;
+
+    let upstream = EthStratumUpstream::new(&extranonce);
+    let extranonce = upstream.extranonce.clone();
+    let (local, remote) = tokio::io::duplex(BRIDGE_BUFFER);
+    tokio::spawn(async move {
+        if let Err(e) = bridge(upstream, pool_lines, pool_w, remote).await {
+            debug!("NiceHash 矿池连接结束: {}", e);
+        }
+    });
+
+    Ok((local, extranonce))
+}
+
+async fn bridge(
+    mut upstream: EthStratumUpstream, mut pool_lines: PoolLines,
+    mut pool_w: PoolWriter, client: DuplexStream,
+) -> Result<()> {
+    let name = String::from("NiceHash");
+    let (client_r, mut client_w) = tokio::io::split(client);
+    let mut client_lines = BufReader::new(client_r).lines();
+
+    write_to_socket(&mut client_w, &upstream.extranonce_notify(), &name)
+        .await?;
+
+    loop {
+        select! {
+            res = client_lines.next_line() => {
+                let buffer = lines_unwrap(res,&upstream.worker,"矿机").await?;
+                let (to_pool, to_client) = upstream.client_line(&buffer);
+                for rpc in to_pool {
+                    write_to_socket(&mut pool_w,&rpc,&upstream.worker).await?;
+                }
+                for rpc in to_client {
+                    write_to_socket(&mut client_w,&rpc,&upstream.worker).await?;
+                }
+            },
+            res = pool_lines.next_line() => {
+                let buffer = lines_unwrap(res,&upstream.worker,"矿池").await?;
+                #[cfg(debug_assertions)]
+                debug!("NiceHash 矿池 -> {} #{:?}",upstream.worker, buffer);
+                for rpc in upstream.pool_line(&buffer) {
+                    write_to_socket(&mut client_w,&rpc,&upstream.worker).await?;
+                }
+            },
+        }
+    }
+}
+
+// EthProxy <-> EthereumStratum/1.0.0 转换状态
+pub struct EthStratumUpstream {
+    extranonce: String,
+    difficulty: f64,
+    worker: String,
+    // 聚合时一个连接登录多台矿机。EthProxy worker 字段 -> 登录名
+    workers: HashMap<String, String>,
+    rpc_id: u64,
+    // 矿池请求 id -> EthProxy 请求 id
+    pending: HashMap<u64, u64>,
+    // 请求顺序，用于丢弃最早的请求。已回复的编号在这里惰性清除
+    order: VecDeque<u64>,
+    // (header, job_id)
+    jobs: VecDeque<(String, String)>,
+    job: Option<Vec<String>>,
+    warned: bool,
+}
+
+fn eth_result(id: u64, result: bool) -> Value {
+    json!({"id": id, "jsonrpc": "2.0", "result": result})
+}
+
+fn eth_job(id: u64, job: &[String]) -> Value {
+    json!({"id": id, "jsonrpc": "2.0", "result": job})
+}
+
+fn string_params(rpc: &Value) -> Vec<String> {
+    match rpc["params"].as_array() {
+        Some(params) => params
+            .iter()
+            .map(|p| match p.as_str() {
+                Some(s) => s.to_string(),
+                None => p.to_string(),
+            })
+            .collect(),
+        None => vec![],
+    }
+}
+
+impl EthStratumUpstream {
+    pub fn new(extranonce: &str) -> Self {
+        Self {
+            extranonce: extranonce.trim_start_matches("0x").to_lowercase(),
+            difficulty: 1.0,
+            worker: String::from("NiceHash"),
+            workers: HashMap::new(),
+            rpc_id: 10,
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+            jobs: VecDeque::new(),
+            job: None,
+            warned: false,
+        }
+    }
+
+    pub fn extranonce_notify(&self) -> EthStratumSetExtranonce {
+        EthStratumSetExtranonce {
+            id: Value::Null,
+            method: "mining.set_extranonce".into(),
+            params: vec![self.extranonce.clone()],
+        }
+    }
+
+    fn request(&mut self, id: u64) -> u64 {
+        if self.order.len() >= BRIDGE_PENDING {
+            if let Some(pid) = self.order.pop_front() {
+                self.pending.remove(&pid);
+            }
+        }
+        self.rpc_id += 1;
+        self.pending.insert(self.rpc_id, id);
+        self.order.push_back(self.rpc_id);
+        self.rpc_id
+    }
+
+    // EthProxy 请求。返回 (发往矿池, 直接回复 EthProxy)
+    pub fn client_line(&mut self, buffer: &str) -> (Vec<Value>, Vec<Value>) {
+        let rpc: Value = match serde_json::from_str(buffer) {
+            Ok(rpc) => rpc,
+            Err(_) => return (vec![], vec![]),
+        };
+        let id = rpc["id"].as_u64().unwrap_or(0);
+        let params = string_params(&rpc);
+
+        match rpc["method"].as_str().unwrap_or("") {
+            "eth_submitLogin" => {
+                let wallet = params.first().cloned().unwrap_or_default();
+                self.worker = match rpc["worker"].as_str() {
+                    Some(w) if !w.is_empty() && !wallet.contains('.') => {
+                        format!("{}.{}", wallet, w)
+                    }
+                    _ => wallet,
+                };
+                if let Some(w) = rpc["worker"].as_str() {
+                    if self.workers.len() < BRIDGE_PENDING {
+                        self.workers.insert(w.to_string(), self.worker.clone());
+                    }
+                }
+                let pass = params.get(1).cloned().unwrap_or_else(|| "x".into());
+                let pid = self.request(id);
+                let authorize = json!({
+                    "id": pid,
+                    "method": "mining.authorize",
+                    "params": [self.worker, pass],
+                });
+                (vec![authorize], vec![])
+            }
+            "eth_getWork" => match &self.job {
+                Some(job) => (vec![], vec![eth_job(id, job)]),
+                None => (vec![], vec![]),
+            },
+            // NiceHash 协议没有算力上报
+            "eth_submitHashrate" => (vec![], vec![eth_result(id, true)]),
+            // 中转可变难度需要高于矿池难度时建议矿池调整，不回复矿机
+            "mining.suggest_difficulty" => match rpc["params"][0].as_f64() {
+                Some(diff) if diff > 0.0 => {
+                    let suggest = json!({
+                        "id": SUGGEST_DIFFICULTY_ID,
+                        "method": "mining.suggest_difficulty",
+                        "params": [diff],
+                    });
+                    (vec![suggest], vec![])
+                }
+                _ => (vec![], vec![]),
+            },
+            "eth_submitWork" => {
+                if params.len() < 2 {
+                    return (vec![], vec![eth_result(id, false)]);
+                }
+                let nonce = params[0].trim_start_matches("0x").to_lowercase();
+                let header = params[1].trim_start_matches("0x").to_lowercase();
+
+                let job_id =
+                    match self.jobs.iter().rev().find(|(h, _)| *h == header) {
+                        Some((_, job_id)) => job_id.clone(),
+                        None => {
+                            debug!("{} 提交了过期任务 {}", self.worker, header);
+                            return (vec![], vec![eth_result(id, false)]);
+                        }
+                    };
+
+                if !nonce.starts_with(&self.extranonce) {
+                    if !self.warned {
+                        self.warned = true;
+                        warn!(
+                            "{} 提交的 nonce {} 不含矿池 extranonce {}。\
+                             EthProxy 矿机无法在 NiceHash 矿池出块",
+                            self.worker, nonce, self.extranonce
+                        );
+                    }
+                    return (vec![], vec![eth_result(id, false)]);
+                }
+
+                let worker = rpc["worker"]
+                    .as_str()
+                    .and_then(|w| self.workers.get(w))
+                    .unwrap_or(&self.worker)
+                    .clone();
+                let pid = self.request(id);
+                let submit = json!({
+                    "id": pid,
+                    "method": "mining.submit",
+                    "params": [
+                        worker,
+                        job_id,
+                        &nonce[self.extranonce.len()..]
+                    ],
+                });
+                (vec![submit], vec![])
+            }
+            _ => (vec![], vec![]),
+        }
+    }
+
+    // 矿池消息。返回需要发给 EthProxy 的消息
+    pub fn pool_line(&mut self, buffer: &str) -> Vec<Value> {
+        let rpc: Value = match serde_json::from_str(buffer) {
+            Ok(rpc) => rpc,
+            Err(_) => return vec![],
+        };
+
+        match rpc["method"].as_str() {
+            Some("mining.notify") => {
+                let params = string_params(&rpc);
+                if params.len() < 3 {
+                    return vec![];
+                }
+                let header = params[2].trim_start_matches("0x").to_lowercase();
+                if self.jobs.len() >= BRIDGE_JOBS {
+                    self.jobs.pop_front();
+                }
+                self.jobs.push_back((header.clone(), params[0].clone()));
+
+                let job = vec![
+                    format!("0x{}", header),
+                    format!("0x{}", params[1].trim_start_matches("0x")),
+                    difficulty_to_target(self.difficulty),
+                ];
+                let rpc = eth_job(0, &job);
+                self.job = Some(job);
+                vec![rpc]
+            }
+            Some("mining.set_difficulty") => {
+                if let Some(diff) = rpc["params"][0].as_f64() {
+                    if diff > 0.0 {
+                        self.difficulty = diff;
+                    }
+                }
+                vec![]
+            }
+            Some("mining.set_extranonce") => match rpc["params"][0].as_str() {
+                Some(extranonce) => {
+                    self.extranonce =
+                        extranonce.trim_start_matches("0x").to_lowercase();
+                    match serde_json::to_value(self.extranonce_notify()) {
+                        Ok(rpc) => vec![rpc],
+                        Err(_) => vec![],
+                    }
+                }
+                None => vec![],
+            },
+            Some(_) => vec![],
+            None => {
+                let pid = match rpc["id"].as_u64() {
+                    Some(pid) => pid,
+                    None => return vec![],
+                };
+                match self.pending.remove(&pid) {
+                    Some(id) => {
+                        let result = rpc["result"].as_bool().unwrap_or(false)
+                            && rpc["error"].is_null();
+                        vec![eth_result(id, result)]
+                    }
+                    None => vec![],
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_eth_stratum_upstream() {
+    let mut upstream = EthStratumUpstream::new("af4c");
+
+    let (to_pool, _) = upstream.client_line(
+        r#"{"id":1001,"method":"eth_submitLogin","params":["0xabc","x"],"worker":"rig1"}"#,
+    );
+    assert_eq!(to_pool[0]["method"], "mining.authorize");
+    assert_eq!(to_pool[0]["params"][0], "0xabc.rig1");
+    let res = upstream.pool_line(&format!(
+        r#"{{"id":{},"result":true,"error":null}}"#,
+        to_pool[0]["id"]
+    ));
+    assert_eq!(res[0], eth_result(1001, true));
+
+    upstream.pool_line(
+        r#"{"id":null,"method":"mining.set_difficulty","params":[2]}"#,
+    );
+    let job = upstream.pool_line(
+        r#"{"id":null,"method":"mining.notify","params":["bf0488aa","abad8f99","645cf201",true]}"#,
+    );
+    assert_eq!(job[0]["result"][0], "0x645cf201");
+    assert_eq!(job[0]["result"][1], "0xabad8f99");
+    assert_eq!(job[0]["result"][2], difficulty_to_target(2.0));
+
+    let (to_pool, to_client) = upstream.client_line(
+        r#"{"id":1007,"method":"mining.suggest_difficulty","params":[8.0]}"#,
+    );
+    assert!(to_client.is_empty());
+    assert_eq!(to_pool[0]["method"], "mining.suggest_difficulty");
+    assert_eq!(to_pool[0]["params"][0], 8.0);
+    // 矿池对建议的回复不转发给矿机
+    let res = upstream.pool_line(&format!(
+        r#"{{"id":{},"result":true,"error":null}}"#,
+        SUGGEST_DIFFICULTY_ID
+    ));
+    assert!(res.is_empty());
+
+    let (to_pool, to_client) = upstream.client_line(
+        r#"{"id":1000,"method":"eth_submitWork","params":["0xaf4c0000deadbeef","0x645cf201","0x00"]}"#,
+    );
+    assert!(to_client.is_empty());
+    assert_eq!(to_pool[0]["params"][1], "bf0488aa");
+    assert_eq!(to_pool[0]["params"][2], "0000deadbeef");
+
+    // 聚合连接上的第二台矿机，工作量以各自的登录名提交
+    upstream.client_line(
+        r#"{"id":1001,"method":"eth_submitLogin","params":["0xabc","x"],"worker":"rig2"}"#,
+    );
+    let (to_pool, _) = upstream.client_line(
+        r#"{"id":1000,"method":"eth_submitWork","params":["0xaf4c0000deadbeef","0x645cf201","0x00"],"worker":"rig1"}"#,
+    );
+    assert_eq!(to_pool[0]["params"][0], "0xabc.rig1");
+
+    // 不含 extranonce 的 nonce 直接拒绝
+    let (to_pool, to_client) = upstream.client_line(
+        r#"{"id":1000,"method":"eth_submitWork","params":["0x12340000deadbeef","0x645cf201","0x00"]}"#,
+    );
+    assert!(to_pool.is_empty());
+    assert_eq!(to_client[0], eth_result(1000, false));
+
+    // 请求过多时只丢弃最早的路由
+    let pids: Vec<Value> = (0..=BRIDGE_PENDING)
+        .map(|i| {
+            let (to_pool, _) = upstream.client_line(&format!(
+                r#"{{"id":{},"method":"eth_submitWork","params":["0xaf4c0000deadbeef","0x645cf201","0x00"]}}"#,
+                i
+            ));
+            to_pool[0]["id"].clone()
+        })
+        .collect();
+    let reply = |pid: &Value| {
+        format!(r#"{{"id":{},"result":true,"error":null}}"#, pid)
+    };
+    assert!(upstream.pool_line(&reply(&pids[0])).is_empty());
+    assert_eq!(upstream.pool_line(&reply(&pids[1])), vec![eth_result(1, true)]);
+    assert_eq!(
+        upstream.pool_line(&reply(&pids[BRIDGE_PENDING])),
+        vec![eth_result(BRIDGE_PENDING as u64, true)]
+    );
+}
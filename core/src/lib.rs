@@ -53,5 +53,6 @@ pub mod client;
 pub mod protocol;
 pub mod proxy;
 pub mod state;
+pub mod storage;
 pub mod util;
 pub mod web;
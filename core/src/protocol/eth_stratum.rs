@@ -1,6 +1,22 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::util::hex_to_int;
+
+pub const ETH_STRATUM_VERSION: &str = "EthereumStratum/1.0.0";
+
+// 每个会话保留的任务数量
+const SESSION_JOBS: usize = 16;
+
+lazy_static! {
+    static ref EXTRANONCE: AtomicU16 = AtomicU16::new(rand::random::<u16>());
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EthLoginNotify {
@@ -16,3 +32,281 @@ pub struct EthSubscriptionNotify {
     pub result: (Vec<String>, String),
     pub error: Value,
 }
+
+//{"id":2,"result":true,"error":null}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EthStratumReply {
+    pub id: u64,
+    pub result: bool,
+    pub error: Value,
+}
+
+//{"id":null,"method":"mining.set_difficulty","params":[0.5]}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EthStratumSetDifficulty {
+    pub id: Value,
+    pub method: String,
+    pub params: Vec<f64>,
+}
+
+//{"id":null,"method":"mining.notify","params":["bf0488aa","abad8f99...","645cf201...",true]}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EthStratumNotify {
+    pub id: Value,
+    pub method: String,
+    pub params: (String, String, String, bool),
+}
+
+//{"id":null,"method":"mining.set_extranonce","params":["af4c"]}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EthStratumSetExtranonce {
+    pub id: Value,
+    pub method: String,
+    pub params: Vec<String>,
+}
+
+// NiceHash 难度1 对应的目标值 0x00000000ffff0000...
+fn diff1_target() -> f64 { 65535.0 * 2f64.powi(208) }
+
+// 目标值换算为 NiceHash 难度
+pub fn target_to_difficulty(target: &str) -> f64 {
+    let target = target.trim_start_matches("0x");
+    let mut value: f64 = 0.0;
+    for c in target.chars() {
+        match c.to_digit(16) {
+            Some(d) => value = value * 16.0 + d as f64,
+            None => return 0.0,
+        }
+    }
+    if value <= 0.0 {
+        return 0.0;
+    }
+    diff1_target() / value
+}
+
+// 找到一个该难度的份额平均需要的哈希次数
+pub fn difficulty_to_hashes(diff: f64) -> f64 {
+    diff * 2f64.powi(256) / diff1_target()
+}
+
+// NiceHash 难度换算为 64 位十六进制目标值
+pub fn difficulty_to_target(diff: f64) -> String {
+    let mut value = if diff > 0.0 {
+        diff1_target() / diff
+    } else {
+        diff1_target()
+    };
+
+    let mut target = String::from("0x");
+    for i in (0..64).rev() {
+        let p = 16f64.powi(i);
+        let d = (value / p).floor().clamp(0.0, 15.0);
+        value -= d * p;
+        target.push(std::char::from_digit(d as u32, 16).unwrap());
+    }
+    target
+}
+
+// 为每个 NiceHash 会话分配 2 字节 extranonce
+pub fn next_extranonce() -> String {
+    format!("{:04x}", EXTRANONCE.fetch_add(1, Ordering::Relaxed))
+}
+
+fn job_height(job: &[String]) -> u64 {
+    match job.get(3) {
+        Some(h) => {
+            let h = h.trim_start_matches("0x");
+            if h.len() > 15 {
+                return 0;
+            }
+            hex_to_int(h).unwrap_or(0) as u64
+        }
+        None => 0,
+    }
+}
+
+// 转换后发给矿机的任务。extranonce 或难度变化时需先行下发
+#[derive(Debug, Clone)]
+pub struct EthStratumJob {
+    pub extranonce: Option<EthStratumSetExtranonce>,
+    pub difficulty: Option<EthStratumSetDifficulty>,
+    pub notify: EthStratumNotify,
+}
+
+#[derive(Debug, Clone)]
+struct SessionJob {
+    id: String,
+    job: Vec<String>,
+    extranonce: String,
+}
+
+// 矿机侧 EthereumStratum/1.0.0 会话状态。
+// 矿池任务 [header, seed, target, (height)] 转为 mining.notify，
+// mining.submit 的 job_id 反查回原任务。
+// 任务可能来自不同的 NiceHash 矿池，各自带有 extranonce，
+// 未订阅 mining.extranonce.subscribe 的矿机只能接收默认 extranonce 的任务。
+#[derive(Debug, Clone)]
+pub struct EthStratumSession {
+    pub extranonce: String,
+    pub extranonce_subscribed: bool,
+    default_extranonce: String,
+    difficulty: f64,
+    seed: String,
+    height: u64,
+    job_idx: u32,
+    jobs: VecDeque<SessionJob>,
+}
+
+impl EthStratumSession {
+    pub fn new(extranonce: String) -> Self {
+        Self {
+            default_extranonce: extranonce.clone(),
+            extranonce,
+            extranonce_subscribed: false,
+            difficulty: 0.0,
+            seed: String::new(),
+            height: 0,
+            job_idx: 0,
+            jobs: VecDeque::new(),
+        }
+    }
+
+    pub fn subscribe_result(&self, id: u64) -> EthSubscriptionNotify {
+        EthSubscriptionNotify {
+            id,
+            result: (
+                vec![
+                    "mining.notify".into(),
+                    format!("{:032x}", rand::random::<u128>()),
+                    ETH_STRATUM_VERSION.into(),
+                ],
+                self.extranonce.clone(),
+            ),
+            error: Value::Null,
+        }
+    }
+
+    // 默认 extranonce 变化（矿池下发 mining.set_extranonce）
+    pub fn set_default_extranonce(&mut self, extranonce: &str) {
+        self.default_extranonce = extranonce.to_string();
+    }
+
+    // 故障转移后矿池分配了新的 extranonce。返回 false 时矿机无法
+    // 接收新矿池的任务 (未订阅 mining.extranonce.subscribe)，需要断开
+    pub fn switch_pool(&mut self, extranonce: &str) -> bool {
+        self.set_default_extranonce(extranonce);
+        self.can_send(None)
+    }
+
+    // 能否下发使用该 extranonce 的任务。None 为默认 extranonce
+    pub fn can_send(&self, extranonce: Option<&str>) -> bool {
+        let extranonce = extranonce.unwrap_or(&self.default_extranonce);
+        self.extranonce_subscribed || extranonce == self.extranonce
+    }
+
+    // 转换任务。extranonce 为 None 时使用默认 extranonce
+    pub fn job(
+        &mut self, job: &[String], extranonce: Option<&str>,
+    ) -> Option<EthStratumJob> {
+        if job.len() < 3 || !self.can_send(extranonce) {
+            return None;
+        }
+
+        let extranonce = extranonce
+            .unwrap_or(&self.default_extranonce)
+            .to_string();
+        let set_extranonce = if extranonce != self.extranonce {
+            self.extranonce = extranonce.clone();
+            Some(EthStratumSetExtranonce {
+                id: Value::Null,
+                method: "mining.set_extranonce".into(),
+                params: vec![extranonce.clone()],
+            })
+        } else {
+            None
+        };
+
+        let set_difficulty = {
+            let diff = target_to_difficulty(&job[2]);
+            if diff > 0.0 && diff != self.difficulty {
+                self.difficulty = diff;
+                Some(EthStratumSetDifficulty {
+                    id: Value::Null,
+                    method: "mining.set_difficulty".into(),
+                    params: vec![diff],
+                })
+            } else {
+                None
+            }
+        };
+
+        let height = job_height(job);
+        let clean = set_extranonce.is_some()
+            || job[1] != self.seed
+            || height == 0
+            || height != self.height;
+        self.seed = job[1].clone();
+        self.height = height;
+
+        self.job_idx = self.job_idx.wrapping_add(1);
+        let job_id = format!("{:08x}", self.job_idx);
+        if self.jobs.len() >= SESSION_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back(SessionJob {
+            id: job_id.clone(),
+            job: job.to_vec(),
+            extranonce,
+        });
+
+        Some(EthStratumJob {
+            extranonce: set_extranonce,
+            difficulty: set_difficulty,
+            notify: EthStratumNotify {
+                id: Value::Null,
+                method: "mining.notify".into(),
+                params: (
+                    job_id,
+                    job[1].trim_start_matches("0x").to_string(),
+                    job[0].trim_start_matches("0x").to_string(),
+                    clean,
+                ),
+            },
+        })
+    }
+
+    // 按 job_id 找回原任务，并补全 nonce。
+    // 矿机只提交 extranonce 之后的部分
+    pub fn submit(
+        &self, job_id: &str, nonce: &str,
+    ) -> Option<(Vec<String>, String)> {
+        let job = self.jobs.iter().rev().find(|j| j.id == job_id)?;
+        let nonce = nonce.trim_start_matches("0x");
+        let nonce = if nonce.len() >= 16 {
+            format!("0x{}", nonce)
+        } else {
+            format!("0x{}{}", job.extranonce, nonce)
+        };
+        Some((job.job.clone(), nonce))
+    }
+}
+
+#[test]
+fn test_switch_pool() {
+    let mut session = EthStratumSession::new("ab12".into());
+    // 同一矿池重连，extranonce 不变
+    assert!(session.switch_pool("ab12"));
+    assert!(!session.switch_pool("cd34"));
+    assert!(!session.can_send(None));
+
+    let mut session = EthStratumSession::new("ab12".into());
+    session.extranonce_subscribed = true;
+    assert!(session.switch_pool("cd34"));
+    let job = ["0x01".into(), "0x02".into(), "0x03".into()];
+    // 已订阅的矿机随任务收到新的 extranonce
+    assert!(session.job(&job, None).unwrap().extranonce.is_some());
+}
@@ -10,6 +10,7 @@ pub const CLIENT_LOGIN: u64 = 1001;
 pub const CLIENT_GETWORK: u64 = 1005;
 pub const CLIENT_SUBHASHRATE: u64 = 1006;
 pub const CLIENT_SUBMITWORK: u64 = 1000;
+pub const CLIENT_SUGGEST_DIFFICULTY: u64 = 1007;
 pub const SUBSCRIBE: u64 = 10002;
 
 #[derive(
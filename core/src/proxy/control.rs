@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use super::Proxy;
+use crate::util::config::FeeRule;
+
+// 主控 web 进程通过标准输入向中转下发的配置更新，每行一条 JSON。
+// 中转不重启，在线矿机在下次上报状态时按新配置调整。
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigUpdate {
+    #[serde(default)]
+    pub fee_rules: Option<Vec<FeeRule>>,
+}
+
+impl ConfigUpdate {
+    // 写入中转进程的标准输入
+    pub async fn send<W>(&self, w: &mut W) -> Result<()>
+    where W: AsyncWrite + Unpin {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        w.write_all(&line).await?;
+        w.flush().await?;
+        Ok(())
+    }
+}
+
+// 中转进程读取配置更新。标准输入关闭时退出
+pub async fn config_updates(proxy: Arc<Proxy>) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let update = match serde_json::from_str::<ConfigUpdate>(&line) {
+            Ok(update) => update,
+            Err(e) => {
+                tracing::warn!("无法解析配置更新 {}", e);
+                continue;
+            }
+        };
+        if let Some(rules) = update.fee_rules {
+            tracing::info!("抽水规则已更新 共 {} 条", rules.len());
+            proxy.config.write().await.fee_rules = rules;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_config_update() {
+    let update = ConfigUpdate {
+        fee_rules: Some(vec![FeeRule {
+            wallet: "0xabc".into(),
+            worker: String::new(),
+            rate: 0.01,
+        }]),
+    };
+    let line = serde_json::to_string(&update).unwrap();
+    assert_eq!(serde_json::from_str::<ConfigUpdate>(&line).unwrap(), update);
+    // 未包含的配置不更新
+    assert_eq!(
+        serde_json::from_str::<ConfigUpdate>("{}").unwrap(),
+        ConfigUpdate::default()
+    );
+}
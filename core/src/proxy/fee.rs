@@ -0,0 +1,279 @@
+use std::{sync::Mutex, time::Duration};
+
+use crate::state::work::{Work, WorkKind, WorkWindow};
+
+// 闭环抽水调度 (share_alg = 2)。
+// 随机抽水在短时间或低算力矿机上与设定比例偏差很大。这里统计滚动窗口内
+// 每台矿机及整个实例按难度加权的实际抽水比例，偏低时提高下发抽水任务的概率，
+// 偏高时降低，使实际比例收敛到设定值。
+// 各矿机的抽水比例可能不同，实例偏差按各份额应抽水量之和计算。
+// 有多个抽水去向时，同样按各去向实际所得与权重的偏差选择去向。
+
+pub const SHARE_ALG_CONTROL: u32 = 2;
+// 按时间段抽水，见 client::handle_stream_timer
+pub const SHARE_ALG_TIMER: u32 = 3;
+
+// 统计窗口
+pub const FEE_WINDOW: Duration = Duration::from_secs(60 * 60);
+// 矿机偏差的修正系数，实例偏差按一半修正
+const GAIN: f64 = 1.0;
+// 抽水概率最多为设定比例的倍数
+const MAX_BOOST: f64 = 3.0;
+
+#[derive(Debug)]
+pub struct FeeScheduler {
+    window: Mutex<WorkWindow>,
+    // 按各份额所属矿机的抽水比例计算的应抽水工作量
+    expected: Mutex<WorkWindow>,
+    // 各抽水去向的抽水工作量
+    pools: Mutex<Vec<WorkWindow>>,
+}
+
+impl Default for FeeScheduler {
+    fn default() -> Self {
+        Self {
+            window: Mutex::new(WorkWindow::new(FEE_WINDOW)),
+            expected: Mutex::new(WorkWindow::new(FEE_WINDOW)),
+            pools: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl FeeScheduler {
+    pub fn new() -> Self { Self::default() }
+
+    // 记录一个份额。rate 及 develop_rate 为该矿机的抽水及开发者抽水比例
+    pub fn record(
+        &self, kind: WorkKind, diff: f64, rate: f64, develop_rate: f64,
+    ) {
+        self.window.lock().unwrap().add(kind, diff);
+        let mut expected = self.expected.lock().unwrap();
+        expected.add(WorkKind::Fee, diff * rate.max(0.0));
+        expected.add(WorkKind::Develop, diff * develop_rate.max(0.0));
+    }
+
+    // 实例窗口内的工作量
+    pub fn work(&self) -> Work { self.window.lock().unwrap().sum() }
+
+    // 记录第 idx 个抽水去向所得的抽水工作量
+    pub fn record_pool(&self, idx: usize, diff: f64) {
+        let mut pools = self.pools.lock().unwrap();
+        while pools.len() <= idx {
+            pools.push(WorkWindow::new(FEE_WINDOW));
+        }
+        pools[idx].add(WorkKind::Fee, diff);
+    }
+
+    // 按权重选择下一个抽水任务的去向
+    pub fn pick_pool(&self, weights: &[u32]) -> usize {
+        if weights.len() <= 1 {
+            return 0;
+        }
+        let work: Vec<f64> = {
+            let mut pools = self.pools.lock().unwrap();
+            (0..weights.len())
+                .map(|i| pools.get_mut(i).map_or(0.0, |w| w.sum().fee))
+                .collect()
+        };
+        let adjusted = pool_weights(weights, &work);
+
+        use rand::SeedableRng;
+        let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
+        let total: f64 = adjusted.iter().sum();
+        let mut point = rand::Rng::gen_range(&mut rng, 0.0..total);
+        for (idx, w) in adjusted.iter().enumerate() {
+            if point < *w {
+                return idx;
+            }
+            point -= w;
+        }
+        adjusted.len() - 1
+    }
+
+    // 下一个任务是否为 kind 类型的抽水任务。worker 为该矿机的统计窗口
+    pub fn is_fee(
+        &self, rate: f64, kind: WorkKind, worker: &mut WorkWindow,
+    ) -> bool {
+        let expected = self.expected.lock().unwrap().sum();
+        let p = fee_probability(
+            rate,
+            kind,
+            &worker.sum(),
+            &self.work(),
+            &expected,
+        );
+        if p <= 0.0 {
+            return false;
+        }
+
+        use rand::SeedableRng;
+        let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
+        rand::Rng::gen_bool(&mut rng, p)
+    }
+}
+
+// instance 为实例实际工作量，expected 为实例应抽水工作量
+pub fn fee_probability(
+    rate: f64, kind: WorkKind, worker: &Work, instance: &Work,
+    expected: &Work,
+) -> f64 {
+    if rate <= 0.0 {
+        return 0.0;
+    }
+
+    let mut p = rate;
+    if worker.total() > 0.0 {
+        p += GAIN * (rate - worker.get(kind) / worker.total());
+    }
+    if instance.total() > 0.0 {
+        p += GAIN / 2.0 * (expected.get(kind) - instance.get(kind))
+            / instance.total();
+    }
+    p.clamp(0.0, (rate * MAX_BOOST).min(1.0))
+}
+
+// 按实际所得修正后的去向权重
+fn pool_weights(weights: &[u32], work: &[f64]) -> Vec<f64> {
+    let total_weight: u32 = weights.iter().sum();
+    let total_work: f64 = work.iter().sum();
+    let adjusted: Vec<f64> = weights
+        .iter()
+        .zip(work)
+        .map(|(weight, work)| {
+            let share = *weight as f64 / total_weight.max(1) as f64;
+            if total_work > 0.0 {
+                (share + GAIN * (share - work / total_work)).max(0.0)
+            } else {
+                share
+            }
+        })
+        .collect();
+
+    // 全部修正为 0 时退回按权重
+    if adjusted.iter().sum::<f64>() > 0.0 {
+        adjusted
+    } else {
+        weights.iter().map(|w| *w as f64).collect()
+    }
+}
+
+#[test]
+fn test_fee_probability() {
+    let work = |normal: f64, fee: f64| Work {
+        normal,
+        fee,
+        develop: 0.0,
+    };
+    let none = Work::default();
+
+    let fee = |rate: f64, worker: &Work, instance: &Work, expected: f64| {
+        let expected = work(0.0, expected);
+        fee_probability(rate, WorkKind::Fee, worker, instance, &expected)
+    };
+
+    assert_eq!(fee(0.0, &none, &none, 0.0), 0.0);
+    assert_eq!(fee(0.05, &none, &none, 0.0), 0.05);
+
+    // 达到设定比例时不修正
+    let on_target = work(95.0, 5.0);
+    let p = fee(0.05, &on_target, &on_target, 5.0);
+    assert!((p - 0.05).abs() < 1e-9);
+
+    // 抽水不足时提高概率，但不超过上限
+    let p = fee(0.05, &work(100.0, 0.0), &none, 0.0);
+    assert!((p - 0.1).abs() < 1e-9);
+    let starved = work(100.0, 0.0);
+    let p = fee(0.05, &starved, &starved, 5.0);
+    assert!((p - 0.125).abs() < 1e-9);
+    let p = fee(0.5, &starved, &starved, 50.0);
+    assert_eq!(p, 1.0);
+
+    // 抽水过多时停止抽水
+    let p = fee(0.05, &work(2.0, 1.0), &none, 0.0);
+    assert_eq!(p, 0.0);
+
+    // 实例中有不抽水的矿机时，实例达到应抽水量即不修正
+    let p = fee(0.1, &work(90.0, 10.0), &work(190.0, 10.0), 10.0);
+    assert!((p - 0.1).abs() < 1e-9);
+
+    // 开发者抽水只看开发者工作量
+    let p = fee_probability(
+        0.01,
+        WorkKind::Develop,
+        &work(99.0, 1.0),
+        &none,
+        &none,
+    );
+    assert!((p - 0.02).abs() < 1e-9);
+}
+
+#[test]
+fn test_fee_scheduler_converges() {
+    // 模拟一台低算力矿机：每个任务以相同难度提交一个份额
+    let scheduler = FeeScheduler::new();
+    let mut worker = WorkWindow::new(FEE_WINDOW);
+    for _ in 0..4000 {
+        let kind = if scheduler.is_fee(0.05, WorkKind::Fee, &mut worker) {
+            WorkKind::Fee
+        } else {
+            WorkKind::Normal
+        };
+        worker.add(kind, 1.0);
+        scheduler.record(kind, 1.0, 0.05, 0.0);
+    }
+    let rate = scheduler.work().fee_rate();
+    assert!((rate - 5.0).abs() < 1.0, "实际抽水比例 {}", rate);
+}
+
+#[test]
+fn test_fee_scheduler_mixed_rates() {
+    // 一台矿机不抽水，另一台按 10% 抽水，各自收敛到自己的比例
+    let scheduler = FeeScheduler::new();
+    let mut workers = [
+        (0.0, WorkWindow::new(FEE_WINDOW)),
+        (0.1, WorkWindow::new(FEE_WINDOW)),
+    ];
+    for _ in 0..10000 {
+        for (rate, worker) in workers.iter_mut() {
+            let kind = if scheduler.is_fee(*rate, WorkKind::Fee, worker) {
+                WorkKind::Fee
+            } else {
+                WorkKind::Normal
+            };
+            worker.add(kind, 1.0);
+            scheduler.record(kind, 1.0, *rate, 0.0);
+        }
+    }
+    for (rate, worker) in workers.iter_mut() {
+        let actual = worker.sum().fee_rate();
+        assert!(
+            (actual - *rate * 100.0).abs() < 0.5,
+            "设定 {} 实际抽水比例 {}",
+            rate,
+            actual
+        );
+    }
+}
+
+#[test]
+fn test_pick_pool() {
+    assert_eq!(pool_weights(&[1, 3], &[0.0, 0.0]), vec![0.25, 0.75]);
+    // 第一个去向所得过多
+    assert_eq!(pool_weights(&[1, 1], &[3.0, 1.0]), vec![0.25, 0.75]);
+    assert_eq!(pool_weights(&[1, 1], &[1.0, 0.0]), vec![0.0, 1.0]);
+
+    let scheduler = FeeScheduler::new();
+    assert_eq!(scheduler.pick_pool(&[5]), 0);
+
+    let weights = [1, 2, 7];
+    for _ in 0..2000 {
+        let idx = scheduler.pick_pool(&weights);
+        scheduler.record_pool(idx, 1.0);
+    }
+    let mut pools = scheduler.pools.lock().unwrap();
+    for (idx, weight) in weights.iter().enumerate() {
+        let share = pools[idx].sum().fee / 2000.0;
+        let expected = *weight as f64 / 10.0;
+        assert!((share - expected).abs() < 0.02, "去向 {} {}", idx, share);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+};
+
+use crate::protocol::ethjson::EthServerRootObjectJsonRpc;
+
+// 抽水及开发者矿池的任务。
+// 只保留最近 MAX_JOBS 个任务，按任务 id 去重；出现更高的区块高度时，
+// 低于该高度的任务全部丢弃。各矿机连接只读取当前任务。
+
+const MAX_JOBS: usize = 16;
+
+#[derive(Debug)]
+struct StoredJob {
+    id: String,
+    // 0 为矿池未提供高度
+    height: u64,
+    job: Arc<Vec<String>>,
+}
+
+#[derive(Debug, Default)]
+struct Jobs {
+    jobs: VecDeque<StoredJob>,
+    height: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct JobStore {
+    inner: RwLock<Jobs>,
+}
+
+pub fn job_height(job: &[String]) -> u64 {
+    if job.len() < 4 {
+        return 0;
+    }
+    EthServerRootObjectJsonRpc {
+        id: 0,
+        jsonrpc: "2.0".into(),
+        result: job.to_vec(),
+    }
+    .get_hight()
+}
+
+impl JobStore {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn push(&self, job: Vec<String>) {
+        let id = match job.first() {
+            Some(id) => id.clone(),
+            None => return,
+        };
+        let height = job_height(&job);
+
+        let mut inner = self.inner.write().unwrap();
+        // 晚到的旧区块任务
+        if height != 0 && height < inner.height {
+            return;
+        }
+        if height > inner.height {
+            inner.height = height;
+            inner.jobs.retain(|j| j.height == 0 || j.height >= height);
+        }
+
+        inner.jobs.retain(|j| j.id != id);
+        if inner.jobs.len() >= MAX_JOBS {
+            inner.jobs.pop_front();
+        }
+        inner.jobs.push_back(StoredJob {
+            id,
+            height,
+            job: Arc::new(job),
+        });
+    }
+
+    // 当前任务
+    pub fn current(&self) -> Option<Arc<Vec<String>>> {
+        let inner = self.inner.read().unwrap();
+        inner.jobs.back().map(|j| j.job.clone())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Vec<String>>> {
+        let inner = self.inner.read().unwrap();
+        inner.jobs.iter().find(|j| j.id == id).map(|j| j.job.clone())
+    }
+
+    pub fn len(&self) -> usize { self.inner.read().unwrap().jobs.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.write().unwrap();
+        inner.jobs.clear();
+        inner.height = 0;
+    }
+}
+
+#[test]
+fn test_job_store() {
+    let job = |id: &str, height: &str| -> Vec<String> {
+        vec![
+            id.to_string(),
+            "0x00".to_string(),
+            "0x00".to_string(),
+            height.to_string(),
+        ]
+    };
+
+    let store = JobStore::new();
+    assert!(store.current().is_none());
+
+    store.push(job("0x01", "0x64"));
+    store.push(job("0x02", "0x64"));
+    store.push(job("0x01", "0x64"));
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.current().unwrap()[0], "0x01");
+    assert!(store.get("0x02").is_some());
+
+    // 新区块淘汰旧高度的任务
+    store.push(job("0x03", "0x65"));
+    assert_eq!(store.len(), 1);
+    assert!(store.get("0x02").is_none());
+
+    // 晚到的旧任务丢弃
+    store.push(job("0x04", "0x64"));
+    assert_eq!(store.current().unwrap()[0], "0x03");
+
+    for i in 0..100 {
+        store.push(job(&format!("0x{:x}", 0x100 + i), ""));
+    }
+    assert_eq!(store.len(), MAX_JOBS);
+
+    store.clear();
+    assert!(store.is_empty());
+}
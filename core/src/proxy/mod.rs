@@ -1,21 +1,64 @@
-use std::{sync::Arc, collections::VecDeque};
+pub mod control;
+pub mod fee;
+pub mod job;
 
-use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender, RwLock, Mutex};
+use std::sync::Arc;
 
-use crate::{state::Worker, util::config::Settings};
+use tokio::sync::{
+    broadcast::Sender,
+    mpsc::{self, Receiver, UnboundedSender},
+    Mutex, RwLock,
+};
 
-pub type Job =    Arc<RwLock<VecDeque<Vec<String>>>>;
+use crate::{
+    state::{ledger::ShareRecord, Worker},
+    util::config::{FeeDestination, Settings},
+};
 
+pub type Job = Arc<job::JobStore>;
+// NiceHash 抽水矿池分配的 extranonce。EthProxy 矿池为 None
+pub type Extranonce = Arc<RwLock<Option<String>>>;
+
+// 转发给抽水或开发者矿池的份额及其账本记录
+#[derive(Debug)]
+pub struct FeeShare {
+    pub params: Vec<String>,
+    pub record: ShareRecord,
+}
+
+// 一个抽水去向：独立的矿池连接、任务及份额通道
+pub struct FeePool {
+    pub name: String,
+    pub weight: u32,
+    pub job: Job,
+    pub extranonce: Extranonce,
+    pub tx: mpsc::Sender<FeeShare>,
+}
+
+impl FeePool {
+    pub fn new(dest: &FeeDestination) -> (Self, Receiver<FeeShare>) {
+        let (tx, rx) = mpsc::channel::<FeeShare>(15);
+        let pool = Self {
+            name: dest.name.clone(),
+            weight: dest.weight,
+            job: Arc::new(job::JobStore::new()),
+            extranonce: Arc::new(RwLock::new(None)),
+            tx,
+        };
+        (pool, rx)
+    }
+}
 
 pub struct Proxy {
     pub config: Arc<RwLock<Settings>>,
     // pub chan: Sender<Vec<String>>,
     // pub dev_chan: Sender<Vec<String>>,
-    pub fee_job:Job,
+    pub fee_pools: Vec<FeePool>,
     pub develop_job:Job,
-    pub tx: tokio::sync::mpsc::Sender<Vec<String>>,
-    pub dev_tx: tokio::sync::mpsc::Sender<Vec<String>>,
+    pub develop_extranonce: Extranonce,
+    pub dev_tx: tokio::sync::mpsc::Sender<FeeShare>,
     pub worker_tx: UnboundedSender<Worker>,
+    pub fee_scheduler: fee::FeeScheduler,
     // pub proxy_write: Arc<Mutex<Box<dyn AsyncWrite + Send + Sync + Unpin>>>,
     // pub dev_write: Arc<Mutex<Box<dyn AsyncWrite + Send + Sync + Unpin>>>,
 }
@@ -0,0 +1,672 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    select,
+    sync::mpsc::{self, Sender, UnboundedSender},
+};
+
+use super::{
+    work::{Work, WorkKind},
+    Worker,
+};
+
+// 份额账本。
+// 矿池返回结果后，每个普通、抽水及开发者份额写入
+// ledger/<中转名称>/<日期>.log，每行一条 JSON 记录，重启后不丢失。
+// 写入时同时按 (矿机, 钱包, 去向, 类型) 汇总当天的份额，定时写入
+// <日期>.sum，对账只读汇总。明细按 ledger_days 保留，汇总一直保留。
+
+const LEDGER_DIR: &str = "./ledger/";
+// 等待结果的份额上限，超出的最早份额按未接受记账
+const MAX_PENDING: usize = 1024;
+// 当天汇总写入文件的间隔
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+// 未设置 ledger_days 时明细保留的天数
+pub const DEFAULT_LEDGER_DAYS: u32 = 90;
+// 导出明细时每块的记录数
+const EXPORT_CHUNK: usize = 1000;
+
+lazy_static! {
+    static ref LEDGER: Mutex<Option<UnboundedSender<ShareRecord>>> =
+        Mutex::new(None);
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareRecord {
+    // unix 时间戳 (秒)
+    pub time: i64,
+    pub worker: String,
+    pub wallet: String,
+    pub kind: WorkKind,
+    // 抽水去向，普通份额为空
+    pub destination: String,
+    pub diff: f64,
+    // 该矿机设定的抽水比例
+    pub rate: f64,
+    pub accepted: bool,
+}
+
+impl ShareRecord {
+    pub fn new(
+        worker: &Worker, kind: WorkKind, destination: &str, diff: f64,
+        rate: f64,
+    ) -> Self {
+        Self {
+            time: Local::now().timestamp(),
+            worker: worker.worker_name.clone(),
+            wallet: worker.worker_wallet.clone(),
+            kind,
+            destination: destination.to_string(),
+            diff,
+            rate,
+            accepted: false,
+        }
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        Local.timestamp(self.time, 0).date().naive_local()
+    }
+}
+
+// 写入账本。未启动账本线程时忽略
+pub fn record(record: ShareRecord) {
+    if let Some(tx) = &*LEDGER.lock().unwrap() {
+        tx.send(record).ok();
+    }
+}
+
+// 已提交、等待矿池返回结果的份额
+#[derive(Debug, Default)]
+pub struct PendingShares {
+    shares: VecDeque<(u64, ShareRecord)>,
+}
+
+impl PendingShares {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn push(&mut self, id: u64, share: ShareRecord) {
+        if self.shares.len() >= MAX_PENDING {
+            if let Some((_, share)) = self.shares.pop_front() {
+                record(share);
+            }
+        }
+        self.shares.push_back((id, share));
+    }
+
+    // 矿池返回 id 的结果。同一 id 有多个份额时按提交顺序对应。
+    // 返回该份额的难度
+    pub fn resolve(&mut self, id: u64, accepted: bool) -> Option<f64> {
+        let idx = self.shares.iter().position(|(i, _)| *i == id)?;
+        let (_, mut share) = self.shares.remove(idx)?;
+        let diff = share.diff;
+        share.accepted = accepted;
+        record(share);
+        Some(diff)
+    }
+
+    // 连接断开，剩余份额按未接受记账
+    pub fn clear(&mut self) {
+        for (_, share) in self.shares.drain(..) {
+            record(share);
+        }
+    }
+}
+
+impl Drop for PendingShares {
+    fn drop(&mut self) { self.clear(); }
+}
+
+// 一天内同一 (矿机, 钱包, 去向, 类型) 的份额汇总
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareSummary {
+    pub worker: String,
+    pub wallet: String,
+    pub kind: WorkKind,
+    pub destination: String,
+    pub shares: u64,
+    pub rejected: u64,
+    // 已接受份额的工作量
+    pub diff: f64,
+    pub rejected_diff: f64,
+    // 已接受份额按矿机设定比例应抽的工作量
+    pub expected_fee: f64,
+}
+
+impl ShareSummary {
+    fn add(&mut self, share: &ShareRecord) {
+        self.shares += 1;
+        if share.accepted {
+            self.diff += share.diff;
+            self.expected_fee += share.diff * share.rate;
+        } else {
+            self.rejected += 1;
+            self.rejected_diff += share.diff;
+        }
+    }
+}
+
+type SummaryKey = (String, String, String, WorkKind);
+
+// 一天的份额汇总
+#[derive(Debug)]
+struct DaySummary {
+    date: NaiveDate,
+    rows: HashMap<SummaryKey, ShareSummary>,
+    // 有未写入文件的份额
+    dirty: bool,
+}
+
+impl DaySummary {
+    fn new(date: NaiveDate) -> Self {
+        Self {
+            date,
+            rows: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    fn add(&mut self, share: &ShareRecord) {
+        let key = (
+            share.worker.clone(),
+            share.wallet.clone(),
+            share.destination.clone(),
+            share.kind,
+        );
+        self.rows
+            .entry(key)
+            .or_insert_with(|| ShareSummary {
+                worker: share.worker.clone(),
+                wallet: share.wallet.clone(),
+                kind: share.kind,
+                destination: share.destination.clone(),
+                shares: 0,
+                rejected: 0,
+                diff: 0.0,
+                rejected_diff: 0.0,
+                expected_fee: 0.0,
+            })
+            .add(share);
+        self.dirty = true;
+    }
+
+    // 先写临时文件再改名，读取方不会读到写了一半的汇总
+    async fn save(&mut self, dir: &Path) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut content = Vec::new();
+        for row in self.rows.values() {
+            serde_json::to_writer(&mut content, row)?;
+            content.push(b'\n');
+        }
+        let path = summary_file(dir, self.date);
+        let tmp = path.with_extension("sum.tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// 按矿机、钱包筛选账本。为空时不筛选
+#[derive(Debug, Clone, Default)]
+pub struct LedgerFilter {
+    pub wallet: String,
+    pub worker: String,
+}
+
+impl LedgerFilter {
+    pub fn new(wallet: &str, worker: &str) -> Self {
+        Self {
+            wallet: wallet.trim().to_lowercase(),
+            worker: worker.trim().to_string(),
+        }
+    }
+
+    fn matches(&self, worker: &str, wallet: &str) -> bool {
+        (self.wallet.is_empty() || wallet.to_lowercase() == self.wallet)
+            && (self.worker.is_empty() || worker == self.worker)
+    }
+}
+
+pub fn ledger_dir(name: &str) -> PathBuf { Path::new(LEDGER_DIR).join(name) }
+
+fn ledger_file(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("{}.log", date.format("%Y-%m-%d")))
+}
+
+fn summary_file(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("{}.sum", date.format("%Y-%m-%d")))
+}
+
+// 逐行读取一天的明细并汇总。无法解析的行 (如写入中断) 跳过
+fn summarize_log(path: &Path, date: NaiveDate) -> Result<DaySummary> {
+    let mut summary = DaySummary::new(date);
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(summary)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(share) = serde_json::from_str::<ShareRecord>(&line?) {
+            summary.add(&share);
+        }
+    }
+    Ok(summary)
+}
+
+// 删除 keep_from 之前的明细
+fn prune(dir: &Path, keep_from: NaiveDate) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("log") {
+            continue;
+        }
+        let date = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok());
+        if matches!(date, Some(date) if date < keep_from) {
+            std::fs::remove_file(&path)?;
+            tracing::info!("删除过期份额明细 {:?}", path);
+        }
+    }
+    Ok(())
+}
+
+fn prune_ledger(dir: &Path, days: u32) {
+    let days = if days == 0 { DEFAULT_LEDGER_DAYS } else { days };
+    let today = Local::now().date().naive_local();
+    if let Err(e) = prune(dir, today - chrono::Duration::days(days as i64)) {
+        tracing::error!("清理份额明细失败 {}", e);
+    }
+}
+
+// 账本写入线程。写入失败只记录日志，不影响中转。
+// days 为明细保留天数，0 为默认值
+pub async fn ledger_writer(name: String, days: u32) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<ShareRecord>();
+    *LEDGER.lock().unwrap() = Some(tx);
+
+    let dir = ledger_dir(&name);
+    tokio::fs::create_dir_all(&dir).await?;
+    prune_ledger(&dir, days);
+
+    // 重启后按当天已有的明细继续汇总
+    let today = Local::now().date().naive_local();
+    let mut summary = summarize_log(&ledger_file(&dir, today), today)?;
+    let mut interval = tokio::time::interval(SUMMARY_INTERVAL);
+
+    let mut file: Option<(NaiveDate, File)> = None;
+    loop {
+        let share = select! {
+            share = rx.recv() => match share {
+                Some(share) => share,
+                None => break,
+            },
+            _ = interval.tick() => {
+                if let Err(e) = summary.save(&dir).await {
+                    tracing::error!("写入份额汇总失败 {}", e);
+                }
+                continue;
+            },
+        };
+        let mut shares = vec![share];
+        while let Ok(share) = rx.try_recv() {
+            shares.push(share);
+        }
+        for share in shares {
+            if let Err(e) = write_record(&dir, &mut file, &share).await {
+                tracing::error!("写入份额账本失败 {}", e);
+                file = None;
+            }
+            let date = share.date();
+            if date != summary.date {
+                // 进入新的一天，写完前一天的汇总并清理过期明细
+                if let Err(e) = summary.save(&dir).await {
+                    tracing::error!("写入份额汇总失败 {}", e);
+                }
+                summary = summarize_log(&ledger_file(&dir, date), date)?;
+                prune_ledger(&dir, days);
+            }
+            summary.add(&share);
+        }
+        if let Some((_, f)) = file.as_mut() {
+            if let Err(e) = f.flush().await {
+                tracing::error!("写入份额账本失败 {}", e);
+            }
+        }
+    }
+    summary.save(&dir).await
+}
+
+async fn write_record(
+    dir: &Path, file: &mut Option<(NaiveDate, File)>, share: &ShareRecord,
+) -> Result<()> {
+    let date = share.date();
+    if !matches!(file, Some((d, _)) if *d == date) {
+        if let Some((_, mut f)) = file.take() {
+            f.flush().await?;
+        }
+        let f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(ledger_file(dir, date))
+            .await?;
+        *file = Some((date, f));
+    }
+
+    let mut line = serde_json::to_vec(share)?;
+    line.push(b'\n');
+    if let Some((_, f)) = file.as_mut() {
+        f.write_all(&line).await?;
+    }
+    Ok(())
+}
+
+// 按天读取 [from, to] 日期内的汇总，一次只读一个文件。
+// 没有汇总的日期 (如旧版本写入的明细) 按明细汇总
+pub fn read_reports(
+    name: &str, from: NaiveDate, to: NaiveDate, filter: &LedgerFilter,
+) -> Result<Vec<DayReport>> {
+    reports_in(&ledger_dir(name), from, to, filter)
+}
+
+fn reports_in(
+    dir: &Path, from: NaiveDate, to: NaiveDate, filter: &LedgerFilter,
+) -> Result<Vec<DayReport>> {
+    let mut reports = Vec::new();
+    let mut date = from;
+    while date <= to {
+        let mut report = DayReport {
+            date: date.format("%Y-%m-%d").to_string(),
+            ..Default::default()
+        };
+        let mut add = |row: &ShareSummary| {
+            if filter.matches(&row.worker, &row.wallet) {
+                report.add(row);
+            }
+        };
+        match std::fs::File::open(summary_file(dir, date)) {
+            Ok(file) => {
+                for line in std::io::BufReader::new(file).lines() {
+                    if let Ok(row) = serde_json::from_str(&line?) {
+                        add(&row);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let summary = summarize_log(&ledger_file(dir, date), date)?;
+                summary.rows.values().for_each(add);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if report.shares > 0 {
+            reports.push(report);
+        }
+        date = date.succ();
+    }
+    Ok(reports)
+}
+
+// 按天逐行读取 [from, to] 日期内的份额明细，每 EXPORT_CHUNK 条转为一块 CSV
+// 发送给 tx。接收方关闭时停止
+pub async fn export_records(
+    name: String, from: NaiveDate, to: NaiveDate, filter: LedgerFilter,
+    tx: Sender<Bytes>,
+) -> Result<()> {
+    export_in(&ledger_dir(&name), from, to, &filter, tx).await
+}
+
+async fn export_in(
+    dir: &Path, from: NaiveDate, to: NaiveDate, filter: &LedgerFilter,
+    tx: Sender<Bytes>,
+) -> Result<()> {
+    if tx.send(Bytes::from(records_csv(&[]))).await.is_err() {
+        return Ok(());
+    }
+
+    let mut chunk = Vec::with_capacity(EXPORT_CHUNK);
+    let mut date = from;
+    while date <= to {
+        let file = match File::open(ledger_file(dir, date)).await {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(file) = file {
+            let mut lines = BufReader::new(file).lines();
+            while let Some(line) = lines.next_line().await? {
+                let share: ShareRecord = match serde_json::from_str(&line) {
+                    Ok(share) => share,
+                    Err(_) => continue,
+                };
+                if !filter.matches(&share.worker, &share.wallet) {
+                    continue;
+                }
+                chunk.push(share);
+                if chunk.len() >= EXPORT_CHUNK {
+                    let csv = records_rows(&chunk);
+                    chunk.clear();
+                    if tx.send(Bytes::from(csv)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        date = date.succ();
+    }
+    if !chunk.is_empty() {
+        tx.send(Bytes::from(records_rows(&chunk))).await.ok();
+    }
+    Ok(())
+}
+
+// 一天的对账结果
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DayReport {
+    pub date: String,
+    pub shares: u64,
+    pub rejected: u64,
+    // 已接受份额按难度累计的工作量
+    pub work: Work,
+    // 按矿机设定比例应抽的工作量
+    pub expected_fee: f64,
+    // 抽水矿池拒绝的抽水工作量
+    pub rejected_fee: f64,
+    // 各抽水去向实际所得
+    pub destinations: BTreeMap<String, f64>,
+}
+
+impl DayReport {
+    fn add(&mut self, row: &ShareSummary) {
+        self.shares += row.shares;
+        self.rejected += row.rejected;
+        self.work.add(row.kind, row.diff);
+        self.expected_fee += row.expected_fee;
+        if row.kind == WorkKind::Fee {
+            self.rejected_fee += row.rejected_diff;
+            if row.shares > row.rejected {
+                *self
+                    .destinations
+                    .entry(row.destination.clone())
+                    .or_default() += row.diff;
+            }
+        }
+    }
+
+    // 应抽比例 (百分比)
+    pub fn expected_rate(&self) -> f64 {
+        if self.work.total() > 0.0 {
+            self.expected_fee / self.work.total() * 100.0
+        } else {
+            0.0
+        }
+    }
+
+    // 实抽减应抽的工作量
+    pub fn difference(&self) -> f64 { self.work.fee - self.expected_fee }
+}
+
+pub fn reports_csv(reports: &[DayReport]) -> String {
+    let mut csv = String::from(
+        "date,shares,rejected,normal,fee,develop,expected_fee,rejected_fee,\
+         fee_rate,expected_rate,difference\n",
+    );
+    for r in reports {
+        csv += &format!(
+            "{},{},{},{},{},{},{},{},{:.4},{:.4},{}\n",
+            r.date,
+            r.shares,
+            r.rejected,
+            r.work.normal,
+            r.work.fee,
+            r.work.develop,
+            r.expected_fee,
+            r.rejected_fee,
+            r.work.fee_rate(),
+            r.expected_rate(),
+            r.difference(),
+        );
+    }
+    csv
+}
+
+pub fn records_csv(records: &[ShareRecord]) -> String {
+    String::from("time,worker,wallet,kind,destination,diff,rate,accepted\n")
+        + &records_rows(records)
+}
+
+fn records_rows(records: &[ShareRecord]) -> String {
+    let mut csv = String::new();
+    for r in records {
+        csv += &format!(
+            "{},{},{},{},{},{},{},{}\n",
+            Local.timestamp(r.time, 0).format("%Y-%m-%d %H:%M:%S"),
+            csv_field(&r.worker),
+            csv_field(&r.wallet),
+            serde_json::to_value(r.kind)
+                .ok()
+                .and_then(|v| v.as_str().map(String::from))
+                .unwrap_or_default(),
+            csv_field(&r.destination),
+            r.diff,
+            r.rate,
+            r.accepted,
+        );
+    }
+    csv
+}
+
+// 矿机名由矿机上报，可能含有逗号或引号
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[tokio::test]
+async fn test_ledger_reconcile() {
+    let share = |time: i64, kind: WorkKind, dest: &str, accepted: bool| {
+        ShareRecord {
+            time,
+            worker: "w1".into(),
+            wallet: "0xabc".into(),
+            kind,
+            destination: dest.into(),
+            diff: 2.0,
+            rate: 0.25,
+            accepted,
+        }
+    };
+    let day = Local.ymd(2022, 3, 1).and_hms(12, 0, 0).timestamp();
+    let records = vec![
+        share(day, WorkKind::Normal, "", true),
+        share(day, WorkKind::Normal, "", true),
+        share(day, WorkKind::Normal, "", false),
+        share(day + 60, WorkKind::Fee, "a", true),
+        share(day + 60, WorkKind::Fee, "b", false),
+        share(day + 86_400, WorkKind::Fee, "b", true),
+    ];
+
+    let line = serde_json::to_string(&records[3]).unwrap();
+    assert!(line.contains("\"kind\":\"fee\""));
+    assert_eq!(serde_json::from_str::<ShareRecord>(&line).unwrap(), records[3]);
+
+    let dir = std::env::temp_dir()
+        .join(format!("ledger-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let first_day = records[0].date();
+    let second_day = records[5].date();
+
+    // 第一天只有明细，第二天只有汇总
+    let mut file = None;
+    for r in &records[..5] {
+        write_record(&dir, &mut file, r).await.unwrap();
+    }
+    file.unwrap().1.flush().await.unwrap();
+    let mut summary = DaySummary::new(second_day);
+    summary.add(&records[5]);
+    summary.save(&dir).await.unwrap();
+
+    let all = LedgerFilter::default();
+    let reports = reports_in(&dir, first_day, second_day, &all).unwrap();
+    assert_eq!(reports.len(), 2);
+    let first = &reports[0];
+    assert_eq!(first.date, "2022-03-01");
+    assert_eq!(first.shares, 5);
+    assert_eq!(first.rejected, 2);
+    assert_eq!(first.work.total(), 6.0);
+    assert_eq!(first.expected_fee, 1.5);
+    assert_eq!(first.rejected_fee, 2.0);
+    assert_eq!(first.destinations.get("a"), Some(&2.0));
+    assert!(first.destinations.get("b").is_none());
+    assert_eq!(first.difference(), 0.5);
+    assert_eq!(reports[1].date, "2022-03-02");
+    assert_eq!(reports[1].expected_rate(), 25.0);
+
+    let other = LedgerFilter::new("", "w2");
+    assert!(reports_in(&dir, first_day, second_day, &other).unwrap().is_empty());
+    let wallet = LedgerFilter::new(" 0xABC ", "");
+    assert_eq!(reports_in(&dir, first_day, first_day, &wallet).unwrap().len(), 1);
+
+    // 明细按块导出
+    let (tx, mut rx) = mpsc::channel(4);
+    let export = tokio::spawn({
+        let dir = dir.clone();
+        async move { export_in(&dir, first_day, second_day, &all, tx).await }
+    });
+    let mut csv = String::new();
+    while let Some(chunk) = rx.recv().await {
+        csv += std::str::from_utf8(&chunk).unwrap();
+    }
+    export.await.unwrap().unwrap();
+    assert!(csv.starts_with("time,worker,"));
+    assert_eq!(csv.lines().count(), 6);
+
+    // 过期明细删除，汇总保留
+    prune(&dir, second_day).unwrap();
+    assert!(!ledger_file(&dir, first_day).exists());
+    assert!(summary_file(&dir, second_day).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let csv = records_csv(&records[3..4]);
+    assert!(csv.ends_with(",w1,0xabc,fee,a,2,0.25,true\n"));
+    assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+}
@@ -1,4 +1,9 @@
-use std::u128;
+pub mod ledger;
+pub mod share;
+pub mod vardiff;
+pub mod work;
+
+use std::{collections::BTreeMap, u128};
 
 extern crate serde_millis;
 
@@ -9,6 +14,16 @@ use tracing::{debug, info};
 
 use crate::protocol::PROTOCOL;
 
+use self::{
+    share::ShareStatus,
+    work::{EffectiveHashrate, Work, WorkKind},
+};
+
+// 本地校验未通过达到 FAULTY_MIN_SHARES 个且占提交份额 FAULTY_PERCENT% 以上
+// 视为故障矿机
+const FAULTY_MIN_SHARES: u64 = 3;
+const FAULTY_PERCENT: u64 = 5;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Worker {
     pub worker: String,
@@ -32,6 +47,27 @@ pub struct Worker {
     pub fee_share_index: u64,
     pub fee_accept_index: u64,
     pub fee_invalid_index: u64,
+    #[serde(default)]
+    pub develop_share_index: u64,
+    // 本地校验未通过的份额
+    #[serde(default)]
+    pub duplicate_share_index: u64,
+    #[serde(default)]
+    pub unknown_share_index: u64,
+    #[serde(default)]
+    pub stale_share_index: u64,
+    // 本地 Ethash 校验未通过的份额
+    #[serde(default)]
+    pub local_invalid_index: u64,
+    // 按难度加权的工作量
+    #[serde(default)]
+    pub work: Work,
+    // 各抽水去向所得的抽水工作量
+    #[serde(default)]
+    pub fee_work: BTreeMap<String, f64>,
+    // 按已接受份额计算的有效算力
+    #[serde(default)]
+    pub effective_hash: EffectiveHashrate,
 }
 
 impl Worker {
@@ -57,6 +93,14 @@ impl Worker {
             fee_share_index: 0,
             fee_accept_index: 0,
             fee_invalid_index: 0,
+            develop_share_index: 0,
+            duplicate_share_index: 0,
+            unknown_share_index: 0,
+            stale_share_index: 0,
+            local_invalid_index: 0,
+            work: Work::default(),
+            fee_work: BTreeMap::new(),
+            effective_hash: EffectiveHashrate::default(),
             rpc_id: 0,
         }
     }
@@ -80,6 +124,14 @@ impl Worker {
             fee_share_index: 0,
             fee_accept_index: 0,
             fee_invalid_index: 0,
+            develop_share_index: 0,
+            duplicate_share_index: 0,
+            unknown_share_index: 0,
+            stale_share_index: 0,
+            local_invalid_index: 0,
+            work: Work::default(),
+            fee_work: BTreeMap::new(),
+            effective_hash: EffectiveHashrate::default(),
             rpc_id: 0,
         }
     }
@@ -187,6 +239,39 @@ impl Worker {
         //debug!("矿工: {} Share Reject #{}", self.worker, self.share_index);
     }
 
+    // 开发者份额增加
+    pub fn develop_share_index_add(&mut self) {
+        self.develop_share_index += 1;
+    }
+
+    // 本地校验未通过的份额增加
+    pub fn bad_share_add(&mut self, status: ShareStatus) {
+        match status {
+            ShareStatus::Duplicate => self.duplicate_share_index += 1,
+            ShareStatus::UnknownJob => self.unknown_share_index += 1,
+            ShareStatus::Stale => self.stale_share_index += 1,
+            ShareStatus::Valid => {}
+        }
+    }
+
+    pub fn local_invalid_add(&mut self) { self.local_invalid_index += 1; }
+
+    // 本地校验未通过的份额达到一定数量和比例，矿机可能超频或硬件故障
+    pub fn is_faulty(&self) -> bool {
+        self.local_invalid_index >= FAULTY_MIN_SHARES
+            && self.local_invalid_index * 100
+                >= self.share_index * FAULTY_PERCENT
+    }
+
+    // 按难度加权的工作量增加
+    pub fn work_add(&mut self, kind: WorkKind, diff: f64) {
+        self.work.add(kind, diff);
+    }
+
+    pub fn fee_pool_work_add(&mut self, pool: &str, diff: f64) {
+        *self.fee_work.entry(pool.to_string()).or_default() += diff;
+    }
+
     pub fn submit_hashrate<T>(&mut self, rpc: &T) -> bool
     where T: crate::protocol::rpc::eth::ClientRpc {
         self.hash = rpc.get_submit_hashrate();
@@ -0,0 +1,133 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::proxy::job::job_height;
+
+// 矿机提交份额的本地校验。
+// 记录下发给矿机的最近任务，提交时区分重复 nonce、未知任务、
+// 过期任务 (低于最近下发任务的高度) 及正常份额。
+
+// 份额处理方式
+pub const SHARE_POLICY_FORWARD: u32 = 0;
+pub const SHARE_POLICY_DROP: u32 = 1;
+pub const SHARE_POLICY_DISCONNECT: u32 = 2;
+
+const MAX_JOBS: usize = 64;
+const MAX_NONCES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareStatus {
+    Valid,
+    Duplicate,
+    UnknownJob,
+    Stale,
+}
+
+#[derive(Debug, Default)]
+pub struct ShareCheck {
+    // (任务 id, 高度, 任务)，高度 0 为矿池未提供高度
+    jobs: VecDeque<(String, u64, Vec<String>)>,
+    // 最近下发任务的高度
+    height: u64,
+    nonces: HashSet<String>,
+    nonce_order: VecDeque<String>,
+}
+
+impl ShareCheck {
+    pub fn new() -> Self { Self::default() }
+
+    // 记录下发的任务。job 为 [header, seed, target, height]
+    pub fn record(&mut self, job: &[String]) {
+        let id = match job.first() {
+            Some(id) => id.to_lowercase(),
+            None => return,
+        };
+        let height = job_height(job);
+        self.jobs.retain(|(j, _, _)| *j != id);
+        if self.jobs.len() >= MAX_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back((id, height, job.to_vec()));
+        if height != 0 {
+            self.height = height;
+        }
+    }
+
+    // 已下发的任务，用于本地校验份额
+    pub fn job(&self, job_id: &str) -> Option<&[String]> {
+        let job_id = job_id.to_lowercase();
+        self.jobs
+            .iter()
+            .find(|(id, _, _)| *id == job_id)
+            .map(|(_, _, job)| job.as_slice())
+    }
+
+    pub fn check(&mut self, job_id: &str, nonce: &str) -> ShareStatus {
+        let job_id = job_id.to_lowercase();
+        let height = match self.jobs.iter().find(|(id, _, _)| *id == job_id) {
+            Some((_, height, _)) => *height,
+            None => return ShareStatus::UnknownJob,
+        };
+
+        let key = format!("{}:{}", job_id, nonce.to_lowercase());
+        if self.nonces.contains(&key) {
+            return ShareStatus::Duplicate;
+        }
+        if self.nonce_order.len() >= MAX_NONCES {
+            if let Some(old) = self.nonce_order.pop_front() {
+                self.nonces.remove(&old);
+            }
+        }
+        self.nonces.insert(key.clone());
+        self.nonce_order.push_back(key);
+
+        if height != 0 && height < self.height {
+            ShareStatus::Stale
+        } else {
+            ShareStatus::Valid
+        }
+    }
+}
+
+#[test]
+fn test_share_check() {
+    let job = |id: &str, height: &str| -> Vec<String> {
+        vec![
+            id.to_string(),
+            "0x00".to_string(),
+            "0x00".to_string(),
+            height.to_string(),
+        ]
+    };
+
+    let mut check = ShareCheck::new();
+    assert_eq!(check.check("0x01", "0x1"), ShareStatus::UnknownJob);
+
+    check.record(&job("0x01", "0x64"));
+    assert_eq!(check.job("0X01").map(|j| j[3].as_str()), Some("0x64"));
+    assert_eq!(check.check("0x01", "0x1"), ShareStatus::Valid);
+    assert_eq!(check.check("0x01", "0x2"), ShareStatus::Valid);
+    assert_eq!(check.check("0X01", "0X1"), ShareStatus::Duplicate);
+
+    // 新区块的任务下发后，旧任务的份额过期
+    check.record(&job("0x02", "0x65"));
+    assert_eq!(check.check("0x01", "0x3"), ShareStatus::Stale);
+    assert_eq!(check.check("0x01", "0x3"), ShareStatus::Duplicate);
+    assert_eq!(check.check("0x02", "0x1"), ShareStatus::Valid);
+
+    // 不带高度的任务不判断过期
+    check.record(&job("0x03", ""));
+    assert_eq!(check.check("0x03", "0x1"), ShareStatus::Valid);
+
+    for i in 0..MAX_JOBS {
+        check.record(&job(&format!("0x{:x}", 0x100 + i), "0x66"));
+    }
+    assert_eq!(check.check("0x02", "0x9"), ShareStatus::UnknownJob);
+
+    // 只保留最近的 nonce
+    assert_eq!(check.check("0x100", "0x0"), ShareStatus::Valid);
+    for i in 1..=MAX_NONCES {
+        check.check("0x100", &format!("0x{:x}", i));
+    }
+    assert_eq!(check.nonces.len(), MAX_NONCES);
+    assert_eq!(check.check("0x100", "0x0"), ShareStatus::Valid);
+}
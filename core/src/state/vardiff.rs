@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant};
+
+use super::work::JobDifficulty;
+
+// 中转对矿机的可变难度。
+// 按矿机实际提交频率调整下发给矿机的难度，使每分钟份额数接近设定值。
+// 下发难度不高于矿池任务难度：矿池按自己的难度计算每个份额的工作量，
+// 高于矿池难度会让矿机损失收益。达到矿池难度的份额才转发给矿池，
+// 其余份额只在本地校验后计入有效算力。
+// 需要高于矿池难度时向矿池建议难度，矿池接受后按新的任务难度下发。
+
+// 调整间隔
+const RETARGET: Duration = Duration::from_secs(90);
+// 每次最多调整的倍数
+const MAX_STEP: f64 = 4.0;
+// 偏差在此范围内不调整，避免难度来回变化
+const TOLERANCE: f64 = 0.25;
+// 最低难度
+pub const MIN_DIFFICULTY: f64 = 0.01;
+
+#[derive(Debug)]
+pub struct VarDiff {
+    // 每分钟份额数
+    spm: f64,
+    // 按提交频率计算的难度，可能高于矿池难度。0 为尚未下发
+    diff: f64,
+    // 上次向矿池建议的难度
+    suggested: f64,
+    shares: u32,
+    since: Instant,
+    // 已下发任务的矿机难度
+    jobs: JobDifficulty,
+}
+
+impl VarDiff {
+    pub fn new(spm: u32) -> Self { Self::new_at(spm, Instant::now()) }
+
+    fn new_at(spm: u32, now: Instant) -> Self {
+        Self {
+            spm: spm as f64,
+            diff: 0.0,
+            suggested: 0.0,
+            shares: 0,
+            since: now,
+            jobs: JobDifficulty::new(),
+        }
+    }
+
+    // 下发任务时的矿机难度。pool_diff 为矿池任务难度
+    pub fn job_diff(&mut self, pool_diff: f64) -> f64 {
+        self.job_diff_at(pool_diff, Instant::now())
+    }
+
+    // 记录下发给矿机的任务
+    pub fn record(&mut self, job: &[String]) { self.jobs.record(job) }
+
+    // 份额所属任务下发时的矿机难度
+    pub fn get(&self, job_id: &str) -> f64 { self.jobs.get(job_id) }
+
+    // 矿机提交了一个达到矿机难度的份额
+    pub fn share(&mut self) { self.shares += 1; }
+
+    // 需要向矿池建议的难度。只在高于矿池难度且与上次建议不同时返回
+    pub fn suggest(&mut self, pool_diff: f64) -> Option<f64> {
+        if pool_diff <= 0.0
+            || self.diff <= pool_diff
+            || self.diff == self.suggested
+        {
+            return None;
+        }
+        self.suggested = self.diff;
+        Some(self.diff)
+    }
+
+    fn job_diff_at(&mut self, pool_diff: f64, now: Instant) -> f64 {
+        if pool_diff <= 0.0 {
+            return pool_diff;
+        }
+        if self.diff <= 0.0 {
+            // 首个任务按矿池难度下发，之后按提交频率调整
+            self.diff = pool_diff;
+            self.since = now;
+        }
+
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed >= RETARGET {
+            // 份额按实际下发的难度提交
+            let current = self.diff.min(pool_diff);
+            let minutes = elapsed.as_secs_f64() / 60.0;
+            let factor = (self.shares as f64 / minutes / self.spm)
+                .clamp(1.0 / MAX_STEP, MAX_STEP);
+            self.diff = if (factor - 1.0).abs() > TOLERANCE {
+                current * factor
+            } else {
+                current
+            };
+            self.shares = 0;
+            self.since = now;
+        }
+
+        self.diff = self.diff.max(MIN_DIFFICULTY.min(pool_diff));
+        self.diff.min(pool_diff)
+    }
+}
+
+#[test]
+fn test_vardiff() {
+    let start = Instant::now();
+    let mut vardiff = VarDiff::new_at(10, start);
+    assert_eq!(vardiff.job_diff_at(0.0, start), 0.0);
+    assert_eq!(vardiff.job_diff_at(8.0, start), 8.0);
+
+    // 90 秒内只有 3 个份额，难度降低但每次最多 4 倍
+    for _ in 0..3 {
+        vardiff.share();
+    }
+    let diff = vardiff.job_diff_at(8.0, start + RETARGET);
+    assert_eq!(diff, 2.0);
+
+    // 接近设定频率时不调整
+    let at = start + RETARGET * 2;
+    for _ in 0..14 {
+        vardiff.share();
+    }
+    assert_eq!(vardiff.job_diff_at(8.0, at), 2.0);
+
+    // 提交过快时提高难度，但不超过矿池难度，超出部分向矿池建议
+    for _ in 0..100 {
+        vardiff.share();
+    }
+    assert_eq!(vardiff.suggest(4.0), None);
+    assert_eq!(vardiff.job_diff_at(4.0, start + RETARGET * 3), 4.0);
+    assert_eq!(vardiff.suggest(4.0), Some(8.0));
+    assert_eq!(vardiff.suggest(4.0), None);
+    // 矿池接受建议后按新的任务难度下发
+    assert_eq!(vardiff.job_diff_at(8.0, start + RETARGET * 3), 8.0);
+    assert_eq!(vardiff.suggest(8.0), None);
+
+    // 长时间没有份额，逐步降到最低难度
+    let mut at = start + RETARGET * 3;
+    assert_eq!(vardiff.job_diff_at(4.0, at), 4.0);
+    for _ in 0..20 {
+        at += RETARGET;
+        vardiff.job_diff_at(4.0, at);
+    }
+    assert_eq!(vardiff.job_diff_at(4.0, at), MIN_DIFFICULTY);
+
+    let target = |t: &str| format!("0x{:0<64}", t);
+    vardiff.record(&["0x01".into(), "0x00".into(), target("00000000ffff")]);
+    assert!((vardiff.get("0x01") - 1.0).abs() < 1e-9);
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::eth_stratum::{
+    difficulty_to_hashes, target_to_difficulty,
+};
+
+// 按难度加权统计的工作量。
+// 不同任务的难度不同，按份额或任务个数统计抽水比例并不准确。
+// 每个份额按其所属任务的难度 (NiceHash 难度) 计入普通、抽水或开发者工作量。
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkKind {
+    Normal,
+    Fee,
+    Develop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Work {
+    pub normal: f64,
+    pub fee: f64,
+    pub develop: f64,
+}
+
+impl Work {
+    pub fn add(&mut self, kind: WorkKind, diff: f64) {
+        match kind {
+            WorkKind::Normal => self.normal += diff,
+            WorkKind::Fee => self.fee += diff,
+            WorkKind::Develop => self.develop += diff,
+        }
+    }
+
+    pub fn get(&self, kind: WorkKind) -> f64 {
+        match kind {
+            WorkKind::Normal => self.normal,
+            WorkKind::Fee => self.fee,
+            WorkKind::Develop => self.develop,
+        }
+    }
+
+    pub fn total(&self) -> f64 { self.normal + self.fee + self.develop }
+
+    pub fn merge(&mut self, other: &Work) {
+        self.normal += other.normal;
+        self.fee += other.fee;
+        self.develop += other.develop;
+    }
+
+    // 实际抽水比例 (百分比)
+    pub fn fee_rate(&self) -> f64 { percent(self.fee, self.total()) }
+
+    // 实际开发者抽水比例 (百分比)
+    pub fn develop_rate(&self) -> f64 { percent(self.develop, self.total()) }
+}
+
+fn percent(part: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        part / total * 100.0
+    } else {
+        0.0
+    }
+}
+
+// 滚动窗口内的工作量，按分钟分段累计
+const WINDOW_BUCKET: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct WorkWindow {
+    window: Duration,
+    buckets: VecDeque<(Instant, Work)>,
+}
+
+impl WorkWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, kind: WorkKind, diff: f64) {
+        self.add_at(Instant::now(), kind, diff)
+    }
+
+    fn add_at(&mut self, now: Instant, kind: WorkKind, diff: f64) {
+        self.expire(now);
+        match self.buckets.back_mut() {
+            Some((start, work)) if now - *start < WINDOW_BUCKET => {
+                work.add(kind, diff)
+            }
+            _ => {
+                let mut work = Work::default();
+                work.add(kind, diff);
+                self.buckets.push_back((now, work));
+            }
+        }
+    }
+
+    pub fn sum(&mut self) -> Work { self.sum_at(Instant::now()) }
+
+    fn sum_at(&mut self, now: Instant) -> Work {
+        self.expire(now);
+        let mut sum = Work::default();
+        for (_, work) in &self.buckets {
+            sum.merge(work);
+        }
+        sum
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if now - *start <= self.window {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
+
+// 有效算力：按已接受份额的难度换算的哈希数除以时间。
+// 分别统计 10 分钟、1 小时、24 小时，不依赖矿机上报的算力。
+// (窗口秒数, 分段秒数)
+const HASHRATE_WINDOWS: [(u64, u64); 3] =
+    [(600, 60), (3600, 300), (86_400, 3600)];
+
+// 有效算力 (H/s)
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectiveHashrate {
+    pub ten_minutes: f64,
+    pub hour: f64,
+    pub day: f64,
+}
+
+impl EffectiveHashrate {
+    pub fn merge(&mut self, other: &EffectiveHashrate) {
+        self.ten_minutes += other.ten_minutes;
+        self.hour += other.hour;
+        self.day += other.day;
+    }
+}
+
+#[derive(Debug)]
+struct RateWindow {
+    window: Duration,
+    bucket: Duration,
+    buckets: VecDeque<(Instant, f64)>,
+}
+
+impl RateWindow {
+    fn new(window: u64, bucket: u64) -> Self {
+        Self {
+            window: Duration::from_secs(window),
+            bucket: Duration::from_secs(bucket),
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn add_at(&mut self, now: Instant, hashes: f64) {
+        self.expire(now);
+        match self.buckets.back_mut() {
+            Some((start, sum)) if now - *start < self.bucket => *sum += hashes,
+            _ => self.buckets.push_back((now, hashes)),
+        }
+    }
+
+    // 窗口内的平均算力。start 为开始统计的时间，不足一个窗口时按实际时长计算
+    fn rate_at(&mut self, now: Instant, start: Instant) -> f64 {
+        self.expire(now);
+        let sum: f64 = self.buckets.iter().map(|(_, h)| h).sum();
+        // 最早一段可能部分超出窗口，按该段开始时间计算时长
+        let mut from =
+            now.checked_sub(self.window).unwrap_or(start).max(start);
+        if let Some((first, _)) = self.buckets.front() {
+            from = from.min(*first);
+        }
+        let elapsed = (now - from).as_secs_f64();
+        if elapsed > 0.0 {
+            sum / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    // 丢弃完全移出窗口的分段
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if now - *start <= self.window + self.bucket {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HashrateMeter {
+    start: Instant,
+    windows: Vec<RateWindow>,
+}
+
+impl Default for HashrateMeter {
+    fn default() -> Self { Self::new_at(Instant::now()) }
+}
+
+impl HashrateMeter {
+    pub fn new() -> Self { Self::default() }
+
+    fn new_at(start: Instant) -> Self {
+        Self {
+            start,
+            windows: HASHRATE_WINDOWS
+                .iter()
+                .map(|(window, bucket)| RateWindow::new(*window, *bucket))
+                .collect(),
+        }
+    }
+
+    // 记录一个已接受份额的难度
+    pub fn add(&mut self, diff: f64) { self.add_at(Instant::now(), diff) }
+
+    fn add_at(&mut self, now: Instant, diff: f64) {
+        let hashes = difficulty_to_hashes(diff);
+        for w in &mut self.windows {
+            w.add_at(now, hashes);
+        }
+    }
+
+    pub fn hashrate(&mut self) -> EffectiveHashrate {
+        self.hashrate_at(Instant::now())
+    }
+
+    fn hashrate_at(&mut self, now: Instant) -> EffectiveHashrate {
+        let start = self.start;
+        let mut rates =
+            self.windows.iter_mut().map(|w| w.rate_at(now, start));
+        EffectiveHashrate {
+            ten_minutes: rates.next().unwrap_or(0.0),
+            hour: rates.next().unwrap_or(0.0),
+            day: rates.next().unwrap_or(0.0),
+        }
+    }
+}
+
+// 最近下发给矿机的任务难度
+const MAX_JOB_DIFFICULTY: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct JobDifficulty {
+    jobs: VecDeque<(String, f64)>,
+}
+
+impl JobDifficulty {
+    pub fn new() -> Self { Self::default() }
+
+    // 记录下发的任务。job 为 [header, seed, target, ...]
+    pub fn record(&mut self, job: &[String]) {
+        if job.len() < 3 {
+            return;
+        }
+        let diff = target_to_difficulty(&job[2]);
+        self.jobs.retain(|(id, _)| *id != job[0]);
+        if self.jobs.len() >= MAX_JOB_DIFFICULTY {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back((job[0].clone(), diff));
+    }
+
+    // 份额所属任务的难度。未知任务按最近一个任务的难度计算
+    pub fn get(&self, job_id: &str) -> f64 {
+        self.jobs
+            .iter()
+            .rev()
+            .find(|(id, _)| id == job_id)
+            .or_else(|| self.jobs.back())
+            .map(|(_, diff)| *diff)
+            .unwrap_or(0.0)
+    }
+}
+
+#[test]
+fn test_work() {
+    let mut jobs = JobDifficulty::new();
+    assert_eq!(jobs.get("0x01"), 0.0);
+
+    let target = |t: &str| format!("0x{:0<64}", t);
+    jobs.record(&["0x01".into(), "0x00".into(), target("00000000ffff")]);
+    jobs.record(&["0x02".into(), "0x00".into(), target("000000007fff8")]);
+    assert!((jobs.get("0x01") - 1.0).abs() < 1e-9);
+    assert!((jobs.get("0x02") - 2.0).abs() < 1e-9);
+    assert!((jobs.get("0x03") - 2.0).abs() < 1e-9);
+
+    let mut work = Work::default();
+    assert_eq!(work.fee_rate(), 0.0);
+    work.normal += 4.0 * jobs.get("0x01");
+    work.fee += jobs.get("0x02");
+    work.develop += jobs.get("0x01");
+    work.merge(&Work {
+        normal: 1.0,
+        fee: 0.0,
+        develop: 0.0,
+    });
+    assert_eq!(work.total(), 8.0);
+    assert_eq!(work.fee_rate(), 25.0);
+    assert_eq!(work.develop_rate(), 12.5);
+
+    let start = Instant::now();
+    let mut window = WorkWindow::new(Duration::from_secs(600));
+    window.add_at(start, WorkKind::Normal, 3.0);
+    window.add_at(start + Duration::from_secs(30), WorkKind::Fee, 1.0);
+    window.add_at(start + Duration::from_secs(300), WorkKind::Normal, 4.0);
+    assert_eq!(window.buckets.len(), 2);
+    let sum = window.sum_at(start + Duration::from_secs(301));
+    assert_eq!(sum.get(WorkKind::Normal), 7.0);
+    assert_eq!(sum.fee_rate(), 12.5);
+    // 第一段移出窗口
+    let sum = window.sum_at(start + Duration::from_secs(700));
+    assert_eq!(sum.total(), 4.0);
+}
+
+#[test]
+fn test_hashrate_meter() {
+    let start = Instant::now();
+    let mut meter = HashrateMeter::new_at(start);
+    assert_eq!(meter.hashrate_at(start), EffectiveHashrate::default());
+
+    // 100 MH/s 的矿机以 4 G 难度每 40 秒接受一个份额
+    let diff = 4.0 * 1e9 / difficulty_to_hashes(1.0);
+    for i in 1..=180 {
+        meter.add_at(start + Duration::from_secs(i * 40), diff);
+    }
+
+    let now = start + Duration::from_secs(7200);
+    let rate = meter.hashrate_at(now);
+    let expect = 100_000_000.0;
+    assert!((rate.ten_minutes - expect).abs() / expect < 0.1, "{:?}", rate);
+    assert!((rate.hour - expect).abs() / expect < 0.05, "{:?}", rate);
+    // 不足 24 小时按实际时长计算
+    assert!((rate.day - expect).abs() / expect < 0.01, "{:?}", rate);
+
+    // 停止提交后 10 分钟算力归零，24 小时算力逐渐下降
+    let later = now + Duration::from_secs(1800);
+    let rate = meter.hashrate_at(later);
+    assert_eq!(rate.ten_minutes, 0.0);
+    assert!(rate.day < expect && rate.day > expect / 2.0);
+}
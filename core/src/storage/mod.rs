@@ -0,0 +1,504 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::{state::Worker, web::AppState};
+
+// 矿机历史数据。
+// 主控每 SNAPSHOT_INTERVAL 秒把各中转的矿机状态写入 SQLite，重启后不丢失。
+// 份额按两次快照的差值记录 (矿机重新登录时计数清零)。主控重启后首次看到的
+// 中转只记下当前计数，不写入。快照每小时汇总一次，
+// 24 小时内的数据按快照查询，7 天及 30 天按小时汇总查询。
+
+pub const HISTORY_DB: &str = "history.db";
+
+const SNAPSHOT_INTERVAL: i64 = 300;
+const HOUR: i64 = 3600;
+// 快照保留 2 天，小时汇总及上下线记录保留 35 天
+const SNAPSHOT_RETENTION: i64 = 2 * 86_400;
+const HOURLY_RETENTION: i64 = 35 * 86_400;
+// 最多返回的上下线记录
+const MAX_EVENTS: i64 = 500;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS worker_snapshot (
+    time INTEGER NOT NULL,
+    proxy TEXT NOT NULL,
+    worker TEXT NOT NULL,
+    wallet TEXT NOT NULL,
+    worker_name TEXT NOT NULL,
+    hash REAL NOT NULL,
+    online REAL NOT NULL,
+    shares INTEGER NOT NULL,
+    accepts INTEGER NOT NULL,
+    rejects INTEGER NOT NULL,
+    fee_shares INTEGER NOT NULL,
+    develop_shares INTEGER NOT NULL,
+    PRIMARY KEY (proxy, worker, time)
+);
+CREATE TABLE IF NOT EXISTS worker_hourly (
+    time INTEGER NOT NULL,
+    proxy TEXT NOT NULL,
+    worker TEXT NOT NULL,
+    wallet TEXT NOT NULL,
+    worker_name TEXT NOT NULL,
+    hash REAL NOT NULL,
+    online REAL NOT NULL,
+    shares INTEGER NOT NULL,
+    accepts INTEGER NOT NULL,
+    rejects INTEGER NOT NULL,
+    fee_shares INTEGER NOT NULL,
+    develop_shares INTEGER NOT NULL,
+    PRIMARY KEY (proxy, worker, time)
+);
+CREATE TABLE IF NOT EXISTS worker_event (
+    time INTEGER NOT NULL,
+    proxy TEXT NOT NULL,
+    worker TEXT NOT NULL,
+    wallet TEXT NOT NULL,
+    worker_name TEXT NOT NULL,
+    online INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS worker_event_time ON worker_event (proxy, time);
+";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Counters {
+    shares: u64,
+    accepts: u64,
+    rejects: u64,
+    fee_shares: u64,
+    develop_shares: u64,
+}
+
+impl Counters {
+    fn of(worker: &Worker) -> Self {
+        Self {
+            shares: worker.share_index,
+            accepts: worker.accept_index,
+            rejects: worker.invalid_index,
+            fee_shares: worker.fee_share_index,
+            develop_shares: worker.develop_share_index,
+        }
+    }
+
+    // 与上次快照的差值。计数变小说明已清零，按当前值计
+    fn since(&self, last: &Counters) -> Self {
+        let delta = |cur: u64, last: u64| {
+            if cur >= last {
+                cur - last
+            } else {
+                cur
+            }
+        };
+        Self {
+            shares: delta(self.shares, last.shares),
+            accepts: delta(self.accepts, last.accepts),
+            rejects: delta(self.rejects, last.rejects),
+            fee_shares: delta(self.fee_shares, last.fee_shares),
+            develop_shares: delta(self.develop_shares, last.develop_shares),
+        }
+    }
+
+    fn is_zero(&self) -> bool { *self == Self::default() }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryRange {
+    Day,
+    Week,
+    Month,
+}
+
+impl HistoryRange {
+    pub fn parse(range: &str) -> Option<Self> {
+        match range {
+            "" | "24h" => Some(Self::Day),
+            "7d" => Some(Self::Week),
+            "30d" => Some(Self::Month),
+            _ => None,
+        }
+    }
+
+    // (时长, 数据表, 每个点的秒数)
+    fn query(&self) -> (i64, &'static str, i64) {
+        match self {
+            Self::Day => (86_400, "worker_snapshot", SNAPSHOT_INTERVAL),
+            Self::Week => (7 * 86_400, "worker_hourly", HOUR),
+            Self::Month => (30 * 86_400, "worker_hourly", 4 * HOUR),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    pub time: i64,
+    // 平均算力及平均在线矿机数
+    pub hash: f64,
+    pub online: f64,
+    pub shares: u64,
+    pub accepts: u64,
+    pub rejects: u64,
+    pub fee_shares: u64,
+    pub develop_shares: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WorkerEvent {
+    pub time: i64,
+    pub wallet: String,
+    pub worker_name: String,
+    pub online: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    pub points: Vec<HistoryPoint>,
+    pub events: Vec<WorkerEvent>,
+}
+
+pub struct Storage {
+    conn: Mutex<Connection>,
+    // 上次快照时各矿机的计数及在线状态
+    last: Mutex<HashMap<(String, String), (Counters, bool)>>,
+    // 启动后已记下计数的中转
+    seen: Mutex<HashSet<String>>,
+}
+
+impl Storage {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            last: Mutex::new(HashMap::new()),
+            seen: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // 写入快照、汇总并清理过期数据。workers 为 (中转名称, 矿机)
+    pub fn tick(&self, workers: &[(String, Worker)]) -> Result<()> {
+        let now = chrono::Local::now().timestamp();
+        self.snapshot(workers, now)?;
+        self.downsample(now)
+    }
+
+    fn snapshot(&self, workers: &[(String, Worker)], now: i64) -> Result<()> {
+        let time = now / SNAPSHOT_INTERVAL * SNAPSHOT_INTERVAL;
+        let mut last = self.last.lock().unwrap();
+        let mut seen = self.seen.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut first = HashSet::new();
+        for (proxy, w) in workers {
+            let key = (proxy.clone(), w.worker.clone());
+            let counters = Counters::of(w);
+            // 重启前已登录的矿机，计数及上线已在重启前记录过
+            if !seen.contains(proxy) {
+                first.insert(proxy.clone());
+                last.insert(key, (counters, w.online));
+                continue;
+            }
+            let (delta, was_online) = match last.get(&key) {
+                Some((prev, online)) => (counters.since(prev), *online),
+                None => (counters, false),
+            };
+            last.insert(key, (counters, w.online));
+
+            if w.online != was_online {
+                tx.execute(
+                    "INSERT INTO worker_event
+                     (time, proxy, worker, wallet, worker_name, online)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        now,
+                        proxy,
+                        w.worker,
+                        w.worker_wallet,
+                        w.worker_name,
+                        w.online
+                    ],
+                )?;
+            }
+            if !w.online && delta.is_zero() {
+                continue;
+            }
+
+            let hash = if w.online { w.hash as f64 } else { 0.0 };
+            tx.execute(
+                "INSERT INTO worker_snapshot
+                 (time, proxy, worker, wallet, worker_name, hash, online,
+                  shares, accepts, rejects, fee_shares, develop_shares)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                 ON CONFLICT (proxy, worker, time) DO UPDATE SET
+                   hash = excluded.hash,
+                   online = excluded.online,
+                   shares = shares + excluded.shares,
+                   accepts = accepts + excluded.accepts,
+                   rejects = rejects + excluded.rejects,
+                   fee_shares = fee_shares + excluded.fee_shares,
+                   develop_shares = develop_shares + excluded.develop_shares",
+                params![
+                    time,
+                    proxy,
+                    w.worker,
+                    w.worker_wallet,
+                    w.worker_name,
+                    hash,
+                    w.online as i64 as f64,
+                    delta.shares as i64,
+                    delta.accepts as i64,
+                    delta.rejects as i64,
+                    delta.fee_shares as i64,
+                    delta.develop_shares as i64,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        seen.extend(first);
+        Ok(())
+    }
+
+    // 重新汇总当前及上一小时的快照，删除过期数据
+    fn downsample(&self, now: i64) -> Result<()> {
+        let from = now / HOUR * HOUR - HOUR;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO worker_hourly
+             (time, proxy, worker, wallet, worker_name, hash, online,
+              shares, accepts, rejects, fee_shares, develop_shares)
+             SELECT time / ?2 * ?2 AS hour, proxy, worker, MAX(wallet),
+               MAX(worker_name), SUM(hash) / ?3, SUM(online) / ?3,
+               SUM(shares), SUM(accepts), SUM(rejects), SUM(fee_shares),
+               SUM(develop_shares)
+             FROM worker_snapshot WHERE time >= ?1
+             GROUP BY hour, proxy, worker
+             ON CONFLICT (proxy, worker, time) DO UPDATE SET
+               hash = excluded.hash,
+               online = excluded.online,
+               shares = excluded.shares,
+               accepts = excluded.accepts,
+               rejects = excluded.rejects,
+               fee_shares = excluded.fee_shares,
+               develop_shares = excluded.develop_shares",
+            params![from, HOUR, (HOUR / SNAPSHOT_INTERVAL) as f64],
+        )?;
+        tx.execute(
+            "DELETE FROM worker_snapshot WHERE time < ?1",
+            params![now - SNAPSHOT_RETENTION],
+        )?;
+        tx.execute(
+            "DELETE FROM worker_hourly WHERE time < ?1",
+            params![now - HOURLY_RETENTION],
+        )?;
+        tx.execute(
+            "DELETE FROM worker_event WHERE time < ?1",
+            params![now - HOURLY_RETENTION],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // 查询中转的历史数据。wallet、worker_name 为空时不筛选
+    pub fn history(
+        &self, proxy: &str, wallet: &str, worker_name: &str,
+        range: HistoryRange,
+    ) -> Result<History> {
+        let now = chrono::Local::now().timestamp();
+        self.history_at(proxy, wallet, worker_name, range, now)
+    }
+
+    fn history_at(
+        &self, proxy: &str, wallet: &str, worker_name: &str,
+        range: HistoryRange, now: i64,
+    ) -> Result<History> {
+        let (span, table, step) = range.query();
+        let slot = if table == "worker_hourly" {
+            HOUR
+        } else {
+            SNAPSHOT_INTERVAL
+        };
+        // 每个点包含 step / slot 个存储的时间段，算力按时间段平均
+        let slots = (step / slot) as f64;
+        let from = now - span;
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT time / ?1 * ?1 AS t, SUM(hash) / ?2, SUM(online) / ?2,
+               SUM(shares), SUM(accepts), SUM(rejects), SUM(fee_shares),
+               SUM(develop_shares)
+             FROM {} WHERE proxy = ?3 AND time >= ?4
+               AND (?5 = '' OR wallet = ?5 COLLATE NOCASE)
+               AND (?6 = '' OR worker_name = ?6)
+             GROUP BY t ORDER BY t",
+            table
+        ))?;
+        let points = stmt
+            .query_map(
+                params![step, slots, proxy, from, wallet, worker_name],
+                |row| {
+                    Ok(HistoryPoint {
+                        time: row.get(0)?,
+                        hash: row.get(1)?,
+                        online: row.get(2)?,
+                        shares: row.get::<_, i64>(3)? as u64,
+                        accepts: row.get::<_, i64>(4)? as u64,
+                        rejects: row.get::<_, i64>(5)? as u64,
+                        fee_shares: row.get::<_, i64>(6)? as u64,
+                        develop_shares: row.get::<_, i64>(7)? as u64,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT time, wallet, worker_name, online FROM worker_event
+             WHERE proxy = ?1 AND time >= ?2
+               AND (?3 = '' OR wallet = ?3 COLLATE NOCASE)
+               AND (?4 = '' OR worker_name = ?4)
+             ORDER BY time DESC LIMIT ?5",
+        )?;
+        let events = stmt
+            .query_map(
+                params![proxy, from, wallet, worker_name, MAX_EVENTS],
+                |row| {
+                    Ok(WorkerEvent {
+                        time: row.get(0)?,
+                        wallet: row.get(1)?,
+                        worker_name: row.get(2)?,
+                        online: row.get(3)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(History { points, events })
+    }
+}
+
+// 定时写入快照
+pub async fn run(storage: Arc<Storage>, app: AppState) -> Result<()> {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(SNAPSHOT_INTERVAL as u64));
+    loop {
+        interval.tick().await;
+        let workers: Vec<(String, Worker)> = app
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(name, server)| {
+                server.workers.iter().map(move |w| (name.clone(), w.clone()))
+            })
+            .collect();
+
+        let storage = storage.clone();
+        match tokio::task::spawn_blocking(move || storage.tick(&workers)).await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("写入矿机历史失败 {}", e),
+            Err(e) => tracing::error!("写入矿机历史失败 {}", e),
+        }
+    }
+}
+
+#[test]
+fn test_storage_history() {
+    let storage = Storage::open(":memory:").unwrap();
+    let mut w =
+        Worker::new("0xabc.w1".into(), "w1".into(), "0xABC".into(), true);
+    w.hash = 100;
+    w.share_index = 10;
+    w.accept_index = 9;
+    w.fee_share_index = 1;
+    let proxy = "p1".to_string();
+
+    let start = 1_000 * HOUR;
+    // 首次快照只记下计数
+    w.online = false;
+    w.share_index = 0;
+    w.accept_index = 0;
+    w.fee_share_index = 0;
+    storage
+        .snapshot(&[(proxy.clone(), w.clone())], start - SNAPSHOT_INTERVAL)
+        .unwrap();
+    w.online = true;
+    w.share_index = 10;
+    w.accept_index = 9;
+    w.fee_share_index = 1;
+    storage.snapshot(&[(proxy.clone(), w.clone())], start).unwrap();
+    // 重新登录后计数清零
+    w.share_index = 4;
+    w.accept_index = 4;
+    storage
+        .snapshot(&[(proxy.clone(), w.clone())], start + SNAPSHOT_INTERVAL)
+        .unwrap();
+    w.online = false;
+    storage
+        .snapshot(&[(proxy.clone(), w.clone())], start + 2 * SNAPSHOT_INTERVAL)
+        .unwrap();
+    storage.downsample(start + 3 * SNAPSHOT_INTERVAL).unwrap();
+
+    let now = start + HOUR;
+    let day = storage
+        .history_at("p1", "0xabc", "", HistoryRange::Day, now)
+        .unwrap();
+    assert_eq!(day.points.len(), 2);
+    assert_eq!(day.points[0].shares, 10);
+    assert_eq!(day.points[1].shares, 4);
+    assert_eq!(day.points[0].fee_shares, 1);
+    assert_eq!(day.points[1].fee_shares, 0);
+    assert_eq!(day.points[0].hash, 100.0);
+    assert_eq!(day.events.len(), 2);
+    assert!(!day.events[0].online);
+
+    let week = storage
+        .history_at("p1", "", "w1", HistoryRange::Week, now)
+        .unwrap();
+    assert_eq!(week.points.len(), 1);
+    assert_eq!(week.points[0].shares, 14);
+    assert_eq!(week.points[0].accepts, 13);
+    // 一小时 12 个快照，在线两个
+    assert!((week.points[0].hash - 200.0 / 12.0).abs() < 1e-9);
+
+    let other = storage
+        .history_at("p2", "", "", HistoryRange::Month, now)
+        .unwrap();
+    assert!(other.points.is_empty());
+
+    // 过期数据被清理
+    storage.downsample(start + HOURLY_RETENTION + HOUR).unwrap();
+    let week = storage
+        .history_at("p1", "", "", HistoryRange::Week, now)
+        .unwrap();
+    assert!(week.points.is_empty());
+
+    // 主控重启，已在线矿机不重复计数，新登录的矿机照常记录
+    let storage = Storage::open(":memory:").unwrap();
+    let mut w2 =
+        Worker::new("0xabc.w2".into(), "w2".into(), "0xABC".into(), true);
+    w2.share_index = 3;
+    storage.snapshot(&[(proxy.clone(), w.clone())], start).unwrap();
+    storage
+        .snapshot(
+            &[(proxy.clone(), w), (proxy, w2)],
+            start + SNAPSHOT_INTERVAL,
+        )
+        .unwrap();
+    let day = storage
+        .history_at("p1", "", "", HistoryRange::Day, now)
+        .unwrap();
+    assert_eq!(day.points.len(), 1);
+    assert_eq!(day.points[0].shares, 3);
+    assert_eq!(day.events.len(), 1);
+    assert_eq!(day.events[0].worker_name, "w2");
+
+    assert_eq!(HistoryRange::parse("7d"), Some(HistoryRange::Week));
+    assert_eq!(HistoryRange::parse("1y"), None);
+}
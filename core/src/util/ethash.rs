@@ -0,0 +1,479 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use tiny_keccak::{Hasher, Keccak};
+
+// Ethash 轻量计算。只保存 cache，数据集元素按需计算。
+// 用于 NiceHash 协议矿机提交时补全 mixhash 以及本地校验份额。
+pub const EPOCH_LENGTH: u64 = 30000;
+const CACHE_BYTES_INIT: usize = 1 << 24;
+const CACHE_BYTES_GROWTH: usize = 1 << 17;
+const DATASET_BYTES_INIT: usize = 1 << 30;
+const DATASET_BYTES_GROWTH: usize = 1 << 23;
+const HASH_BYTES: usize = 64;
+const HASH_WORDS: usize = 16;
+const MIX_BYTES: usize = 128;
+const MIX_WORDS: usize = 32;
+const DATASET_PARENTS: u32 = 256;
+const CACHE_ROUNDS: usize = 3;
+const ACCESSES: u32 = 64;
+const FNV_PRIME: u32 = 0x01000193;
+
+// ETC 自 ECIP-1099 (区块 11700000) 起纪元长度改为 60000，
+// 种子仍按 30000 块的纪元计算
+pub const ECIP1099_EPOCH_LENGTH: u64 = 60000;
+const ECIP1099_BLOCK: u64 = 11_700_000;
+
+// 通过种子反查纪元时最多尝试的次数
+const MAX_EPOCH: u64 = 2048;
+
+// (大小纪元, 种子纪元) -> cache。生成中的 cache 也占一项，同一纪元只生成一次
+type CacheMap = HashMap<(u64, u64), Arc<OnceCell<Arc<LightCache>>>>;
+
+lazy_static! {
+    static ref CACHES: Mutex<CacheMap> = Mutex::new(HashMap::new());
+    static ref SEEDS: Mutex<HashMap<[u8; 32], u64>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn keccak_256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let mut k = Keccak::v256();
+    k.update(data);
+    k.finalize(&mut out);
+    out
+}
+
+pub fn keccak_512(data: &[u8]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    let mut k = Keccak::v512();
+    k.update(data);
+    k.finalize(&mut out);
+    out
+}
+
+#[inline(always)]
+fn fnv(a: u32, b: u32) -> u32 { a.wrapping_mul(FNV_PRIME) ^ b }
+
+fn is_prime(n: usize) -> bool {
+    if n < 2 {
+        return false;
+    }
+    let mut i = 2;
+    while i * i <= n {
+        if n % i == 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub fn cache_size(epoch: u64) -> usize {
+    let mut sz =
+        CACHE_BYTES_INIT + CACHE_BYTES_GROWTH * epoch as usize - HASH_BYTES;
+    while !is_prime(sz / HASH_BYTES) {
+        sz -= 2 * HASH_BYTES;
+    }
+    sz
+}
+
+pub fn full_size(epoch: u64) -> usize {
+    let mut sz =
+        DATASET_BYTES_INIT + DATASET_BYTES_GROWTH * epoch as usize - MIX_BYTES;
+    while !is_prime(sz / MIX_BYTES) {
+        sz -= 2 * MIX_BYTES;
+    }
+    sz
+}
+
+pub fn seed_hash(epoch: u64) -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    for _ in 0..epoch {
+        seed = keccak_256(&seed);
+    }
+    seed
+}
+
+// 种子对应 keccak256 迭代的次数
+pub fn seed_to_epoch(seed: &[u8]) -> Option<u64> {
+    if seed.len() != 32 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(seed);
+    if let Some(epoch) = SEEDS.lock().unwrap().get(&key) {
+        return Some(*epoch);
+    }
+
+    let mut s = [0u8; 32];
+    for epoch in 0..MAX_EPOCH {
+        if s == key {
+            SEEDS.lock().unwrap().insert(key, epoch);
+            return Some(epoch);
+        }
+        s = keccak_256(&s);
+    }
+    None
+}
+
+// 种子纪元 (每 30000 块) 对应计算 cache 及数据集大小的纪元
+pub fn size_epoch(coin: &str, seed_epoch: u64) -> u64 {
+    let block = seed_epoch * EPOCH_LENGTH;
+    if coin == "ETC" && block >= ECIP1099_BLOCK {
+        block / ECIP1099_EPOCH_LENGTH
+    } else {
+        seed_epoch
+    }
+}
+
+fn to_words(bytes: &[u8], out: &mut [u32]) {
+    for (i, w) in out.iter_mut().enumerate() {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(&bytes[i * 4..i * 4 + 4]);
+        *w = u32::from_le_bytes(b);
+    }
+}
+
+fn to_bytes(words: &[u32], out: &mut [u8]) {
+    for (i, w) in words.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&w.to_le_bytes());
+    }
+}
+
+#[derive(Debug)]
+pub struct LightCache {
+    pub epoch: u64,
+    full_size: usize,
+    cache: Vec<u32>,
+}
+
+impl LightCache {
+    pub fn new(coin: &str, seed_epoch: u64) -> Self {
+        let epoch = size_epoch(coin, seed_epoch);
+        Self::with_size(
+            epoch,
+            cache_size(epoch),
+            full_size(epoch),
+            &seed_hash(seed_epoch),
+        )
+    }
+
+    pub fn with_size(
+        epoch: u64, cache_size: usize, full_size: usize, seed: &[u8],
+    ) -> Self {
+        let rows = cache_size / HASH_BYTES;
+        let mut bytes = vec![0u8; rows * HASH_BYTES];
+
+        bytes[..HASH_BYTES].copy_from_slice(&keccak_512(seed));
+        for i in 1..rows {
+            let prev = keccak_512(&bytes[(i - 1) * HASH_BYTES..i * HASH_BYTES]);
+            bytes[i * HASH_BYTES..(i + 1) * HASH_BYTES].copy_from_slice(&prev);
+        }
+
+        let mut temp = [0u8; HASH_BYTES];
+        for _ in 0..CACHE_ROUNDS {
+            for i in 0..rows {
+                let src = ((i + rows - 1) % rows) * HASH_BYTES;
+                let mut v = [0u8; 4];
+                v.copy_from_slice(&bytes[i * HASH_BYTES..i * HASH_BYTES + 4]);
+                let dst = (u32::from_le_bytes(v) as usize % rows) * HASH_BYTES;
+                for j in 0..HASH_BYTES {
+                    temp[j] = bytes[src + j] ^ bytes[dst + j];
+                }
+                bytes[i * HASH_BYTES..(i + 1) * HASH_BYTES]
+                    .copy_from_slice(&keccak_512(&temp));
+            }
+        }
+
+        let mut cache = vec![0u32; rows * HASH_WORDS];
+        to_words(&bytes, &mut cache);
+
+        Self {
+            epoch,
+            full_size,
+            cache,
+        }
+    }
+
+    fn dataset_item(&self, index: u32) -> [u32; HASH_WORDS] {
+        let rows = (self.cache.len() / HASH_WORDS) as u32;
+        let mut mix = [0u32; HASH_WORDS];
+        let start = (index % rows) as usize * HASH_WORDS;
+        mix.copy_from_slice(&self.cache[start..start + HASH_WORDS]);
+        mix[0] ^= index;
+
+        let mut bytes = [0u8; HASH_BYTES];
+        to_bytes(&mix, &mut bytes);
+        to_words(&keccak_512(&bytes), &mut mix);
+
+        for j in 0..DATASET_PARENTS {
+            let parent = fnv(index ^ j, mix[j as usize % HASH_WORDS]) % rows;
+            let start = parent as usize * HASH_WORDS;
+            let row = &self.cache[start..start + HASH_WORDS];
+            for (m, p) in mix.iter_mut().zip(row) {
+                *m = fnv(*m, *p);
+            }
+        }
+
+        to_bytes(&mix, &mut bytes);
+        to_words(&keccak_512(&bytes), &mut mix);
+        mix
+    }
+
+    // 返回 (mixhash, result)
+    pub fn hashimoto(&self, header: &[u8], nonce: u64) -> ([u8; 32], [u8; 32]) {
+        let rows = (self.full_size / MIX_BYTES) as u32;
+
+        let mut seed_data = [0u8; 40];
+        seed_data[..32].copy_from_slice(&header[..32]);
+        seed_data[32..].copy_from_slice(&nonce.to_le_bytes());
+        let seed = keccak_512(&seed_data);
+        let mut seed_words = [0u32; HASH_WORDS];
+        to_words(&seed, &mut seed_words);
+
+        let mut mix = [0u32; MIX_WORDS];
+        for (i, m) in mix.iter_mut().enumerate() {
+            *m = seed_words[i % HASH_WORDS];
+        }
+
+        for i in 0..ACCESSES {
+            let parent =
+                fnv(i ^ seed_words[0], mix[i as usize % MIX_WORDS]) % rows;
+            for j in 0..(MIX_BYTES / HASH_BYTES) {
+                let item = self.dataset_item(2 * parent + j as u32);
+                let mix = &mut mix[j * HASH_WORDS..(j + 1) * HASH_WORDS];
+                for (m, d) in mix.iter_mut().zip(item.iter()) {
+                    *m = fnv(*m, *d);
+                }
+            }
+        }
+
+        let mut cmix = [0u32; MIX_WORDS / 4];
+        for (i, c) in cmix.iter_mut().enumerate() {
+            let p = i * 4;
+            *c = fnv(fnv(fnv(mix[p], mix[p + 1]), mix[p + 2]), mix[p + 3]);
+        }
+
+        let mut digest = [0u8; 32];
+        to_bytes(&cmix, &mut digest);
+
+        let mut result_data = [0u8; HASH_BYTES + 32];
+        result_data[..HASH_BYTES].copy_from_slice(&seed);
+        result_data[HASH_BYTES..].copy_from_slice(&digest);
+
+        (digest, keccak_256(&result_data))
+    }
+}
+
+// 取得种子纪元对应的 cache。只保留最近两个纪元。会阻塞，需在
+// spawn_blocking 中调用
+pub fn get_cache(coin: &str, seed_epoch: u64) -> Arc<LightCache> {
+    let key = (size_epoch(coin, seed_epoch), seed_epoch);
+    cached(&CACHES, key, || {
+        tracing::info!("生成 Ethash 纪元 {} 缓存", key.0);
+        LightCache::new(coin, seed_epoch)
+    })
+}
+
+// 纪元切换时大量份额同时需要新 cache，只由第一个调用生成，其余等待
+fn cached<F>(
+    caches: &Mutex<CacheMap>, key: (u64, u64), build: F,
+) -> Arc<LightCache>
+where F: FnOnce() -> LightCache {
+    let cell = {
+        let mut caches = caches.lock().unwrap();
+        if !caches.contains_key(&key) && caches.len() >= 2 {
+            if let Some(old) = caches.keys().min().copied() {
+                caches.remove(&old);
+            }
+        }
+        caches.entry(key).or_default().clone()
+    };
+    cell.get_or_init(|| Arc::new(build())).clone()
+}
+
+pub fn hex_to_bytes(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim_start_matches("0x");
+    match hex::decode(s) {
+        Ok(b) => Ok(b),
+        Err(e) => bail!("十六进制解析失败 {} {}", s, e),
+    }
+}
+
+fn parse_nonce(nonce: &str) -> Result<u64> {
+    match u64::from_str_radix(nonce.trim_start_matches("0x"), 16) {
+        Ok(n) => Ok(n),
+        Err(e) => bail!("nonce 解析失败 {} {}", nonce, e),
+    }
+}
+
+// 计算份额的 mixhash 与最终哈希
+pub async fn hashimoto(
+    coin: &str, seed: &str, header: &str, nonce: &str,
+) -> Result<([u8; 32], [u8; 32])> {
+    let seed = hex_to_bytes(seed)?;
+    let header = hex_to_bytes(header)?;
+    if header.len() != 32 {
+        bail!("区块头长度不正确 {}", header.len());
+    }
+    let nonce = parse_nonce(nonce)?;
+
+    let epoch = match seed_to_epoch(&seed) {
+        Some(e) => e,
+        None => bail!("无法识别的种子 {}", hex::encode(&seed)),
+    };
+
+    let coin = coin.to_string();
+    Ok(tokio::task::spawn_blocking(move || {
+        get_cache(&coin, epoch).hashimoto(&header, nonce)
+    })
+    .await?)
+}
+
+// 根据种子、区块头及 nonce 计算 mixhash 与最终哈希。均为带 0x 的十六进制
+pub async fn compute(
+    coin: &str, seed: &str, header: &str, nonce: &str,
+) -> Result<(String, String)> {
+    let (mix, result) = hashimoto(coin, seed, header, nonce).await?;
+    Ok((
+        format!("0x{}", hex::encode(mix)),
+        format!("0x{}", hex::encode(result)),
+    ))
+}
+
+// 最终哈希 (大端) 不大于任务边界值
+pub fn meets_target(hash: &[u8], target: &str) -> Result<bool> {
+    let target = hex_to_bytes(target)?;
+    if hash.len() != 32 || target.len() > 32 {
+        bail!("边界值长度不正确 {}", target.len());
+    }
+    let mut boundary = [0u8; 32];
+    boundary[32 - target.len()..].copy_from_slice(&target);
+    Ok(hash <= &boundary[..])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verify {
+    Valid,
+    // 提交的 mixhash 与计算结果不一致
+    BadMix,
+    // 未达到任务难度
+    LowDifficulty,
+}
+
+// 本地校验矿机提交的份额。job 为 [header, seed, target, ...]
+pub async fn verify(
+    coin: &str, job: &[String], nonce: &str, mix: &str,
+) -> Result<Verify> {
+    if job.len() < 3 {
+        bail!("任务格式不正确 {:?}", job);
+    }
+    let (digest, result) = hashimoto(coin, &job[1], &job[0], nonce).await?;
+    if hex_to_bytes(mix)? != digest {
+        return Ok(Verify::BadMix);
+    }
+    if !meets_target(&result, &job[2])? {
+        return Ok(Verify::LowDifficulty);
+    }
+    Ok(Verify::Valid)
+}
+
+#[test]
+fn test_hashimoto() {
+    // go-ethereum 的小尺寸 cache 测试向量
+    let cache = LightCache::with_size(0, 1024, 32 * 1024, &[0u8; 32]);
+    let header = hex_to_bytes(
+        "c9149cc0386e689d789a1c2f3d5d169a61a6218ed30e74414dc736e442ef3d1f",
+    )
+    .unwrap();
+    let (mix, result) = cache.hashimoto(&header, 0);
+    assert_eq!(
+        hex::encode(mix),
+        "e4073cffaef931d37117cefd9afd27ea0f1cad6a981dd2605c4a1ac97c519800"
+    );
+    assert_eq!(
+        hex::encode(result),
+        "d3539235ee2e6f8db665c0a72169f55b7f6c605712330b778ec3944f0eb5a557"
+    );
+
+    assert!(!meets_target(&result, "0xe0").unwrap());
+    assert!(meets_target(&result, &format!("0x{}", "ff".repeat(33))).is_err());
+    assert!(meets_target(&result, &format!("0x{}", "ff".repeat(32))).unwrap());
+    assert!(!meets_target(&result, &format!("0x{}", "d3".repeat(31))).unwrap());
+}
+
+#[test]
+fn test_cache_once() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let caches = Mutex::new(HashMap::new());
+    let built = AtomicUsize::new(0);
+    let build = || {
+        built.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        LightCache::with_size(0, 1024, 32 * 1024, &[0u8; 32])
+    };
+
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| cached(&caches, (0, 0), build));
+        }
+    });
+    assert_eq!(built.load(Ordering::SeqCst), 1);
+
+    // 只保留最近两个纪元
+    cached(&caches, (1, 1), build);
+    cached(&caches, (2, 2), build);
+    assert_eq!(built.load(Ordering::SeqCst), 3);
+    let keys: Vec<_> = caches.lock().unwrap().keys().copied().collect();
+    assert_eq!(keys.len(), 2);
+    assert!(!keys.contains(&(0, 0)));
+}
+
+#[test]
+fn test_ecip1099_epoch() {
+    assert_eq!(size_epoch("ETH", 390), 390);
+    assert_eq!(size_epoch("ETC", 389), 389);
+    // 区块 11700000 起 ETC 纪元减半
+    assert_eq!(size_epoch("ETC", 390), 195);
+    assert_eq!(size_epoch("ETC", 392), 196);
+    assert_eq!(size_epoch("ETC", 393), 196);
+}
+
+#[tokio::test]
+async fn test_verify_block_1() {
+    // 以太坊主网区块 1
+    let job = vec![
+        "0x85913a3057ea8bec78cd916871ca73802e77724e014dda65add3405d02240eb7"
+            .to_string(),
+        format!("0x{}", hex::encode(seed_hash(0))),
+        format!("0x{}", "0".repeat(8) + &"f".repeat(56)),
+    ];
+    let nonce = "0x539bd4979fef1ec4";
+    let mix =
+        "0x969b900de27b6ac6a67742365dd65f55a0526c41fd18e1b16f1a1215c2e66f59";
+
+    let (computed, _) =
+        compute("ETH", &job[1], &job[0], nonce).await.unwrap();
+    assert_eq!(computed, mix);
+    assert_eq!(verify("ETH", &job, nonce, mix).await.unwrap(), Verify::Valid);
+    // 区块 1 在 ETC 上与 ETH 相同
+    assert_eq!(verify("ETC", &job, nonce, mix).await.unwrap(), Verify::Valid);
+
+    let bad_mix = format!("0x{}", "00".repeat(32));
+    assert_eq!(
+        verify("ETH", &job, nonce, &bad_mix).await.unwrap(),
+        Verify::BadMix
+    );
+    let mut hard = job.clone();
+    hard[2] = format!("0x{}", "0".repeat(16) + &"f".repeat(48));
+    assert_eq!(
+        verify("ETH", &hard, nonce, mix).await.unwrap(),
+        Verify::LowDifficulty
+    );
+}
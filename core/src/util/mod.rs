@@ -1,4 +1,5 @@
 pub mod config;
+pub mod ethash;
 pub mod logger;
 
 extern crate clap;
@@ -334,13 +335,16 @@ pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
 
     let handle = handle
         .arg("--server")
+        // 用于下发配置更新，见 proxy::control
+        .stdin(std::process::Stdio::piped())
         .env("PROXY_NAME", config.name.clone())
         .env("PROXY_LOG_LEVEL", config.log_level.to_string())
         .env("PROXY_TCP_PORT", config.tcp_port.to_string())
         .env("PROXY_SSL_PORT", config.ssl_port.to_string())
         .env("PROXY_ENCRYPT_PORT", config.encrypt_port.to_string())
-        .env("PROXY_POOL_ADDRESS", config.pool_address[0].clone())
-        .env("PROXY_SHARE_ADDRESS", config.share_address[0].clone())
+        .env("PROXY_AUTO_PORT", config.auto_port.to_string())
+        .env("PROXY_POOL_ADDRESS", config.pool_address.join(","))
+        .env("PROXY_SHARE_ADDRESS", config.share_address.join(","))
         .env("PROXY_SHARE_RATE", config.share_rate.to_string())
         .env("PROXY_SHARE_WALLET", config.share_wallet.to_string())
         .env("PROXY_SHARE_ALG", config.share_alg.to_string())
@@ -348,6 +352,21 @@ pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
         .env("PROXY_COIN", config.coin.to_string())
         .env("PROXY_SHARE_NAME", config.share_name.to_string())
         .env("PROXY_SHARE", config.share.to_string())
+        .env("PROXY_VIA", config.via.to_string())
+        .env("PROXY_AGGREGATE", config.aggregate.to_string())
+        .env("PROXY_POOL_SCORE", config.pool_score.to_string())
+        .env("PROXY_FEE_WINDOW", config.fee_window.to_string())
+        .env("PROXY_DUPLICATE_SHARE", config.duplicate_share.to_string())
+        .env("PROXY_UNKNOWN_SHARE", config.unknown_share.to_string())
+        .env("PROXY_STALE_SHARE", config.stale_share.to_string())
+        .env("PROXY_VERIFY_SHARE", config.verify_share.to_string())
+        .env("PROXY_VARDIFF", config.vardiff.to_string())
+        .env("PROXY_LEDGER_DAYS", config.ledger_days.to_string())
+        .env("PROXY_FEE_RULES", serde_json::to_string(&config.fee_rules)?)
+        .env(
+            "PROXY_FEE_DESTINATIONS",
+            serde_json::to_string(&config.fee_destinations)?,
+        )
         .env(
             "PROXY_PEM_PATH",
             exe_path.to_str().expect("无法转换路径为字符串").to_string()
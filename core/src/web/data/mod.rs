@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::util::config::FeeDestination;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct CreateRequest {
@@ -9,15 +11,62 @@ pub struct CreateRequest {
     pub tcp_port: u32,
     pub ssl_port: u32,
     pub encrypt_port: u32,
+    pub auto_port: u32,
     pub share: u32,
     pub pool_address: String,
     pub share_address: String,
     pub share_rate: f32,
     pub share_wallet: String,
+    pub via: String,
+    pub aggregate: u32,
+    #[serde(default)]
+    pub pool_score: bool,
+    pub fee_window: u32,
+    // 多个抽水去向，设置后代替 share_address 与 share_wallet
+    pub fee_destinations: Vec<FeeDestination>,
+    pub duplicate_share: u32,
+    pub unknown_share: u32,
+    pub stale_share: u32,
+    #[serde(default)]
+    pub verify_share: bool,
+    #[serde(default)]
+    pub vardiff: u32,
+    #[serde(default)]
+    pub ledger_days: u32,
     pub key: String,
     pub iv: String,
 }
 
+// 抽水规则。rate 为百分比
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct FeeRuleData {
+    pub wallet: String,
+    pub worker: String,
+    pub rate: f32,
+}
+
+// 账本查询。日期格式为 YYYY-MM-DD，默认为最近 30 天
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LedgerQuery {
+    pub from: String,
+    pub to: String,
+    pub wallet: String,
+    pub worker: String,
+    // 导出份额明细而不是按天汇总
+    pub detail: bool,
+}
+
+// 矿机历史查询。range 为 24h、7d 或 30d
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct HistoryQuery {
+    pub range: String,
+    pub wallet: String,
+    pub worker: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct TokenDataResponse {
@@ -1,3 +1,4 @@
 pub mod auth;
+pub mod public;
 pub mod server;
 pub mod user;
@@ -0,0 +1,131 @@
+use actix_web::{get, web, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    state::work::{EffectiveHashrate, Work},
+    util::{human_bytes, time_to_string},
+    web::{
+        data::*,
+        handles::server::{floor, hashrate_result, HashrateResult},
+        AppState,
+    },
+    DEVELOP_FEE,
+};
+
+// 公开的抽水信息，无需登录。
+// 矿工按钱包查询自己矿机的设定及实际抽水比例，只返回该钱包的数据，
+// 不包含中转名称、矿池等配置。
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PublicWorker {
+    pub worker_name: String,
+    pub online: bool,
+    pub hash: String,
+    pub effective_hash: HashrateResult,
+    pub online_time: String,
+    // 设定及按难度加权的实际比例 (百分比)
+    pub fee_rate: f64,
+    pub achieved_rate: f64,
+    pub develop_rate: f64,
+    pub share_index: u64,
+    pub fee_share_index: u64,
+    pub develop_share_index: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PublicFeeResult {
+    pub wallet: String,
+    pub hash: String,
+    pub effective_hash: HashrateResult,
+    pub develop_fee: f64,
+    pub achieved_rate: f64,
+    pub develop_rate: f64,
+    pub share_index: u64,
+    pub fee_share_index: u64,
+    pub develop_share_index: u64,
+    pub workers: Vec<PublicWorker>,
+}
+
+// 查询钱包的抽水信息
+#[get("/public/fee/{wallet}")]
+async fn fee_disclosure(
+    wallet: web::Path<String>, app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let wallet = wallet.trim().to_lowercase();
+    if wallet.is_empty() || wallet.len() > 128 {
+        return Ok(web::Json(Response::<PublicFeeResult> {
+            code: 40000,
+            message: "钱包地址不正确".into(),
+            data: PublicFeeResult::default(),
+        }));
+    }
+
+    let mut res = PublicFeeResult {
+        wallet: wallet.clone(),
+        develop_fee: floor(*DEVELOP_FEE * 100.0, 2),
+        ..Default::default()
+    };
+    let mut total_hash: f64 = 0.0;
+    let mut work = Work::default();
+    let mut effective_hash = EffectiveHashrate::default();
+    {
+        let proxy_server = app.lock().unwrap();
+        for other_server in proxy_server.values() {
+            for r in &other_server.workers {
+                if r.worker_wallet.to_lowercase() != wallet {
+                    continue;
+                }
+                if r.is_online() {
+                    total_hash += r.hash as f64;
+                    effective_hash.merge(&r.effective_hash);
+                }
+                work.merge(&r.work);
+                res.share_index += r.share_index;
+                res.fee_share_index += r.fee_share_index;
+                res.develop_share_index += r.develop_share_index;
+                res.workers.push(PublicWorker {
+                    worker_name: r.worker_name.clone(),
+                    online: r.is_online(),
+                    hash: human_bytes(r.hash as f64),
+                    effective_hash: hashrate_result(&r.effective_hash),
+                    online_time: time_to_string(
+                        r.login_time.elapsed().as_secs(),
+                    ),
+                    fee_rate: floor(
+                        other_server
+                            .config
+                            .fee_rate(&r.worker_wallet, &r.worker_name)
+                            as f64
+                            * 100.0,
+                        2,
+                    ),
+                    achieved_rate: floor(r.work.fee_rate(), 2),
+                    develop_rate: floor(r.work.develop_rate(), 2),
+                    share_index: r.share_index,
+                    fee_share_index: r.fee_share_index,
+                    develop_share_index: r.develop_share_index,
+                });
+            }
+        }
+    }
+
+    if res.workers.is_empty() {
+        return Ok(web::Json(Response::<PublicFeeResult> {
+            code: 40000,
+            message: "未找到该钱包的矿机".into(),
+            data: PublicFeeResult::default(),
+        }));
+    }
+
+    res.hash = human_bytes(total_hash);
+    res.effective_hash = hashrate_result(&effective_hash);
+    res.achieved_rate = floor(work.fee_rate(), 2);
+    res.develop_rate = floor(work.develop_rate(), 2);
+    res.workers.sort_by(|a, b| a.worker_name.cmp(&b.worker_name));
+
+    Ok(web::Json(Response::<PublicFeeResult> {
+        code: 20000,
+        message: "".into(),
+        data: res,
+    }))
+}
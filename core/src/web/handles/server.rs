@@ -1,17 +1,33 @@
 use actix_web_grants::proc_macro::has_permissions;
 use std::{
+    collections::HashMap,
     fs::OpenOptions,
     io::{Read, Write},
+    pin::Pin,
+    task::{Context, Poll},
 };
 
 use clap::crate_version;
 
-use actix_web::{get, post, web, Responder};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use bytes::Bytes;
+use chrono::{Duration, Local, NaiveDate};
+use tokio::sync::mpsc;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    util::{config::Settings, human_bytes, time_to_string},
+    client::health::PoolHealth,
+    proxy::control::ConfigUpdate,
+    state::{
+        ledger::{self, DayReport, LedgerFilter},
+        work::{EffectiveHashrate, Work},
+    },
+    storage::{History, HistoryRange, Storage},
+    util::{
+        config::{FeeRule, Settings},
+        human_bytes, time_to_string,
+    },
     web::{data::*, AppState, OnlineWorker},
 };
 
@@ -30,7 +46,11 @@ pub async fn crate_app(
         }));
     }
 
-    if req.tcp_port == 0 && req.ssl_port == 0 && req.encrypt_port == 0 {
+    if req.tcp_port == 0
+        && req.ssl_port == 0
+        && req.encrypt_port == 0
+        && req.auto_port == 0
+    {
         return Ok(web::Json(Response::<String> {
             code: 40000,
             message: "未开启端口。请至少开启一个端口".into(),
@@ -47,7 +67,7 @@ pub async fn crate_app(
         }));
     }
 
-    if req.share != 0 {
+    if req.share != 0 && req.fee_destinations.is_empty() {
         if req.share_address.is_empty() {
             //println!("抽水矿池必须填写");
             return Ok(web::Json(Response::<String> {
@@ -81,16 +101,36 @@ pub async fn crate_app(
     config.log_level = "DEBUG".into();
     //config.log_path = "".into();
     config.name = req.name.clone();
-    config.pool_address = vec![req.pool_address.clone()];
-    config.share_address = vec![req.share_address.clone()];
+    // 多个中转矿池以逗号分隔，按顺序故障转移
+    config.pool_address = req
+        .pool_address
+        .split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect();
+    if !req.share_address.is_empty() {
+        config.share_address = vec![req.share_address.clone()];
+    }
+    config.fee_destinations = req.fee_destinations.clone();
     config.tcp_port = req.tcp_port;
     config.ssl_port = req.ssl_port;
     config.encrypt_port = req.encrypt_port;
+    config.auto_port = req.auto_port;
     config.share = req.share;
     config.share_rate = req.share_rate as f32 / 100.0;
     config.share_alg = req.share_alg;
     config.hash_rate = 100;
     config.share_wallet = req.share_wallet.clone();
+    config.via = req.via.trim().to_string();
+    config.aggregate = req.aggregate;
+    config.pool_score = req.pool_score;
+    config.fee_window = req.fee_window;
+    config.duplicate_share = req.duplicate_share;
+    config.unknown_share = req.unknown_share;
+    config.stale_share = req.stale_share;
+    config.verify_share = req.verify_share;
+    config.vardiff = req.vardiff;
+    config.ledger_days = req.ledger_days;
 
     match config.check().await {
         Ok(_) => {}
@@ -205,6 +245,7 @@ pub async fn crate_app(
                         config: config.clone(),
                         workers: vec![],
                         online: 0,
+                        pool_health: vec![],
                     };
                     app.lock().unwrap().insert(config.name, online);
                 }
@@ -258,6 +299,7 @@ pub async fn crate_app(
                         config: config.clone(),
                         workers: vec![],
                         online: 0,
+                        pool_health: vec![],
                     };
                     app.lock().unwrap().insert(config.name, online);
                 }
@@ -299,17 +341,53 @@ async fn server_list(
     }))
 }
 
+// 有效算力，与上报算力格式相同
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HashrateResult {
+    pub ten_minutes: String,
+    pub hour: String,
+    pub day: String,
+}
+
+pub fn hashrate_result(hash: &EffectiveHashrate) -> HashrateResult {
+    HashrateResult {
+        ten_minutes: human_bytes(hash.ten_minutes),
+        hour: human_bytes(hash.hour),
+        day: human_bytes(hash.day),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ResWorker {
     pub worker_name: String,
     pub worker_wallet: String,
     pub hash: String,
+    pub effective_hash: HashrateResult,
     pub last_subwork_time: String,
     pub online_time: String,
     pub share_index: u64,
     pub accept_index: u64,
     pub fee_accept_index: u64,
     pub invalid_index: u64,
+    // 本地校验未通过的份额
+    pub duplicate_index: u64,
+    pub unknown_index: u64,
+    pub stale_index: u64,
+    // 本地校验未通过的份额，过多时标记为故障矿机
+    pub local_invalid_index: u64,
+    pub faulty: bool,
+    pub achieved_rate: f64,
+    // 按抽水规则适用的比例
+    pub fee_rate: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FeeDestinationResult {
+    pub name: String,
+    pub weight: u32,
+    // 按权重应得的比例及实际所得比例
+    pub rate: f64,
+    pub achieved_rate: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -320,6 +398,7 @@ pub struct OnlineWorkerResult {
     pub config: Settings,
     pub fee_hash: String,
     pub total_hash: String,
+    pub effective_hash: HashrateResult,
     pub accept_index: u64,
     pub share_index: u64,
     pub reject_index: u64,
@@ -328,6 +407,12 @@ pub struct OnlineWorkerResult {
     pub fee_reject_index: u64,
     pub rate: f64,
     pub share_rate: f64,
+    // 按难度加权的实际抽水比例
+    pub achieved_rate: f64,
+    pub develop_rate: f64,
+    pub fee_destinations: Vec<FeeDestinationResult>,
+    // 矿池健康状态：评分、最近错误等
+    pub pool_health: Vec<PoolHealth>,
 }
 
 // 展示选中的数据信息。以json格式返回
@@ -347,6 +432,9 @@ async fn server(
         let mut fee_accept_index: u64 = 0;
         let mut fee_share_index: u64 = 0;
         let mut fee_reject_index: u64 = 0;
+        let mut work = Work::default();
+        let mut fee_work: HashMap<String, f64> = HashMap::new();
+        let mut effective_hash = EffectiveHashrate::default();
 
         for (name, server) in &*proxy_server {
             if *name == proxy_server_name.to_string() {
@@ -358,10 +446,25 @@ async fn server(
                             worker_name: r.worker_name.clone(),
                             worker_wallet: r.worker_wallet.clone(),
                             hash: human_bytes(r.hash as f64),
+                            effective_hash: hashrate_result(&r.effective_hash),
                             share_index: r.share_index,
                             accept_index: r.accept_index,
                             invalid_index: r.invalid_index,
+                            duplicate_index: r.duplicate_share_index,
+                            unknown_index: r.unknown_share_index,
+                            stale_index: r.stale_share_index,
+                            local_invalid_index: r.local_invalid_index,
+                            faulty: r.is_faulty(),
                             fee_accept_index: r.fee_accept_index,
+                            achieved_rate: floor(r.work.fee_rate(), 2),
+                            fee_rate: floor(
+                                server.config.fee_rate(
+                                    &r.worker_wallet,
+                                    &r.worker_name,
+                                ) as f64
+                                    * 100.0,
+                                2,
+                            ),
                             online_time: time_to_string(
                                 r.login_time.elapsed().as_secs(),
                             ),
@@ -376,9 +479,15 @@ async fn server(
                         fee_accept_index += r.fee_share_index;
                         fee_share_index += r.fee_accept_index;
                         fee_reject_index += r.fee_invalid_index;
+                        work.merge(&r.work);
+                        effective_hash.merge(&r.effective_hash);
+                        for (pool, diff) in &r.fee_work {
+                            *fee_work.entry(pool.clone()).or_default() += diff;
+                        }
                     }
                 }
                 res.config = server.config.clone();
+                res.pool_health = server.pool_health.clone();
             }
         }
 
@@ -399,11 +508,34 @@ async fn server(
                 res.fee_share_index as f64 / res.accept_index as f64 * 100.0,
                 2,
             );
+            res.achieved_rate = floor(work.fee_rate(), 2);
+            res.develop_rate = floor(work.develop_rate(), 2);
+        }
+
+        let dests = res.config.fee_destinations();
+        let total_weight: u32 = dests.iter().map(|d| d.weight).sum();
+        let total_work: f64 = fee_work.values().sum();
+        for dest in dests {
+            let work = fee_work.get(&dest.name).copied().unwrap_or(0.0);
+            res.fee_destinations.push(FeeDestinationResult {
+                rate: floor(
+                    dest.weight as f64 / total_weight.max(1) as f64 * 100.0,
+                    2,
+                ),
+                achieved_rate: if total_work > 0.0 {
+                    floor(work / total_work * 100.0, 2)
+                } else {
+                    0.0
+                },
+                name: dest.name,
+                weight: dest.weight,
+            });
         }
 
         res.fee_hash =
             human_bytes(total_hash as f64 * res.config.share_rate as f64);
         res.total_hash = human_bytes(total_hash as f64);
+        res.effective_hash = hashrate_result(&effective_hash);
     }
 
     //1. 基本配置文件信息 .
@@ -428,6 +560,7 @@ pub struct DashboardResult {
     pub online: u32,
     pub fee_hash: String,
     pub total_hash: String,
+    pub effective_hash: HashrateResult,
     pub accept_index: u64,
     pub share_index: u64,
     pub reject_index: u64,
@@ -436,6 +569,8 @@ pub struct DashboardResult {
     pub fee_reject_index: u64,
     pub rate: f64,       //总代理算力
     pub share_rate: f64, //抽水算力
+    pub achieved_rate: f64, //按难度加权的实际抽水比例
+    pub develop_rate: f64,
     pub version: String,
     pub develop_worker_name: String,
     pub online_time: String,
@@ -458,6 +593,8 @@ async fn dashboard(
         let mut fee_accept_index: u64 = 0;
         let mut fee_share_index: u64 = 0;
         let mut fee_reject_index: u64 = 0;
+        let mut work = Work::default();
+        let mut effective_hash = EffectiveHashrate::default();
 
         for (_, other_server) in &*proxy_server {
             for r in &other_server.workers {
@@ -470,6 +607,8 @@ async fn dashboard(
                     fee_accept_index += r.fee_share_index;
                     fee_share_index += r.fee_accept_index;
                     fee_reject_index += r.fee_invalid_index;
+                    work.merge(&r.work);
+                    effective_hash.merge(&r.effective_hash);
                 }
             }
 
@@ -484,6 +623,9 @@ async fn dashboard(
         res.fee_share_index += fee_share_index;
         res.fee_reject_index += fee_reject_index;
 
+        res.achieved_rate = floor(work.fee_rate(), 2);
+        res.develop_rate = floor(work.develop_rate(), 2);
+        res.effective_hash = hashrate_result(&effective_hash);
         res.proxy_num = proxy_server.len() as i32;
         res.online = online;
     }
@@ -512,3 +654,298 @@ async fn dashboard(
         data: res,
     }))
 }
+
+fn rule_data(rule: &FeeRule) -> FeeRuleData {
+    FeeRuleData {
+        wallet: rule.wallet.clone(),
+        worker: rule.worker.clone(),
+        rate: rule.rate * 100.0,
+    }
+}
+
+// 查看中转的抽水规则
+#[get("/user/server/{name}/fee_rules")]
+#[has_permissions("ROLE_ADMIN")]
+async fn fee_rules(
+    proxy_server_name: web::Path<String>, app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let proxy_server = app.lock().unwrap();
+    match proxy_server.get(proxy_server_name.as_str()) {
+        Some(other_server) => Ok(web::Json(Response::<Vec<FeeRuleData>> {
+            code: 20000,
+            message: "".into(),
+            data: other_server.config.fee_rules.iter().map(rule_data).collect(),
+        })),
+        None => Ok(web::Json(Response::<Vec<FeeRuleData>> {
+            code: 40000,
+            message: format!("中转 {} 不存在", proxy_server_name),
+            data: vec![],
+        })),
+    }
+}
+
+// 替换中转的抽水规则。保存后下发给运行中的中转，不需要重启
+#[post("/user/server/{name}/fee_rules")]
+#[has_permissions("ROLE_ADMIN")]
+async fn set_fee_rules(
+    proxy_server_name: web::Path<String>, req: web::Json<Vec<FeeRuleData>>,
+    app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let error = |message: String| {
+        Ok(web::Json(Response::<String> {
+            code: 40000,
+            message,
+            data: String::default(),
+        }))
+    };
+
+    let name = proxy_server_name.to_string();
+    let mut config = match app.lock().unwrap().get(&name) {
+        Some(other_server) => other_server.config.clone(),
+        None => return error(format!("中转 {} 不存在", name)),
+    };
+    config.fee_rules = req
+        .iter()
+        .map(|r| FeeRule {
+            wallet: r.wallet.trim().to_string(),
+            worker: r.worker.trim().to_string(),
+            rate: r.rate / 100.0,
+        })
+        .collect();
+
+    if let Err(err) = config.check_fee_rules() {
+        return error(format!("配置错误 {}", err));
+    }
+    if let Err(err) = save_config(&config) {
+        return error(format!("保存配置失败 {}", err));
+    }
+
+    // 写入时不能持有 AppState 的锁，先取出标准输入
+    let stdin = match app.lock().unwrap().get_mut(&name) {
+        Some(other_server) => other_server.child.stdin.take(),
+        None => return error(format!("中转 {} 不存在", name)),
+    };
+    let mut stdin = match stdin {
+        Some(stdin) => stdin,
+        None => {
+            return error(format!("中转 {} 正在更新配置，请稍后重试", name))
+        }
+    };
+    let update = ConfigUpdate {
+        fee_rules: Some(config.fee_rules.clone()),
+    };
+    let res = update.send(&mut stdin).await;
+    if let Some(other_server) = app.lock().unwrap().get_mut(&name) {
+        other_server.child.stdin = Some(stdin);
+        other_server.config.fee_rules = config.fee_rules;
+    }
+    if let Err(err) = res {
+        return error(format!("下发抽水规则失败，重启中转后生效 {}", err));
+    }
+
+    Ok(web::Json(Response::<String> {
+        code: 20000,
+        message: "".into(),
+        data: String::default(),
+    }))
+}
+
+// 更新 configs.yaml 中同名中转的配置
+fn save_config(config: &Settings) -> anyhow::Result<()> {
+    let configs = std::fs::read_to_string("configs.yaml")?;
+    let mut configs: Vec<Settings> = serde_yaml::from_str(&configs)?;
+    match configs.iter_mut().find(|c| c.name == config.name) {
+        Some(c) => *c = config.clone(),
+        None => configs.push(config.clone()),
+    }
+    std::fs::write("configs.yaml", serde_yaml::to_string(&configs)?)?;
+    Ok(())
+}
+
+// 账本最多查询的天数
+const MAX_LEDGER_DAYS: i64 = 366;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LedgerDayResult {
+    #[serde(flatten)]
+    pub report: DayReport,
+    // 实抽与应抽比例 (百分比)
+    pub fee_rate: f64,
+    pub expected_rate: f64,
+    pub difference: f64,
+}
+
+fn parse_date(date: &str, default: NaiveDate) -> Result<NaiveDate, String> {
+    if date.is_empty() {
+        return Ok(default);
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("日期格式错误 {}", date))
+}
+
+// 查询的中转及日期范围
+fn ledger_range(
+    name: &str, query: &LedgerQuery, app: &AppState,
+) -> Result<(NaiveDate, NaiveDate), String> {
+    if !app.lock().unwrap().contains_key(name) {
+        return Err(format!("中转 {} 不存在", name));
+    }
+
+    let today = Local::now().date().naive_local();
+    let to = parse_date(&query.to, today)?;
+    let from = parse_date(&query.from, to - Duration::days(29))?;
+    if from > to {
+        return Err("开始日期晚于结束日期".into());
+    }
+    if to - from >= Duration::days(MAX_LEDGER_DAYS) {
+        return Err(format!("最多查询 {} 天", MAX_LEDGER_DAYS));
+    }
+    Ok((from, to))
+}
+
+// 读取中转按天汇总的账本并按矿机、钱包筛选
+async fn ledger_reports(
+    name: String, query: &LedgerQuery, app: &AppState,
+) -> Result<Vec<DayReport>, String> {
+    let (from, to) = ledger_range(&name, query, app)?;
+    let filter = LedgerFilter::new(&query.wallet, &query.worker);
+    web::block(move || ledger::read_reports(&name, from, to, &filter))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("读取账本失败 {}", e))
+}
+
+// 份额明细导出，由账本读取任务逐块发送
+struct ExportStream(mpsc::Receiver<Bytes>);
+
+impl futures_core::Stream for ExportStream {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|chunk| chunk.map(Ok))
+    }
+}
+
+// 按天核对应抽与实抽的工作量
+#[get("/user/server/{name}/ledger")]
+#[has_permissions("ROLE_ADMIN")]
+async fn fee_ledger(
+    proxy_server_name: web::Path<String>, query: web::Query<LedgerQuery>,
+    app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let reports =
+        match ledger_reports(proxy_server_name.to_string(), &query, &app).await
+        {
+            Ok(reports) => reports,
+            Err(message) => {
+                return Ok(web::Json(Response::<Vec<LedgerDayResult>> {
+                    code: 40000,
+                    message,
+                    data: vec![],
+                }))
+            }
+        };
+
+    let data = reports
+        .into_iter()
+        .map(|report| LedgerDayResult {
+            fee_rate: floor(report.work.fee_rate(), 4),
+            expected_rate: floor(report.expected_rate(), 4),
+            difference: report.difference(),
+            report,
+        })
+        .collect();
+
+    Ok(web::Json(Response::<Vec<LedgerDayResult>> {
+        code: 20000,
+        message: "".into(),
+        data,
+    }))
+}
+
+// 导出对账结果或份额明细 (CSV)
+#[get("/user/server/{name}/ledger/export")]
+#[has_permissions("ROLE_ADMIN")]
+async fn export_fee_ledger(
+    proxy_server_name: web::Path<String>, query: web::Query<LedgerQuery>,
+    app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let name = proxy_server_name.to_string();
+    let error = |message: String| {
+        Ok(HttpResponse::Ok().json(Response::<String> {
+            code: 40000,
+            message,
+            data: String::default(),
+        }))
+    };
+
+    let file = if query.detail { "shares" } else { "ledger" };
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/csv; charset=utf-8").insert_header((
+        "Content-Disposition",
+        format!("attachment; filename=\"{}-{}.csv\"", name, file),
+    ));
+
+    if !query.detail {
+        return match ledger_reports(name, &query, &app).await {
+            Ok(reports) => Ok(response.body(ledger::reports_csv(&reports))),
+            Err(message) => error(message),
+        };
+    }
+
+    // 明细可能很大，逐个文件读取并分块发送
+    let (from, to) = match ledger_range(&name, &query, &app) {
+        Ok(range) => range,
+        Err(message) => return error(message),
+    };
+    let filter = LedgerFilter::new(&query.wallet, &query.worker);
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(e) = ledger::export_records(name, from, to, filter, tx).await
+        {
+            tracing::error!("导出份额明细失败 {}", e);
+        }
+    });
+    Ok(response.streaming(ExportStream(rx)))
+}
+
+// 矿机的算力、份额及上下线历史
+#[get("/user/server/{name}/history")]
+#[has_permissions("ROLE_ADMIN")]
+async fn worker_history(
+    proxy_server_name: web::Path<String>, query: web::Query<HistoryQuery>,
+    storage: web::Data<Storage>,
+) -> actix_web::Result<impl Responder> {
+    let error = |message: String| {
+        Ok(web::Json(Response::<History> {
+            code: 40000,
+            message,
+            data: History::default(),
+        }))
+    };
+
+    let range = match HistoryRange::parse(&query.range) {
+        Some(range) => range,
+        None => return error(format!("不支持的时间范围 {}", query.range)),
+    };
+    let name = proxy_server_name.to_string();
+    let wallet = query.wallet.trim().to_string();
+    let worker = query.worker.trim().to_string();
+    let storage = storage.into_inner();
+    let history = web::block(move || {
+        storage.history(&name, &wallet, &worker, range)
+    })
+    .await;
+
+    match history {
+        Ok(Ok(data)) => Ok(web::Json(Response::<History> {
+            code: 20000,
+            message: "".into(),
+            data,
+        })),
+        Ok(Err(e)) => error(format!("读取历史数据失败 {}", e)),
+        Err(e) => error(format!("读取历史数据失败 {}", e)),
+    }
+}
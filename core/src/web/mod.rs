@@ -1,4 +1,6 @@
-use crate::{state::Worker, util::config::Settings};
+use crate::{
+    client::health::PoolHealth, state::Worker, util::config::Settings,
+};
 
 pub mod data;
 pub mod handles;
@@ -17,4 +19,6 @@ pub struct OnlineWorker {
     pub workers: Vec<Worker>,
     pub online: u32,
     pub config: Settings,
+    // 中转上报的矿池健康状态
+    pub pool_health: Vec<PoolHealth>,
 }
@@ -7,7 +7,7 @@ include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 use rustls_pemfile::{certs, rsa_private_keys};
 use tokio_rustls::rustls::{self, Certificate, PrivateKey};
 
-use std::{path::Path, sync::Arc, collections::VecDeque};
+use std::{path::Path, sync::Arc};
 use tracing::Level;
 
 use tokio::sync::{broadcast, RwLock, Mutex};
@@ -28,11 +28,17 @@ use actix_web::{dev::ServiceRequest, web, App, Error, HttpServer};
 
 use core::{
     client::{
-        encry::accept_en_tcp, tcp::accept_tcp, tls::accept_tcp_with_tls, SSL,
-        TCP,
+        auto::accept_auto,
+        encry::accept_en_tcp,
+        health::{pool_health, PoolHealth},
+        tcp::accept_tcp,
+        tls::accept_tcp_with_tls,
+    },
+    proxy::{
+        fee::FeeScheduler, job::JobStore, Extranonce, FeePool, FeeShare, Job,
     },
-    proxy::Job,
     state::Worker,
+    storage::Storage,
     util::config::Settings,
     web::{handles::auth::Claims, AppState, OnlineWorker},
 };
@@ -140,6 +146,7 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
                                     config: config.clone(),
                                     workers: vec![],
                                     online: 0,
+                                    pool_health: vec![],
                                 };
 
                                 data.lock()
@@ -161,6 +168,12 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
 
     tokio::spawn(async move { recv_from_child(tcp_data).await });
 
+    let storage = web::Data::new(Storage::open(core::storage::HISTORY_DB)?);
+    tokio::spawn(core::storage::run(
+        storage.clone().into_inner(),
+        data.clone(),
+    ));
+
     let port: i32 = match std::env::var("MINING_PROXY_WEB_PORT") {
         Ok(p) => p.parse().unwrap(),
         Err(_) => 8888,
@@ -176,14 +189,21 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
         App::new()
             .wrap(auth)
             .app_data(web::Data::new(http_data.clone()))
+            .app_data(storage.clone())
             .service(
                 web::scope("/api")
                     .service(core::web::handles::user::login)
                     .service(core::web::handles::user::info)
                     .service(core::web::handles::user::logout)
+                    .service(core::web::handles::public::fee_disclosure)
                     .service(core::web::handles::server::crate_app)
                     .service(core::web::handles::server::server_list)
                     .service(core::web::handles::server::server)
+                    .service(core::web::handles::server::fee_rules)
+                    .service(core::web::handles::server::set_fee_rules)
+                    .service(core::web::handles::server::fee_ledger)
+                    .service(core::web::handles::server::export_fee_ledger)
+                    .service(core::web::handles::server::worker_history)
                     .service(core::web::handles::server::dashboard),
             )
             .service(actix_web_static_files::ResourceFiles::new("/", generated))
@@ -225,6 +245,11 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
         }
     };
 
+    if let Err(err) = core::client::dial::set_default_via(&config.via) {
+        tracing::error!("config配置错误 {}", err);
+        std::process::exit(1);
+    }
+
     match config.check_net_work().await {
         Ok(_) => {}
         Err(err) => {
@@ -276,17 +301,15 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
 
     tracing::info!("名称 {} 当前启动模式为: {}", config.name, mode);
 
-    let worker_name = config.share_name.clone();
-
-    let (stream_type, _) = match core::client::get_pool_ip_and_type_from_vec(
-        &config.share_address,
-    ) {
-        Ok((stream, addr)) => (stream, addr),
-        Err(e) => {
-            tracing::error!("Share_address 矿池参数格式化失败。无法启动 {}", e);
-            return Ok(());
-        }
-    };
+    for dest in config.fee_destinations() {
+        match core::client::failover::parse_pools(&dest.address) {
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!("Share_address 矿池参数格式化失败。无法启动 {}", e);
+                return Ok(());
+            }
+        };
+    }
 
     let certs = match load_certs(Path::new(&config.pem_path)) {
         Ok(cert) => {
@@ -357,11 +380,13 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
     //    if config.coin == "ETH" {
     // let (chan_tx, _chan_rx) = broadcast::channel::<Vec<String>>(1);
     // let (dev_chan_tx, _dev_chan_rx) = broadcast::channel::<Vec<String>>(1);
-    let fee_job:Job = Arc::new(RwLock::new(VecDeque::new()));
-    let develop_job:Job = Arc::new(RwLock::new(VecDeque::new()));
-    
-    let (tx, rx) = mpsc::channel::<Vec<String>>(15);
-    let (dev_tx, dev_rx) = mpsc::channel::<Vec<String>>(15);
+    let develop_job: Job = Arc::new(JobStore::new());
+    let develop_extranonce: Extranonce = Arc::new(RwLock::new(None));
+
+    // 每个抽水去向一个份额通道
+    let (fee_pools, fee_rxs): (Vec<FeePool>, Vec<_>) =
+        config.fee_destinations().iter().map(FeePool::new).unzip();
+    let (dev_tx, dev_rx) = mpsc::channel::<FeeShare>(15);
     // let (tx, rx) =
     //     bounded::<Vec<String>>(15);
     // let (dev_tx, dev_rx) =
@@ -375,10 +400,11 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
         config: Arc::new(RwLock::new(config)),
         worker_tx,
 //        chan: chan_tx.clone(),
-        tx,
         dev_tx,
-	fee_job:fee_job.clone(),
+        fee_pools,
 	develop_job:develop_job.clone(),
+        develop_extranonce,
+        fee_scheduler: FeeScheduler::new(),
 //        dev_chan: dev_chan_tx.clone(),
     });
 
@@ -386,69 +412,31 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
         core::client::dev_pool_ssl_login(core::DEVELOP_WORKER_NAME.to_string())
             .await?;
 
-    if stream_type == TCP {
-        let (proxy_lines, proxy_w) =
-            core::client::proxy_pool_login(&mconfig, worker_name.clone())
-                .await?;
-        let res = tokio::try_join!(
-            accept_tcp(Arc::clone(&proxy)),
-            accept_en_tcp(Arc::clone(&proxy)),
-            accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
-            send_to_parent(worker_rx, &mconfig),
-            core::client::fee::fee_tcp(
-                rx,
-		fee_job,
-                proxy_lines,
-                proxy_w,
-                worker_name.clone(),
-		proxy.clone(),
-            ),
-            core::client::fee::develop_fee_ssl(
-                dev_rx,
-		develop_job,
-                dev_lines,
-                dev_w,
-                core::DEVELOP_WORKER_NAME.to_string(),
-		proxy,
-            ),
-        );
-
-        if let Err(err) = res {
-            tracing::error!("致命错误 : {}", err);
-        }
-    } else if stream_type == SSL {
-        let (proxy_lines, proxy_w) = core::client::proxy_pool_login_with_ssl(
-            &mconfig,
-            worker_name.clone(),
-        )
-        .await?;
-
-        let res = tokio::try_join!(
-            accept_tcp(Arc::clone(&proxy)),
-            accept_en_tcp(Arc::clone(&proxy)),
-            accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
-            send_to_parent(worker_rx, &mconfig),
-            core::client::fee::fee_ssl(
-                rx,
-		fee_job,
-                proxy_lines,
-                proxy_w,
-                worker_name.clone(),
-		proxy.clone(),
-            ),
-            core::client::fee::develop_fee_ssl(
-                dev_rx,
-		develop_job,
-                dev_lines,
-                dev_w,
-                core::DEVELOP_WORKER_NAME.to_string(),
-		proxy,
-            ),
-        );
-
-        if let Err(err) = res {
-            tracing::error!("致命错误 : {}", err);
-        }
+    let res = tokio::try_join!(
+        accept_tcp(Arc::clone(&proxy)),
+        accept_en_tcp(Arc::clone(&proxy)),
+        accept_tcp_with_tls(Arc::clone(&proxy), cert_config.clone()),
+        accept_auto(Arc::clone(&proxy), cert_config),
+        core::client::health::health_check(Arc::clone(&proxy)),
+        send_to_parent(worker_rx, &mconfig),
+        core::state::ledger::ledger_writer(
+            mconfig.name.clone(),
+            mconfig.ledger_days,
+        ),
+        core::proxy::control::config_updates(proxy.clone()),
+        core::client::fee::proxy_fees(fee_rxs, proxy.clone()),
+        core::client::fee::develop_fee(
+            dev_rx,
+            develop_job,
+            dev_lines,
+            dev_w,
+            core::DEVELOP_WORKER_NAME.to_string(),
+            proxy,
+        ),
+    );
+
+    if let Err(err) = res {
+        tracing::error!("致命错误 : {}", err);
     }
 
     Ok(())
@@ -461,6 +449,14 @@ pub struct SendToParentStruct {
     worker: Worker,
 }
 
+// 中转定时上报的矿池健康状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolHealthReport {
+    name: String,
+    pool_health: Vec<PoolHealth>,
+}
+
 async fn send_to_parent(
     mut worker_rx: UnboundedReceiver<Worker>, config: &Settings,
 ) -> Result<()> {
@@ -469,8 +465,19 @@ async fn send_to_parent(
             tokio::net::TcpStream::connect("127.0.0.1:65501").await
         {
             //let name = config.name.clone();
+            let mut health_tick =
+                tokio::time::interval(tokio::time::Duration::from_secs(30));
             loop {
                 select! {
+                    _ = health_tick.tick() => {
+                        let send = PoolHealthReport{
+                            name:config.name.clone(),
+                            pool_health:pool_health(),
+                        };
+                        let mut rpc = serde_json::to_vec(&send)?;
+                        rpc.push(b'\n');
+                        stream.write(&rpc).await.unwrap();
+                    },
                     Some(w) = worker_rx.recv() => {
                         let send = SendToParentStruct{
                             name:config.name.clone(),
@@ -534,6 +541,14 @@ async fn recv_from_child(app: AppState) -> Result<()> {
                         } else {
                             tracing::error!("未找到此端口");
                         }
+                    } else if let Ok(report) =
+                        serde_json::from_str::<PoolHealthReport>(&buf_str)
+                    {
+                        if let Some(temp_app) =
+                            inner_app.lock().unwrap().get_mut(&report.name)
+                        {
+                            temp_app.pool_health = report.pool_health;
+                        }
                     }
                 };
             }